@@ -1,7 +1,5 @@
 //! Receive talkers messages for a few seconds and store them in a Vec.
 
-use talkers;
-
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -28,7 +26,7 @@ fn main() {
             ))
         }));
 
-        if t.expect_handshake().is_ok() && t.perform_handshake().is_ok() {
+        if t.handshake(false).is_ok() {
             thread::spawn(move || {
                 for _ in 0..80 {
                     if t.read_maybe().is_err() {
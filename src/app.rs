@@ -1,20 +1,150 @@
 //! A simple example of a chat app with SOCKS5 support.
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
-use std::io::Result;
+use std::io::{BufRead, ErrorKind, Read, Result, Write};
 use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 use std::time;
 
-use std::io::stdin;
+use std::io::{stdin, stdout};
 use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
 
+use socket2::{Domain, Socket, Type};
 use socks::Socks5Stream;
 
 type Chat = Arc<Mutex<talkers::Talker>>;
-type Chats = Arc<Mutex<Vec<(usize, Chat)>>>;
+type Chats = Arc<Mutex<Vec<(usize, String, Chat)>>>;
+type Credentials = Option<(String, String)>;
+type Log = VecDeque<(bool, String)>;
 
-/// Listens on a port, waits for and dispatches connections.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Prints a line to stdout like `println!`, but flushes it right away (see `say`).
+macro_rules! say {
+    ($($arg:tt)*) => {
+        say(format_args!($($arg)*))
+    };
+}
+
+/// The maximum length of a line of input. Messages cannot be longer anyway.
+const MAX_LINE: usize = 1024 * 1024;
+
+/// The number of messages remembered per peer for `/history`.
+const HISTORY_LEN: usize = 1000;
+
+/// How often to look for the chat in which a side channel was announced, once every poll interval.
+const SIDE_CHANNEL_TRIES: usize = 20;
+
+/// How long to wait for the capabilities of a new peer before assuming that it supports none.
+const CAPABILITIES_WAIT: time::Duration = time::Duration::from_secs(5);
+
+/// The messages exchanged with each peer, keyed by the IP address of the peer rather than by chat, so that they are kept when a peer reconnects. Ports are ignored, as peers connect from a different port each time; hence, peers that share an IP address share a history. Chats opened with `/new` and a host name are keyed by the target as given. Each entry records whether we sent the message.
+#[derive(Clone, Default)]
+struct History(Arc<Mutex<HashMap<String, Log>>>);
+
+impl History {
+    fn key(peer: &str) -> String {
+        match peer.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip().to_string(),
+            Err(_) => peer.to_string(),
+        }
+    }
+
+    fn log(&self, peer: &str, sent: bool, msg: &str) {
+        let mut history = lock(&self.0);
+        let log = history.entry(Self::key(peer)).or_default();
+
+        log.push_back((sent, msg.trim_end().to_string()));
+
+        if log.len() > HISTORY_LEN {
+            log.pop_front();
+        }
+    }
+
+    fn get(&self, peer: &str) -> Vec<(bool, String)> {
+        lock(&self.0)
+            .get(&Self::key(peer))
+            .map(|log| log.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// The chat that a message was last received from or sent to. Lines that are not commands are sent to this chat.
+#[derive(Clone, Default)]
+struct Current(Arc<AtomicUsize>);
+
+impl Current {
+    fn set(&self, id: usize) {
+        self.0.store(id, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> Option<usize> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None, // ids start at 1
+            id => Some(id),
+        }
+    }
+}
+
+/// The settings of the example server (see `start_server_with`).
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// The address to listen on.
+    pub bind_to: SocketAddr,
+    /// The SOCKS5 proxy used for outgoing connections, if any.
+    pub proxy: Option<SocketAddr>,
+    /// The username and password for the proxy, if it requires authentication.
+    pub proxy_auth: Credentials,
+    /// The directory in which received files are stored (in a subdirectory per chat). By default, the current working directory is used.
+    pub downloads: Option<PathBuf>,
+    /// The maximum number of simultaneous connections. By default, there is no limit.
+    pub max_conns: Option<usize>,
+    /// The size of the largest file that is accepted, in octets. By default, all files are accepted.
+    pub max_file: Option<usize>,
+    /// The size of the largest message that is accepted, in octets (see `Talker::set_max_msg_size`). By default, the library's limit applies.
+    pub max_msg_size: Option<usize>,
+    /// The size of the largest chunked message that is accepted once reassembled, in octets (see `Talker::max_chunked_size`). By default, the library's limit applies.
+    pub max_chunked_size: Option<usize>,
+    /// The number of payload octets that may be received over the lifetime of a chat (see `Talker::max_total_received`). By default, there is no limit.
+    pub max_total_received: Option<usize>,
+    /// How long to wait between checks for incoming instructions on each connection (default: 125 ms).
+    pub poll_interval: time::Duration,
+    /// The file to which a line is appended for every message and file sent or received, consisting of the time (in seconds since the Unix epoch), the chat, the direction and the hash. By default, there is no such log.
+    pub audit: Option<PathBuf>,
+    /// If set, a chat is pinged whenever nothing has been sent or received for this long, e.g. to keep Tor circuits or NAT mappings from timing out. By default, no heartbeat is sent.
+    pub heartbeat: Option<time::Duration>,
+    /// Whether files sent to chats that we have initiated are sent over a second connection to the peer, so that messages keep flowing during the transfer. This requires the peer to support side channels. By default, files are sent over the chat's connection.
+    pub side_channels: bool,
+    /// If set, the server stops once this becomes true: no more connections are accepted, all chats are closed and `start_server_with` returns. The flag is checked once every poll interval. By default, the server runs until the process ends.
+    pub shutdown: Option<Arc<AtomicBool>>,
+}
+
+impl ServerConfig {
+    /// Returns the default settings for a server listening on `bind_to`.
+    pub fn new(bind_to: SocketAddr) -> Self {
+        ServerConfig {
+            bind_to,
+            proxy: None,
+            proxy_auth: None,
+            downloads: None,
+            max_conns: None,
+            max_file: None,
+            max_msg_size: None,
+            max_chunked_size: None,
+            max_total_received: None,
+            poll_interval: time::Duration::from_millis(125),
+            audit: None,
+            heartbeat: None,
+            side_channels: false,
+            shutdown: None,
+        }
+    }
+}
+
+/// Listens on a port, waits for and dispatches connections. This is a shorthand for `start_server_with`.
 ///
 /// # Examples
 ///
@@ -30,93 +160,467 @@ type Chats = Arc<Mutex<Vec<(usize, Chat)>>>;
 ///
 /// app::start_server(bind_to, Some(proxy));
 /// ```
+#[allow(dead_code)] // kept for compatibility, the binary uses `start_server_with`
 pub fn start_server(bind_to: SocketAddr, proxy: Option<SocketAddr>) {
-    let listener = TcpListener::bind(bind_to).expect("Could not listen on port");
+    start_server_with(ServerConfig {
+        proxy,
+        ..ServerConfig::new(bind_to)
+    })
+}
+
+/// Listens on a port, waits for and dispatches connections, using the given settings.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use talkers::app::{self, ServerConfig};
+/// use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+/// use std::time::Duration;
+///
+/// let bind_to = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 50505)); // bind on 0.0.0.0:50505
+///
+/// let mut config = ServerConfig::new(bind_to);
+/// config.max_file = Some(1024 * 1024); // accept files of up to 1 MiB
+/// config.poll_interval = Duration::from_millis(50);
+///
+/// app::start_server_with(config);
+/// ```
+pub fn start_server_with(config: ServerConfig) {
+    let listener = listen(config.bind_to).expect("Could not listen on port");
 
     let chats = Arc::new(Mutex::new(vec![]));
+    let current = Current::default();
 
     let cloned_chats = Arc::clone(&chats);
+    let cloned_current = current.clone();
+    let cloned_config = config.clone();
+    let history = History::default();
+    let cloned_history = history.clone();
 
-    thread::spawn(move || handle_commands(proxy, cloned_chats));
+    thread::spawn(move || {
+        handle_commands(cloned_config, cloned_chats, cloned_current, cloned_history)
+    });
 
-    eprintln!("Listening on {}.", bind_to);
-    if let Some(proxy) = proxy {
+    eprintln!("Listening on {}.", config.bind_to);
+    if let Some(proxy) = config.proxy {
         eprintln!("Using SOCKS5 proxy on {}.", proxy);
     }
+    if config.proxy_auth.is_some() {
+        // the credentials themselves are not logged
+        eprintln!("Authenticating with the proxy using a username and password.");
+    }
+    if let Some(ref downloads) = config.downloads {
+        eprintln!("Storing received files in {}.", downloads.display());
+    }
+    if let Some(max_conns) = config.max_conns {
+        eprintln!("Accepting at most {} connections at once.", max_conns);
+    }
+    if let Some(max_file) = config.max_file {
+        eprintln!("Accepting files of at most {} octets.", max_file);
+    }
+    if let Some(ref audit) = config.audit {
+        eprintln!("Logging the hashes of all messages to {}.", audit.display());
+    }
+    if let Some(heartbeat) = config.heartbeat {
+        eprintln!("Pinging chats idle for {} s.", heartbeat.as_secs());
+    }
+    if config.side_channels {
+        eprintln!("Sending files over side channels where possible.");
+    }
     eprintln!("Type `/help` for a list of accepted commands.");
 
-    for stream in listener.incoming() {
-        if let Ok(s) = stream {
-            new_connection(s, Arc::clone(&chats), false);
+    if config.shutdown.is_some() {
+        // the flag cannot be checked while blocked waiting for connections
+        listener
+            .set_nonblocking(true)
+            .expect("Could not set up listener");
+    }
+
+    let stopped = || {
+        config
+            .shutdown
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+    };
+
+    while !stopped() {
+        let s = match listener.accept() {
+            Ok((s, _)) => s,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(config.poll_interval);
+
+                continue;
+            }
+            Err(_) => continue,
+        };
+
+        if s.set_nonblocking(false).is_err() {
+            continue;
+        }
+
+        if let Some(max_conns) = config.max_conns {
+            if lock(&chats).len() >= max_conns {
+                // dropping the stream closes it
+                eprintln!("Refusing connection: limit of {} reached.", max_conns);
+
+                continue;
+            }
+        }
+
+        if let Ok(peer) = s.peer_addr() {
+            new_connection(
+                s,
+                &peer.to_string(),
+                Arc::clone(&chats),
+                false,
+                &config,
+                current.clone(),
+                history.clone(),
+            );
         }
     }
+
+    eprintln!("Shutting down.");
+
+    for (_, _, t) in lock(&chats).iter() {
+        let _ = lock(t).close();
+    }
 }
 
-fn try_parse(buf: &str) -> Option<(usize, usize)> {
-    let mut si = buf.trim().split(' ');
+/// Writes a line to stdout and flushes it, so that messages appear right away and in order with the status lines on stderr, even if stdout is piped.
+fn say(args: std::fmt::Arguments) {
+    say_to(&mut stdout().lock(), args)
+}
+
+/// Like `say`, but writes to `out`.
+fn say_to<W: Write>(out: &mut W, args: std::fmt::Arguments) {
+    // there is nobody to tell if stdout is gone
+    let _ = writeln!(out, "{}", args);
+    let _ = out.flush();
+}
+
+/// Appends a line for a message or file sent or received to the audit log, if any. The file is opened for every line and written without buffering, so that nothing is lost if the app crashes.
+fn audit(path: Option<&Path>, id: usize, direction: &str, hash: &[u8; 32]) {
+    let path = match path {
+        Some(path) => path,
+        None => return,
+    };
+
+    let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let line = format!(
+        "{}.{:03} {} {} {}\n",
+        now.as_secs(),
+        now.subsec_millis(),
+        id,
+        direction,
+        talkers::hash_hex(hash)
+    );
+
+    let res = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut fp| fp.write_all(line.as_bytes()));
+
+    if let Err(e) = res {
+        eprintln!("Could not write to audit log {}: {}", path.display(), e);
+    }
+}
+
+/// Locks a mutex. If another thread has panicked while holding the lock, a warning is printed and the lock is acquired nonetheless, so that one bad connection does not take down the whole app. The poison is cleared, so that the warning is printed only once.
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| {
+        eprintln!("Warning: recovering from a poisoned lock.");
+
+        let guard = e.into_inner();
+        m.clear_poison();
+
+        guard
+    })
+}
+
+/// Binds a listener on `bind_to`. If `bind_to` is the unspecified IPv6 address (`[::]`), the listener is dual-stack, i.e. it accepts IPv4 peers as well.
+fn listen(bind_to: SocketAddr) -> Result<TcpListener> {
+    if !bind_to.is_ipv6() || !bind_to.ip().is_unspecified() {
+        return TcpListener::bind(bind_to);
+    }
+
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, None)?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&bind_to.into())?;
+    socket.listen(128)?;
+
+    Ok(socket.into())
+}
+
+/// Splits off the first word of `buf`. Returns the word and the offset at which the remaining text starts. Words may be separated by any run of whitespace.
+fn try_parse(buf: &str) -> Option<(&str, usize)> {
+    let start = buf.len() - buf.trim_start().len();
+    let rest = &buf[start..];
+
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+
+    if end == 0 {
+        return None;
+    }
+
+    let after = &rest[end..];
+    let skip = after.len() - after.trim_start().len();
+
+    Some((&rest[..end], start + end + skip))
+}
+
+/// Resolves a chat that is given either by its id or by the address of the peer (e.g. `1.2.3.4:50505`). Fails if no chat or several chats match the address.
+fn resolve(chats: &[(usize, String, Chat)], target: &str) -> std::result::Result<usize, String> {
+    if let Ok(id) = target.parse::<usize>() {
+        return Ok(id);
+    }
+
+    let ids: Vec<usize> = chats
+        .iter()
+        .filter(|(_, addr, _)| addr == target)
+        .map(|(id, _, _)| *id)
+        .collect();
+
+    match ids[..] {
+        [] => Err(format!("There is no chat with `{}`.", target)),
+        [id] => Ok(id),
+        _ => Err(format!(
+            "There are several chats with `{}` ({:?}). Please use an id instead.",
+            target, ids
+        )),
+    }
+}
+
+/// Connects to `target` via the SOCKS5 proxy, authenticating with a username and password if given.
+fn proxy_connect(proxy: SocketAddr, target: &str, auth: &Credentials) -> Result<TcpStream> {
+    let ts = match auth {
+        Some((user, pass)) => Socks5Stream::connect_with_password(proxy, target, user, pass)?,
+        None => Socks5Stream::connect(proxy, target)?,
+    };
+
+    Ok(ts.into_inner())
+}
+
+/// Connects to `target`, via the SOCKS5 proxy if one is configured.
+fn connect(config: &ServerConfig, target: &str) -> Result<TcpStream> {
+    match config.proxy {
+        Some(proxy) => proxy_connect(proxy, target, &config.proxy_auth),
+        None => TcpStream::connect(target),
+    }
+}
+
+/// Reads a line of at most `MAX_LINE` octets into `buf`. Returns `Ok(false)` if the line was longer, in which case the rest of it is skipped and `buf` is left unchanged. Invalid UTF-8 sequences are replaced with U+FFFD.
+fn read_line_capped<R: BufRead>(input: &mut R, buf: &mut String) -> Result<bool> {
+    let mut line = Vec::new();
+    input
+        .by_ref()
+        .take(MAX_LINE as u64)
+        .read_until(b'\n', &mut line)?;
+
+    if line.len() == MAX_LINE && line.last() != Some(&b'\n') {
+        // skip the rest of the line without holding on to it
+        loop {
+            let rest = input.fill_buf()?;
 
-    if let Some(zs) = si.next() {
-        if let Ok(z) = zs.parse::<usize>() {
-            return Some((z, zs.len() + 1));
+            match rest.iter().position(|&c| c == b'\n') {
+                Some(end) => {
+                    input.consume(end + 1);
+                    break;
+                }
+                None if rest.is_empty() => break,
+                None => {
+                    let n = rest.len();
+                    input.consume(n);
+                }
+            }
         }
+
+        return Ok(false);
     }
 
-    None
+    buf.push_str(&String::from_utf8_lossy(&line));
+
+    Ok(true)
 }
 
-fn handle_commands(proxy: Option<SocketAddr>, chats: Chats) {
+fn handle_commands(config: ServerConfig, chats: Chats, current: Current, history: History) {
     let mut buf = String::new();
 
-    while stdin().read_line(&mut buf).is_ok() {
-        if buf.starts_with("/new ") {
-            if let Some(proxy) = proxy {
-                if let Ok(ts) = Socks5Stream::connect(proxy, buf[5..].trim()) {
-                    new_connection(ts.into_inner(), Arc::clone(&chats), true);
-                } else {
-                    eprintln!("Could not connect to remote socket via proxy.");
+    let resolve = |target: &str| {
+        let res = resolve(&lock(&chats), target);
+
+        if let Err(ref e) = res {
+            eprintln!("{}", e);
+        }
+
+        res.ok()
+    };
+
+    let mut input = stdin().lock();
+
+    // only the addresses of chats that we have initiated are known to be reachable
+    let mut dialed = HashSet::new();
+
+    while let Ok(complete) = read_line_capped(&mut input, &mut buf) {
+        if complete && buf.is_empty() {
+            // end of input, even empty lines end with a newline
+            break;
+        } else if !complete {
+            eprintln!("Line longer than {} octets. Ignoring.", MAX_LINE);
+        } else if let Some(target) = buf.strip_prefix("/new ") {
+            let target = target.trim();
+
+            match connect(&config, target) {
+                Ok(s) => {
+                    let id = new_connection(
+                        s,
+                        target,
+                        Arc::clone(&chats),
+                        true,
+                        &config,
+                        current.clone(),
+                        history.clone(),
+                    );
+
+                    dialed.insert(id);
                 }
-            } else if let Ok(s) = TcpStream::connect(buf[5..].trim()) {
-                new_connection(s, Arc::clone(&chats), true);
-            } else {
-                eprintln!("Could not connect to remote socket.");
+                Err(_) if config.proxy.is_some() => {
+                    eprintln!("Could not connect to remote socket via proxy.")
+                }
+                Err(_) => eprintln!("Could not connect to remote socket."),
             }
-        } else if buf.starts_with("/file ") {
-            if let Some((dest, offset)) = try_parse(&buf[6..]) {
-                let filen = &buf[(offset + 6)..].trim_end();
+        } else if let Some(rest) = buf.strip_prefix("/file ") {
+            if let Some((target, offset)) = try_parse(rest) {
+                let filen = &rest[offset..].trim_end();
+
+                if let Some(dest) = resolve(target) {
+                    if let Ok(fm) = fs::metadata(filen) {
+                        let side = config.side_channels
+                            && dialed.contains(&dest)
+                            && send_file_side(
+                                &chats,
+                                dest,
+                                filen,
+                                &config,
+                                current.clone(),
+                                history.clone(),
+                            );
 
-                if let Ok(fm) = fs::metadata(filen) {
-                    eprintln!("{} : Sending `{}` ({} octets) …", dest, filen, fm.len());
-                    eprintln!("{} : (Until complete, you can't enter new commands.)", dest);
+                        if side {
+                            eprintln!(
+                                "{} : Sending `{}` ({} octets) over a side channel …",
+                                dest,
+                                filen,
+                                fm.len()
+                            );
+                        } else {
+                            eprintln!("{} : Sending `{}` ({} octets) …", dest, filen, fm.len());
+                            eprintln!("{} : (Until complete, you can't enter new commands.)", dest);
 
-                    if send_file(Arc::clone(&chats), dest, filen, fm.len()).is_err() {
-                        eprintln!("{} : The file could not be sent.", dest);
+                            if send_file(Arc::clone(&chats), dest, filen).is_err() {
+                                eprintln!("{} : The file could not be sent.", dest);
+                            }
+                        }
+                    } else {
+                        eprintln!(
+                            "{} : File `{}` could not be opened for reading. Ignoring.",
+                            dest, filen
+                        );
                     }
-                } else {
-                    eprintln!(
-                        "{} : File `{}` could not be opened for reading. Ignoring.",
-                        dest, filen
-                    );
                 }
             } else {
                 eprintln!("You must use /file like this: `/file 2 file.ext`.");
             }
-        } else if buf.starts_with("/close ") {
-            if let Some((id, _)) = try_parse(&buf[7..]) {
-                terminate(Arc::clone(&chats), id);
+        } else if let Some(filen) = buf.strip_prefix("/fileall ") {
+            let filen = filen.trim();
+
+            if let Ok(fm) = fs::metadata(filen) {
+                eprintln!(
+                    "Sending `{}` ({} octets) to {} chats …",
+                    filen,
+                    fm.len(),
+                    lock(&chats).len()
+                );
+                eprintln!("(Until complete, you can't enter new commands.)");
+
+                for (id, res) in send_file_to_all(&chats, filen) {
+                    match res {
+                        Ok(()) => eprintln!("{} : The file has been sent.", id),
+                        Err(e) => eprintln!("{} : The file could not be sent: {}", id, e),
+                    }
+                }
+            } else {
+                eprintln!(
+                    "File `{}` could not be opened for reading. Ignoring.",
+                    filen
+                );
+            }
+        } else if let Some(rest) = buf.strip_prefix("/close ") {
+            if let Some((target, _)) = try_parse(rest) {
+                if let Some(id) = resolve(target) {
+                    terminate(Arc::clone(&chats), id);
+                }
             } else {
                 eprintln!("You must use /close like this: `/close 4`.");
             }
-        } else if let Some((dest, offset)) = try_parse(&buf[1..]) {
-            if send(Arc::clone(&chats), dest, &buf[(offset + 1)..]).is_err() {
-                terminate(Arc::clone(&chats), dest);
+        } else if let Some(rest) = buf.strip_prefix("/verify ") {
+            if let Some((target, _)) = try_parse(rest) {
+                if let Some(id) = resolve(target) {
+                    verify(Arc::clone(&chats), id);
+                }
+            } else {
+                eprintln!("You must use /verify like this: `/verify 3`.");
+            }
+        } else if let Some(rest) = buf.strip_prefix("/history ") {
+            if let Some((target, _)) = try_parse(rest) {
+                if let Some(id) = resolve(target) {
+                    show_history(&chats, &history, id);
+                }
+            } else {
+                eprintln!("You must use /history like this: `/history 2`.");
             }
         } else if buf.starts_with("/help") {
             eprintln!("/--------------------------------------------------------------------\\");
             eprintln!("|  /new host:port       Connects to a talkers instance at host:port  |");
             eprintln!("|  /close k             Terminates the connection with chat k.       |");
             eprintln!("|  /file k file.ext     Sends the file `file.ext` to chat k.         |");
+            eprintln!("|  /fileall file.ext    Sends the file `file.ext` to all chats.      |");
+            eprintln!("|  /verify k            Checks the last file received in chat k      |");
+            eprintln!("|                       against the hash declared by the peer.       |");
+            eprintln!("|  /history k           Shows the messages exchanged with the peer   |");
+            eprintln!("|                       of chat k, including earlier connections.    |");
             eprintln!("|  /k message           Sends the message `message` to chat k.       |");
+            eprintln!("|  message              Sends the message `message` to the chat that |");
+            eprintln!("|                       was last written to or received from.        |");
+            eprintln!("|                                                                    |");
+            eprintln!("|  Instead of its id k, a chat can be given by the address of the    |");
+            eprintln!("|  peer (host:port).                                                 |");
             eprintln!("\\--------------------------------------------------------------------/");
+        } else if let Some((target, offset)) = buf.strip_prefix('/').and_then(try_parse) {
+            if let Some(dest) = resolve(target) {
+                current.set(dest);
+
+                if send(Arc::clone(&chats), &history, dest, &buf[(offset + 1)..]).is_err() {
+                    terminate(Arc::clone(&chats), dest);
+                }
+            }
+        } else if buf.trim().is_empty() {
+            // ignore empty lines
+        } else if !buf.starts_with('/') {
+            if let Some(dest) = current.get() {
+                if send(Arc::clone(&chats), &history, dest, &buf).is_err() {
+                    terminate(Arc::clone(&chats), dest);
+                }
+            } else {
+                eprintln!("There is no current chat yet. Please use `/k message`.");
+            }
         } else {
             eprintln!("Invalid command. Ignoring. Type `/help` for help.");
         }
@@ -125,65 +629,163 @@ fn handle_commands(proxy: Option<SocketAddr>, chats: Chats) {
     }
 }
 
-fn new_connection(s: TcpStream, chats: Chats, inited_by_us: bool) {
-    let peer = s.peer_addr().unwrap();
-
+/// Sets up a chat on a new connection and starts receiving from it. Returns the id of the chat, which is dropped again if the handshake fails or the connection turns out to be a side channel of another chat.
+fn new_connection(
+    s: TcpStream,
+    peer: &str,
+    chats: Chats,
+    inited_by_us: bool,
+    config: &ServerConfig,
+    current: Current,
+    history: History,
+) -> usize {
     let t1 = Arc::new(Mutex::new(talkers::Talker::new(s)));
     let t2 = Arc::clone(&t1);
-    let t3 = Arc::clone(&t2);
 
-    if let Ok(mut t) = t1.lock() {
-        if (!inited_by_us && t.expect_handshake().is_ok() && t.perform_handshake().is_ok())
-            || (inited_by_us && t.perform_handshake().is_ok() && t.expect_handshake().is_ok())
-        {
-            if let Some(id) = insert_as_next(chats, t2) {
-                set_example_handlers(&mut t, id);
+    // the handlers are in place before anything is read, so that no message gets lost
+    let id = insert_as_next(Arc::clone(&chats), peer, t2);
 
-                println!("{} : Connection established with {}.", id, peer);
-            }
-        } else {
-            return;
-        }
-    } else {
-        return;
+    {
+        let mut t = lock(&t1);
+
+        set_example_handlers(&mut t, id, current.clone(), config, peer, history.clone());
+        set_limits(&mut t, config);
+
+        // each chat gets its own subdirectory
+        t.download_dir = config.downloads.as_ref().map(|d| d.join(id.to_string()));
     }
 
+    let peer = peer.to_string();
+    let config = config.clone();
+
+    // a slow peer holds up only its own chat
     thread::spawn(move || {
+        let established = {
+            let mut t = lock(&t1);
+
+            // the capabilities tell whether the peer answers pings, but not every peer sends them
+            t.handshake(inited_by_us)
+                .and_then(|_| t.exchange_capabilities_within(CAPABILITIES_WAIT))
+                .map(|_| t.side_channel())
+        }; // unlock mutex (avoid deadlocks)
+
+        match established {
+            Ok(None) => say!("{} : Connection established with {}.", id, peer),
+            Ok(Some(token)) => {
+                lock(&chats).retain(|(i, _, _)| *i != id);
+                attach_side_channel(t1, token, chats, &config, current, history);
+
+                return;
+            }
+            Err(_) => {
+                lock(&chats).retain(|(i, _, _)| *i != id);
+
+                return;
+            }
+        }
+
         loop {
             {
-                if let Ok(mut t) = t3.lock() {
-                    if t.read_maybe().is_err() {
+                let mut t = lock(&t1);
+
+                if t.read_maybe().is_err() {
+                    break;
+                }
+
+                if let Some(interval) = config.heartbeat {
+                    if t.heartbeat(interval).is_err() {
                         break;
                     }
                 }
             } // unlock mutex (avoid deadlocks)
-            thread::sleep(time::Duration::from_millis(125));
+            thread::sleep(config.poll_interval);
         }
 
-        let _ = t3.lock().unwrap().close();
+        let _ = lock(&t1).close();
+
+        // closed chats no longer count towards the connection limit
+        lock(&chats).retain(|(i, _, _)| *i != id);
+    });
+
+    id
+}
+
+/// Receives files on a side channel that the peer has opened, on behalf of the chat in which it was announced. The side channel ends when the peer closes it.
+fn attach_side_channel(
+    t: Chat,
+    token: u64,
+    chats: Chats,
+    config: &ServerConfig,
+    current: Current,
+    history: History,
+) {
+    let config = config.clone();
+
+    thread::spawn(move || {
+        let mut chat = None;
+
+        for _ in 0..SIDE_CHANNEL_TRIES {
+            // the announcement may not have been read on the chat's connection yet
+            chat = lock(&chats)
+                .iter()
+                .find(|(_, _, c)| lock(c).claim_side_channel(token))
+                .map(|(id, peer, _)| (*id, peer.clone()));
+
+            if chat.is_some() {
+                break;
+            }
+
+            thread::sleep(config.poll_interval);
+        }
+
+        let mut t = lock(&t);
+
+        let (id, peer) = match chat {
+            Some(chat) => chat,
+            None => {
+                eprintln!("Refusing side channel: it was not announced in any chat.");
+                let _ = t.close();
+
+                return;
+            }
+        };
+
+        set_example_handlers(&mut t, id, current, &config, &peer, history);
+        t.chat_close = None; // the chat itself remains open
+        set_limits(&mut t, &config);
+        t.download_dir = config.downloads.as_ref().map(|d| d.join(id.to_string()));
+
+        say!("{} : Side channel opened.", id);
+
+        while t.read_once().is_ok() {}
+
+        say!("{} : Side channel closed.", id);
     });
 }
 
 fn terminate(chats: Chats, id: usize) {
-    let mut chats = chats.lock().expect("Could not lock chats mutex");
+    let mut chats = lock(&chats);
 
-    for (i, ref mut t) in chats.iter_mut() {
+    for (i, _, ref mut t) in chats.iter_mut() {
         if *i == id {
-            let _ = t.lock().unwrap().close();
+            let _ = lock(t).close_after_drain(time::Duration::from_millis(500));
 
             break;
         }
     }
 }
 
-fn send(chats: Chats, id: usize, msg: &str) -> Result<()> {
-    let mut chats = chats.lock().expect("Could not lock chats mutex");
+fn send(chats: Chats, history: &History, id: usize, msg: &str) -> Result<()> {
+    let mut chats = lock(&chats);
 
-    for (i, ref mut t) in chats.iter_mut() {
+    for (i, peer, ref mut t) in chats.iter_mut() {
         if *i == id {
-            t.lock().unwrap().send(msg)?;
+            // the chat's own thread must not read the hash in between
+            let mut t = lock(t);
+            t.send(msg)?;
+            t.expect_hash()?;
 
-            t.lock().unwrap().expect_hash()?;
+            history.log(peer, true, msg);
 
             break;
         }
@@ -192,16 +794,14 @@ fn send(chats: Chats, id: usize, msg: &str) -> Result<()> {
     Ok(())
 }
 
-fn send_file(chats: Chats, id: usize, filen: &str, fsize: u64) -> Result<()> {
-    let mut chats = chats.lock().expect("Could not lock chats mutex");
+fn send_file(chats: Chats, id: usize, filen: &str) -> Result<()> {
+    let mut chats = lock(&chats);
 
-    for (i, ref mut t) in chats.iter_mut() {
+    for (i, _, ref mut t) in chats.iter_mut() {
         if *i == id {
-            let mut fp = fs::File::open(filen)?;
-
-            t.lock().unwrap().send_stream(&mut fp, fsize)?;
-
-            t.lock().unwrap().expect_hash()?;
+            let mut t = lock(t);
+            t.send_file(filen)?;
+            t.expect_hash()?;
 
             break;
         }
@@ -210,43 +810,944 @@ fn send_file(chats: Chats, id: usize, filen: &str, fsize: u64) -> Result<()> {
     Ok(())
 }
 
-fn insert_as_next(chats: Chats, talker: Chat) -> Option<usize> {
-    let mut chats = chats.lock().ok()?;
-    let this_id = if let Some((z, _)) = chats.last() {
-        z + 1
-    } else {
-        1
+/// Sends a file over a side channel, i.e. a second connection to the peer of a chat, so that messages keep flowing on the chat's connection during the transfer. The file is sent in the background. Returns false if the peer does not support side channels, in which case nothing has been done.
+fn send_file_side(
+    chats: &Chats,
+    id: usize,
+    filen: &str,
+    config: &ServerConfig,
+    current: Current,
+    history: History,
+) -> bool {
+    let (peer, token) = {
+        let chats = lock(chats);
+
+        let (peer, t) = match chats.iter().find(|(i, _, _)| *i == id) {
+            Some((_, peer, t)) => (peer.clone(), t),
+            None => return false,
+        };
+
+        let mut t = lock(t);
+
+        if !t.peer_supports(talkers::Capability::SideChannels) {
+            return false;
+        }
+
+        match t.announce_side_channel() {
+            Ok(token) => (peer, token),
+            Err(_) => return false,
+        }
+    };
+
+    let filen = filen.to_string();
+    let config = config.clone();
+
+    thread::spawn(move || {
+        let res = connect(&config, &peer).and_then(|s| {
+            let mut t = talkers::Talker::new(s);
+            set_example_handlers(&mut t, id, current, &config, &peer, history);
+            t.chat_close = None; // the chat itself remains open
+
+            t.handshake(true)?;
+            t.join_side_channel(token)?;
+            t.exchange_capabilities_within(CAPABILITIES_WAIT)?;
+
+            t.send_file(&filen)?;
+            t.expect_hash()?;
+
+            t.close_after_drain(time::Duration::from_millis(500))
+        });
+
+        match res {
+            Ok(()) => eprintln!("{} : `{}` has been sent over a side channel.", id, filen),
+            Err(e) => eprintln!(
+                "{} : `{}` could not be sent over a side channel: {}",
+                id, filen, e
+            ),
+        }
+    });
+
+    true
+}
+
+/// Sends a file to every chat in turn. A failure on one connection does not prevent sending to the rest. Returns the result for each chat.
+fn send_file_to_all(chats: &Chats, filen: &str) -> Vec<(usize, Result<()>)> {
+    let ids: Vec<usize> = lock(chats).iter().map(|(id, _, _)| *id).collect();
+
+    ids.into_iter()
+        .map(|id| (id, send_file(Arc::clone(chats), id, filen)))
+        .collect()
+}
+
+/// Prints the messages exchanged with the peer of a chat, including those of earlier connections from or to the same address.
+fn show_history(chats: &Chats, history: &History, id: usize) {
+    let peer = match lock(chats).iter().find(|(i, _, _)| *i == id) {
+        Some((_, peer, _)) => peer.clone(),
+        None => return,
+    };
+
+    let log = history.get(&peer);
+
+    if log.is_empty() {
+        eprintln!("{} : No messages exchanged with {} yet.", id, peer);
+    }
+
+    for (sent, msg) in log {
+        say!("{} {} {}", id, if sent { "<" } else { ">" }, msg);
+    }
+}
+
+fn verify(chats: Chats, id: usize) {
+    let chats = lock(&chats);
+
+    let file = match chats.iter().find(|(i, _, _)| *i == id) {
+        Some((_, _, t)) => last_file_hash(&lock(t)),
+        None => return,
+    };
+
+    let (file, hash) = match file {
+        Some(last) => last,
+        None => {
+            eprintln!("{} : No file has been received yet.", id);
+            return;
+        }
     };
 
-    chats.push((this_id, talker));
+    match (hash, file.peer_hash) {
+        (Ok(hash), Some(peer_hash)) if hash == peer_hash => {
+            say!("{} : `{}` matches the peer's hash.", id, file.name)
+        }
+        (Ok(hash), Some(peer_hash)) => say!(
+            "{} : `{}` does NOT match the peer's hash ({} vs. {}).",
+            id,
+            file.name,
+            talkers::hash_hex(&hash),
+            talkers::hash_hex(&peer_hash)
+        ),
+        (Ok(_), None) => say!(
+            "{} : The peer did not declare a hash for `{}`.",
+            id,
+            file.name
+        ),
+        (Err(e), _) => say!("{} : `{}` could not be read: {}", id, file.name, e),
+    }
+}
+
+/// Returns the last file received by `t` along with its current hash, i.e. that of the file as it is stored now.
+fn last_file_hash(t: &talkers::Talker) -> Option<(talkers::ReceivedFile, Result<[u8; 32]>)> {
+    let file = t.received_files().last().cloned()?;
+
+    // the file is where the talker has stored it
+    let hash = talkers::hash_file_in(&*t.file_system, &file.name);
 
-    Some(this_id)
+    Some((file, hash))
+}
+
+fn insert_as_next(chats: Chats, peer: &str, talker: Chat) -> usize {
+    let mut chats = lock(&chats);
+    let this_id = NEXT_ID.fetch_add(1, Ordering::Relaxed); // ids are never reused
+
+    chats.push((this_id, peer.to_string(), talker));
+
+    this_id
+}
+
+/// Applies the limits on what is received from the peer of a chat.
+fn set_limits(t: &mut talkers::Talker, config: &ServerConfig) {
+    if let Some(size) = config.max_msg_size {
+        // without notifying the peer, this cannot fail
+        let _ = t.set_max_msg_size(size, false);
+    }
+
+    if let Some(size) = config.max_chunked_size {
+        t.max_chunked_size = size;
+    }
+
+    t.max_total_received = config.max_total_received;
 }
 
 /// These are example handlers for the app. Feel free to use and adapt them for your own projects.
-fn set_example_handlers(t: &mut talkers::Talker, id: usize) {
-    t.chat_close = Some(Box::new(move || println!("{} : Closed.", id)));
-    t.msg_new = Some(Box::new(move |msg| println!("{} > {}", id, msg.trim_end())));
+fn set_example_handlers(
+    t: &mut talkers::Talker,
+    id: usize,
+    current: Current,
+    config: &ServerConfig,
+    peer: &str,
+    history: History,
+) {
+    let peer = peer.to_string();
+    let max_file = config.max_file;
+    let audit_sent = config.audit.clone();
+    let audit_rcvd = config.audit.clone();
+
+    t.sanitize = talkers::Sanitize::Escape; // messages are printed to the terminal
+    t.chat_close = Some(Box::new(move || say!("{} : Closed.", id)));
+    t.msg_new = Some(Box::new(move |msg| {
+        current.set(id); // received messages make this the current chat
+        history.log(&peer, false, &msg);
+
+        say!("{} > {}", id, msg.trim_end())
+    }));
     t.file_incoming = Box::new(move |fsize| {
-        println!(
+        if max_file.is_some_and(|max| fsize > max) {
+            say!(
+                "{} : Incoming file transfer of {} octets. Rejecting (too large).",
+                id,
+                fsize
+            );
+
+            return false;
+        }
+
+        say!(
             "{} : Incoming file transfer of {} octets. Accepting.",
-            id, fsize
+            id,
+            fsize
         );
 
-        true // accept all file transfers
+        true // accept all other file transfers
     });
     t.file_failed = Some(Box::new(move |_, e| {
-        println!("{} : File transfer failed: {}", id, e)
+        say!("{} : File transfer failed: {}", id, e)
     }));
     t.file_complete = Some(Box::new(move |filen| {
-        println!("{} : File transfer of `{}` complete.", id, filen)
+        say!("{} : File transfer of `{}` complete.", id, filen)
     }));
     t.file_hash_by_peer = Some(Box::new(move |_, hash| {
-        println!("{} = peer {:x?}", id, hash)
+        say!("{} = peer {}", id, talkers::hash_hex(&hash))
     }));
     t.file_our_hash = Some(Box::new(move |_, hash| {
-        println!("{} = hash {:x?}", id, hash)
+        say!("{} = hash {}", id, talkers::hash_hex(&hash))
     }));
-    t.hash_of_sent = Some(Box::new(move |hash| println!("{} = true {:x?}", id, hash)));
-    t.hash_rcvd = Some(Box::new(move |hash| println!("{} = rcvd {:x?}", id, hash)));
+    t.hash_of_sent = Some(Box::new(move |hash| {
+        audit(audit_sent.as_deref(), id, "sent", &hash);
+
+        say!("{} = true {}", id, talkers::hash_hex(&hash))
+    }));
+    t.hash_of_rcvd = Some(Box::new(move |hash| {
+        audit(audit_rcvd.as_deref(), id, "received", &hash)
+    }));
+    t.hash_rcvd = Some(Box::new(move |hash| {
+        say!("{} = rcvd {}", id, talkers::hash_hex(&hash))
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    /// Returns a chat on a loopback connection whose other end is dropped.
+    fn chat() -> Chat {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let s = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        Arc::new(Mutex::new(talkers::Talker::new(s)))
+    }
+
+    /// Returns an empty directory for the test `name` to store files in.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("talkers-app-{}-{}", name, std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    /// Connects a peer to the app as if it had been accepted by `start_server`. Returns the id of the new chat and the peer.
+    fn accept_peer(
+        config: &ServerConfig,
+        chats: &Chats,
+        current: &Current,
+        history: &History,
+    ) -> (usize, talkers::Talker) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let peer = thread::spawn(move || {
+            let mut peer = talkers::Talker::connect(addr).unwrap();
+            peer.exchange_capabilities().unwrap();
+
+            peer
+        });
+
+        let (s, from) = listener.accept().unwrap();
+        let id = new_connection(
+            s,
+            &from.to_string(),
+            Arc::clone(chats),
+            false,
+            config,
+            current.clone(),
+            history.clone(),
+        );
+
+        (id, peer.join().unwrap())
+    }
+
+    #[test]
+    fn targets_resolve_by_id_or_address() {
+        let chats = vec![
+            (1, "127.0.0.1:50505".to_string(), chat()),
+            (2, "example.onion:50505".to_string(), chat()),
+            (3, "127.0.0.1:50505".to_string(), chat()),
+        ];
+
+        assert_eq!(resolve(&chats, "7"), Ok(7));
+        assert_eq!(resolve(&chats, "example.onion:50505"), Ok(2));
+        assert!(resolve(&chats, "127.0.0.1:50506").is_err());
+
+        // ambiguous addresses must be addressed by id
+        assert!(resolve(&chats, "127.0.0.1:50505").is_err());
+    }
+
+    #[test]
+    fn files_land_in_subdirectory_of_chat() {
+        let dir = temp_dir("subdirs");
+        let mut config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        config.downloads = Some(dir.clone());
+
+        let chats = Chats::default();
+        let (id, mut peer) = accept_peer(&config, &chats, &Current::default(), &History::default());
+
+        peer.send_reader(&mut std::io::Cursor::new(b"data".to_vec()))
+            .unwrap();
+        peer.expect_hash().unwrap();
+
+        let entries: Vec<_> = fs::read_dir(dir.join(id.to_string()))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(fs::read(&entries[0]).unwrap(), b"data");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dual_stack_listener_accepts_both_families() {
+        let listener = listen("[::]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        for peer in ["127.0.0.1", "[::1]"] {
+            let s = TcpStream::connect(format!("{}:{}", peer, port)).unwrap();
+            let (_, from) = listener.accept().unwrap();
+
+            assert_eq!(
+                from.ip().to_canonical(),
+                s.local_addr().unwrap().ip().to_canonical()
+            );
+        }
+    }
+
+    #[test]
+    fn try_parse_tolerates_whitespace() {
+        for line in [
+            "2 file.txt",
+            "2   file.txt",
+            "2\tfile.txt",
+            "  2 \t file.txt",
+        ] {
+            let (word, offset) = try_parse(line).unwrap();
+
+            assert_eq!(word, "2");
+            assert_eq!(&line[offset..], "file.txt");
+        }
+
+        // names may contain spaces themselves
+        let line = "3 my file.txt";
+        let (_, offset) = try_parse(line).unwrap();
+        assert_eq!(&line[offset..], "my file.txt");
+
+        assert_eq!(try_parse("4"), Some(("4", 1)));
+        assert_eq!(try_parse(" \t"), None);
+    }
+
+    /// Waits for up to two seconds until `cond` holds.
+    fn wait_for<F: Fn() -> bool>(cond: F) -> bool {
+        for _ in 0..200 {
+            if cond() {
+                return true;
+            }
+
+            thread::sleep(time::Duration::from_millis(10));
+        }
+
+        false
+    }
+
+    #[test]
+    fn bare_lines_go_to_current_chat() {
+        let config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        let chats = Chats::default();
+        let current = Current::default();
+        let history = History::default();
+
+        let (_, mut first) = accept_peer(&config, &chats, &current, &history);
+        let (id, mut second) = accept_peer(&config, &chats, &current, &history);
+        assert_eq!(current.get(), None);
+
+        second.send("hello").unwrap();
+        assert!(wait_for(|| current.get() == Some(id)));
+
+        let reader = thread::spawn(move || {
+            (0..2)
+                .map(|_| second.read_once_detailed().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        // as `handle_commands` does for lines that are not commands
+        send(
+            Arc::clone(&chats),
+            &history,
+            current.get().unwrap(),
+            "bare line",
+        )
+        .unwrap();
+
+        let outcomes = reader.join().unwrap();
+        assert!(matches!(outcomes[0], talkers::ReadOutcome::Hash(_)));
+        assert_eq!(
+            outcomes[1],
+            talkers::ReadOutcome::Message("bare line".to_string())
+        );
+
+        assert!(!first.read_maybe().unwrap());
+    }
+
+    /// Runs `start_server_with` on a free loopback port until the returned flag is set.
+    fn serve(mut config: ServerConfig) -> (SocketAddr, Arc<AtomicBool>, thread::JoinHandle<()>) {
+        // the port is free again once the probing listener is dropped
+        config.bind_to = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        config.shutdown = Some(Arc::clone(&shutdown));
+
+        let addr = config.bind_to;
+        let server = thread::spawn(move || start_server_with(config));
+
+        (addr, shutdown, server)
+    }
+
+    /// Connects a peer to the server at `addr`, waiting for it to start listening.
+    fn connect_peer(addr: SocketAddr) -> talkers::Talker {
+        for _ in 0..200 {
+            if let Ok(mut peer) = talkers::Talker::connect(addr) {
+                peer.exchange_capabilities().unwrap();
+
+                return peer;
+            }
+
+            thread::sleep(time::Duration::from_millis(10));
+        }
+
+        panic!("server did not start listening on {}", addr);
+    }
+
+    #[test]
+    fn connections_beyond_limit_are_refused() {
+        let mut config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        config.max_conns = Some(1);
+        config.poll_interval = time::Duration::from_millis(10);
+
+        let (addr, shutdown, server) = serve(config);
+
+        let first = connect_peer(addr);
+        assert!(talkers::Talker::connect(addr).is_err());
+
+        // closed chats free their slot
+        drop(first);
+        assert!(wait_for(|| talkers::Talker::connect(addr)
+            .and_then(|mut t| t.exchange_capabilities())
+            .is_ok()));
+
+        shutdown.store(true, Ordering::Relaxed);
+        server.join().unwrap();
+    }
+
+    /// Runs a SOCKS5 proxy for a single client that refuses every request. Returns the authentication methods offered by the client and the username and password it sent, if any.
+    fn refusing_proxy() -> (SocketAddr, thread::JoinHandle<(Vec<u8>, Credentials)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let proxy = thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+
+            let mut greeting = [0; 2];
+            s.read_exact(&mut greeting).unwrap();
+            let mut methods = vec![0; greeting[1] as usize];
+            s.read_exact(&mut methods).unwrap();
+
+            if !methods.contains(&2) {
+                // no acceptable method
+                s.write_all(&[5, 0xff]).unwrap();
+
+                return (methods, None);
+            }
+
+            s.write_all(&[5, 2]).unwrap();
+
+            // the version of the username/password subnegotiation
+            let mut version = [0; 1];
+            s.read_exact(&mut version).unwrap();
+
+            let mut read_field = || {
+                let mut len = [0; 1];
+                s.read_exact(&mut len).unwrap();
+                let mut field = vec![0; len[0] as usize];
+                s.read_exact(&mut field).unwrap();
+
+                String::from_utf8(field).unwrap()
+            };
+
+            let user = read_field();
+            let pass = read_field();
+
+            // authentication failed
+            s.write_all(&[1, 1]).unwrap();
+
+            (methods, Some((user, pass)))
+        });
+
+        (addr, proxy)
+    }
+
+    #[test]
+    fn proxy_credentials_select_authentication() {
+        let (proxy, stub) = refusing_proxy();
+        assert!(proxy_connect(proxy, "example.onion:50505", &None).is_err());
+        assert_eq!(stub.join().unwrap(), (vec![0], None));
+
+        let auth = Some(("alice".to_string(), "secret".to_string()));
+        let (proxy, stub) = refusing_proxy();
+        assert!(proxy_connect(proxy, "example.onion:50505", &auth).is_err());
+
+        let (methods, sent) = stub.join().unwrap();
+        assert!(methods.contains(&2));
+        assert_eq!(sent, auth);
+    }
+
+    #[test]
+    fn lock_recovers_poisoned_mutex() {
+        let m = Arc::new(Mutex::new(1));
+
+        let cloned = Arc::clone(&m);
+        let _ = thread::spawn(move || {
+            let mut guard = cloned.lock().unwrap();
+            *guard = 2;
+
+            panic!("poisoning the lock");
+        })
+        .join();
+        assert!(m.is_poisoned());
+
+        assert_eq!(*lock(&m), 2);
+        assert!(!m.is_poisoned());
+    }
+
+    #[test]
+    fn verify_detects_corrupted_files() {
+        let dir = temp_dir("verify");
+        let mut config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        config.downloads = Some(dir.clone());
+
+        let chats = Chats::default();
+        let (_, mut peer) = accept_peer(&config, &chats, &Current::default(), &History::default());
+        let chat = Arc::clone(&lock(&chats)[0].2);
+        assert!(last_file_hash(&lock(&chat)).is_none());
+
+        peer.send_reader(&mut std::io::Cursor::new(b"data".to_vec()))
+            .unwrap();
+        peer.expect_hash().unwrap();
+
+        let (file, hash) = last_file_hash(&lock(&chat)).unwrap();
+        assert_eq!(file.peer_hash, Some(hash.unwrap()));
+
+        fs::write(&file.name, b"corrupted").unwrap();
+        let (_, hash) = last_file_hash(&lock(&chat)).unwrap();
+        assert_ne!(file.peer_hash, Some(hash.unwrap()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn overlong_lines_are_skipped() {
+        let mut input = vec![b'x'; MAX_LINE + 10];
+        input.extend_from_slice(b"\n/help\n");
+        let mut input = std::io::Cursor::new(input);
+        let mut buf = String::new();
+
+        assert!(!read_line_capped(&mut input, &mut buf).unwrap());
+        assert!(buf.is_empty());
+
+        // the rest of the line is not taken for the next one
+        assert!(read_line_capped(&mut input, &mut buf).unwrap());
+        assert_eq!(buf, "/help\n");
+    }
+
+    #[test]
+    fn invalid_utf8_lines_are_read_lossily() {
+        let mut input = std::io::Cursor::new(b"caf\xe9\nnext\n".to_vec());
+        let mut buf = String::new();
+
+        assert!(read_line_capped(&mut input, &mut buf).unwrap());
+        assert_eq!(buf, "caf\u{fffd}\n");
+
+        buf.clear();
+        assert!(read_line_capped(&mut input, &mut buf).unwrap());
+        assert_eq!(buf, "next\n");
+    }
+
+    #[test]
+    fn file_is_sent_to_all_chats_despite_failures() {
+        let dir = temp_dir("fileall");
+        let path = dir.join("file");
+        fs::write(&path, b"for everyone").unwrap();
+
+        // the peer of the first chat is gone
+        let chats = Chats::default();
+        lock(&chats).push((1, "gone".to_string(), chat()));
+
+        let mut peers = Vec::new();
+
+        for id in [2, 3] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let s = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let t = talkers::Talker::new_handshaked(listener.accept().unwrap().0);
+            lock(&chats).push((id, id.to_string(), Arc::new(Mutex::new(t))));
+
+            let downloads = dir.join(id.to_string());
+            peers.push(thread::spawn(move || {
+                let mut peer = talkers::Talker::new_handshaked(s);
+                peer.download_dir = Some(downloads);
+                peer.file_incoming = Box::new(|_| true);
+
+                match peer.read_once_detailed().unwrap() {
+                    talkers::ReadOutcome::File(name) => fs::read(name).unwrap(),
+                    outcome => panic!("unexpected outcome: {:?}", outcome),
+                }
+            }));
+        }
+
+        let results = send_file_to_all(&chats, path.to_str().unwrap());
+        assert_eq!(
+            results
+                .iter()
+                .map(|(id, res)| (*id, res.is_ok()))
+                .collect::<Vec<_>>(),
+            [(1, false), (2, true), (3, true)]
+        );
+
+        for peer in peers {
+            assert_eq!(peer.join().unwrap(), b"for everyone");
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn server_honors_poll_interval_and_max_file() {
+        let dir = temp_dir("server-config");
+        let mut config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        config.downloads = Some(dir.clone());
+        config.max_file = Some(10);
+        config.poll_interval = time::Duration::from_millis(200);
+
+        let (addr, shutdown, server) = serve(config);
+        let mut peer = connect_peer(addr);
+
+        peer.send("first").unwrap();
+        peer.expect_hash().unwrap();
+
+        // the chat is checked again only after the poll interval
+        let start = time::Instant::now();
+        peer.send("second").unwrap();
+        peer.expect_hash().unwrap();
+        assert!(start.elapsed() >= time::Duration::from_millis(100));
+
+        peer.send_reader(&mut std::io::Cursor::new(vec![0; 11]))
+            .unwrap();
+        peer.send_reader(&mut std::io::Cursor::new(vec![1; 10]))
+            .unwrap();
+
+        // only the file within the limit is stored
+        let stored = || {
+            fs::read_dir(&dir)
+                .unwrap()
+                .flat_map(|chat| fs::read_dir(chat.unwrap().path()).unwrap())
+                .map(|file| fs::read(file.unwrap().path()).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert!(wait_for(|| !stored().is_empty()));
+        assert_eq!(stored(), [vec![1; 10]]);
+
+        shutdown.store(true, Ordering::Relaxed);
+        server.join().unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn receive_limits_apply_to_chats() {
+        let mut config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        config.max_total_received = Some(8);
+
+        let chats = Chats::default();
+        let (_, mut peer) = accept_peer(&config, &chats, &Current::default(), &History::default());
+
+        peer.send("hello").unwrap();
+        peer.expect_hash().unwrap();
+
+        // the chat is closed once the limit is exceeded
+        peer.send("hello").unwrap();
+        assert!(peer.expect_hash().is_err());
+        assert!(wait_for(|| lock(&chats).is_empty()));
+    }
+
+    #[test]
+    fn history_survives_reconnect() {
+        let config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        let chats = Chats::default();
+        let current = Current::default();
+        let history = History::default();
+
+        let (_, mut first) = accept_peer(&config, &chats, &current, &history);
+        first.send("before").unwrap();
+        assert!(wait_for(|| !history.get("127.0.0.1:1").is_empty()));
+        drop(first);
+
+        // the peer reconnects from another port
+        let (id, mut second) = accept_peer(&config, &chats, &current, &history);
+        let reader = thread::spawn(move || second.read_once_detailed().unwrap());
+        send(Arc::clone(&chats), &history, id, "after").unwrap();
+        reader.join().unwrap();
+
+        assert_eq!(
+            history.get("127.0.0.1:50505"),
+            [(false, "before".to_string()), (true, "after".to_string())]
+        );
+
+        // host names are kept as given
+        history.log("example.onion:50505", false, "onion\n");
+        assert_eq!(
+            history.get("example.onion:50505"),
+            [(false, "onion".to_string())]
+        );
+        assert!(history.get("example.onion:50506").is_empty());
+    }
+
+    /// A writer that records how much had been written when it was last flushed.
+    #[derive(Default)]
+    struct Recorder {
+        written: Vec<u8>,
+        flushed: usize,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.written.extend_from_slice(buf);
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.flushed = self.written.len();
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_is_flushed_after_writing() {
+        let mut out = Recorder::default();
+
+        say_to(&mut out, format_args!("{} > {}", 3, "hello"));
+
+        assert_eq!(out.written, b"3 > hello\n");
+        assert_eq!(out.flushed, out.written.len());
+    }
+
+    fn hash_of(msg: &str) -> [u8; 32] {
+        use sha2::Digest;
+
+        sha2::Sha256::digest(msg.as_bytes()).into()
+    }
+
+    #[test]
+    fn exchanged_messages_are_audited() {
+        let dir = temp_dir("audit");
+        let log = dir.join("audit.log");
+        let mut config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        config.audit = Some(log.clone());
+
+        let chats = Chats::default();
+        let history = History::default();
+        let (id, mut peer) = accept_peer(&config, &chats, &Current::default(), &history);
+
+        peer.send("from peer").unwrap();
+        peer.expect_hash().unwrap();
+        assert!(wait_for(|| log.exists()));
+
+        let reader = thread::spawn(move || peer.read_once_detailed().unwrap());
+        send(Arc::clone(&chats), &history, id, "from app").unwrap();
+        reader.join().unwrap();
+
+        let contents = fs::read_to_string(&log).unwrap();
+        let lines: Vec<Vec<&str>> = contents
+            .lines()
+            .map(|line| line.split(' ').collect())
+            .collect();
+        assert_eq!(lines.len(), 2);
+
+        for (fields, (direction, msg)) in lines
+            .iter()
+            .zip([("received", "from peer"), ("sent", "from app")])
+        {
+            let (secs, millis) = fields[0].split_once('.').unwrap();
+            assert!(secs.parse::<u64>().is_ok() && millis.len() == 3);
+            assert_eq!(fields[1], id.to_string());
+            assert_eq!(fields[2], direction);
+            assert_eq!(fields[3], talkers::hash_hex(&hash_of(msg)));
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn slow_peers_hold_up_only_their_own_chat() {
+        let config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        let chats = Chats::default();
+        let history = History::default();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // this peer never even completes the handshake
+        let _silent = TcpStream::connect(addr).unwrap();
+        let (s, from) = listener.accept().unwrap();
+
+        let start = time::Instant::now();
+        new_connection(
+            s,
+            &from.to_string(),
+            Arc::clone(&chats),
+            false,
+            &config,
+            Current::default(),
+            history.clone(),
+        );
+        assert!(start.elapsed() < CAPABILITIES_WAIT);
+
+        let peer = thread::spawn(move || {
+            let mut peer = talkers::Talker::connect(addr).unwrap();
+            peer.send("early").unwrap();
+            peer.exchange_capabilities().unwrap();
+
+            peer
+        });
+
+        let (s, from) = listener.accept().unwrap();
+        new_connection(
+            s,
+            &from.to_string(),
+            Arc::clone(&chats),
+            false,
+            &config,
+            Current::default(),
+            history.clone(),
+        );
+        let _peer = peer.join().unwrap();
+
+        // a message sent before the capabilities is not lost
+        assert!(wait_for(|| !history.get(&from.to_string()).is_empty()));
+        assert_eq!(
+            history.get(&from.to_string()),
+            [(false, "early".to_string())]
+        );
+        assert!(start.elapsed() < CAPABILITIES_WAIT);
+        assert_eq!(lock(&chats).len(), 2);
+    }
+
+    #[test]
+    fn side_channel_carries_files_while_chatting() {
+        let dir = temp_dir("side");
+        let mut config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        config.downloads = Some(dir.clone());
+        config.side_channels = true;
+        config.poll_interval = time::Duration::from_millis(10);
+
+        let chats = Chats::default();
+        let history = History::default();
+        let (id, mut peer) = accept_peer(&config, &chats, &Current::default(), &history);
+        assert!(peer.peer_supports(talkers::Capability::SideChannels));
+
+        let data = vec![7; 4 * 1024 * 1024];
+        let filen = dir.join("big.bin");
+        fs::write(&filen, &data).unwrap();
+
+        let token = peer.announce_side_channel().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let side = thread::spawn(move || {
+            let mut side = talkers::Talker::connect(addr).unwrap();
+            side.join_side_channel(token).unwrap();
+            side.exchange_capabilities().unwrap();
+
+            side.send_file(filen.to_str().unwrap()).unwrap();
+            side.expect_hash().unwrap();
+        });
+
+        let (s, from) = listener.accept().unwrap();
+        let attached = new_connection(
+            s,
+            &from.to_string(),
+            Arc::clone(&chats),
+            false,
+            &config,
+            Current::default(),
+            history.clone(),
+        );
+
+        // the side channel does not remain a chat of its own
+        assert!(wait_for(|| lock(&chats)
+            .iter()
+            .all(|(i, _, _)| *i != attached)));
+
+        // the chat itself keeps going during the transfer
+        peer.send("still there?").unwrap();
+        peer.expect_hash().unwrap();
+
+        side.join().unwrap();
+
+        let received: Vec<_> = fs::read_dir(dir.join(id.to_string()))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(received.len(), 1);
+        assert_eq!(fs::read(&received[0]).unwrap(), data);
+        assert_eq!(lock(&chats).len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn server_stops_on_shutdown() {
+        let mut config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        config.poll_interval = time::Duration::from_millis(10);
+
+        let (addr, shutdown, server) = serve(config);
+        let mut peer = connect_peer(addr);
+
+        shutdown.store(true, Ordering::Relaxed);
+        server.join().unwrap();
+
+        // the chat has been closed and no one listens anymore
+        assert!(peer.read_once().is_err());
+        assert!(TcpStream::connect(addr).is_err());
+    }
 }
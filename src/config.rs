@@ -0,0 +1,181 @@
+//! Configuration of the app. With the `config` feature, it can be read from a TOML file such as this one:
+//!
+//! ```toml
+//! bind = "0.0.0.0:50505"
+//! proxy = "127.0.0.1:9150"
+//! proxy_user = "alice"
+//! proxy_pass = "secret"
+//! downloads = "received"
+//! max_conns = 16
+//! dual_stack = false
+//! poll_ms = 125
+//! max_file = 104857600
+//! max_msg_size = 65536
+//! max_chunked_size = 16777216
+//! max_total_received = 1073741824
+//! audit = "audit.log"
+//! heartbeat = 60
+//! side_channels = true
+//! ```
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+#[cfg(feature = "config")]
+use std::io::{Error, ErrorKind, Result};
+#[cfg(feature = "config")]
+use std::path::Path;
+
+/// The settings of the app. Unset values fall back to the defaults of the app.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize))]
+#[cfg_attr(feature = "config", serde(default, deny_unknown_fields))]
+pub struct Config {
+    /// The address to listen on.
+    pub bind: Option<SocketAddr>,
+    /// The SOCKS5 proxy to connect to peers with.
+    pub proxy: Option<SocketAddr>,
+    /// The username for the SOCKS5 proxy.
+    pub proxy_user: Option<String>,
+    /// The password for the SOCKS5 proxy.
+    pub proxy_pass: Option<String>,
+    /// The directory in which received files are stored.
+    pub downloads: Option<PathBuf>,
+    /// The maximum number of open chats while accepting connections.
+    pub max_conns: Option<usize>,
+    /// Whether to listen on `[::]` for both IPv4 and IPv6 peers.
+    pub dual_stack: Option<bool>,
+    /// The number of milliseconds between checks for incoming instructions on each chat.
+    pub poll_ms: Option<u64>,
+    /// The size of the largest file that is accepted, in octets.
+    pub max_file: Option<usize>,
+    /// The size of the largest message that is accepted, in octets.
+    pub max_msg_size: Option<usize>,
+    /// The size of the largest chunked message that is accepted once reassembled, in octets.
+    pub max_chunked_size: Option<usize>,
+    /// The number of payload octets that may be received over the lifetime of a chat.
+    pub max_total_received: Option<usize>,
+    /// The file to which the hashes of all messages and files sent and received are appended.
+    pub audit: Option<PathBuf>,
+    /// The number of seconds without traffic after which a chat is pinged.
+    pub heartbeat: Option<u64>,
+    /// Whether files are sent over a second connection to the peer where possible.
+    pub side_channels: Option<bool>,
+}
+
+impl Config {
+    /// Reads a config from a TOML file.
+    #[cfg(feature = "config")]
+    pub fn load(path: &Path) -> Result<Config> {
+        let contents = std::fs::read_to_string(path)?;
+
+        let config: Config = toml::from_str(&contents)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+
+        if config.heartbeat == Some(0) {
+            // as with `--heartbeat`, a chat would be pinged all the time
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "The heartbeat interval must be at least 1 second",
+            ));
+        }
+
+        Ok(config)
+    }
+
+    /// Returns this config with all values that are set in `other` replaced, e.g. to let command-line flags take precedence over a file.
+    pub fn overridden_by(self, other: Config) -> Config {
+        Config {
+            bind: other.bind.or(self.bind),
+            proxy: other.proxy.or(self.proxy),
+            proxy_user: other.proxy_user.or(self.proxy_user),
+            proxy_pass: other.proxy_pass.or(self.proxy_pass),
+            downloads: other.downloads.or(self.downloads),
+            max_conns: other.max_conns.or(self.max_conns),
+            dual_stack: other.dual_stack.or(self.dual_stack),
+            poll_ms: other.poll_ms.or(self.poll_ms),
+            max_file: other.max_file.or(self.max_file),
+            max_msg_size: other.max_msg_size.or(self.max_msg_size),
+            max_chunked_size: other.max_chunked_size.or(self.max_chunked_size),
+            max_total_received: other.max_total_received.or(self.max_total_received),
+            audit: other.audit.or(self.audit),
+            heartbeat: other.heartbeat.or(self.heartbeat),
+            side_channels: other.side_channels.or(self.side_channels),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_take_precedence_over_file() {
+        let file = Config {
+            bind: Some("0.0.0.0:50505".parse().unwrap()),
+            proxy: Some("127.0.0.1:9150".parse().unwrap()),
+            max_conns: Some(16),
+            side_channels: Some(true),
+            max_file: Some(1000),
+            max_msg_size: Some(100),
+            ..Config::default()
+        };
+        let cli = Config {
+            bind: Some("127.0.0.1:60606".parse().unwrap()),
+            max_conns: Some(2),
+            side_channels: Some(false),
+            max_file: Some(10),
+            poll_ms: Some(50),
+            ..Config::default()
+        };
+
+        let config = file.overridden_by(cli);
+
+        assert_eq!(config.bind, Some("127.0.0.1:60606".parse().unwrap()));
+        assert_eq!(config.max_conns, Some(2));
+        assert_eq!(config.side_channels, Some(false));
+        assert_eq!(config.max_file, Some(10));
+        assert_eq!(config.poll_ms, Some(50));
+
+        // values only set in the file are kept
+        assert_eq!(config.proxy, Some("127.0.0.1:9150".parse().unwrap()));
+        assert_eq!(config.max_msg_size, Some(100));
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn file_values_load() {
+        let path = std::env::temp_dir().join(format!("talkers-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "bind = \"0.0.0.0:50505\"\nmax_conns = 16\npoll_ms = 50\nmax_msg_size = 65536\n",
+        )
+        .unwrap();
+
+        let config = Config::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                bind: Some("0.0.0.0:50505".parse().unwrap()),
+                max_conns: Some(16),
+                poll_ms: Some(50),
+                max_msg_size: Some(65536),
+                ..Config::default()
+            }
+        );
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn zero_heartbeat_is_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("talkers-heartbeat-{}.toml", std::process::id()));
+        std::fs::write(&path, "heartbeat = 0\n").unwrap();
+
+        let res = Config::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -5,365 +5,6628 @@
 //! This library is in an early stage and very much a work in progress. There might be major breaking changes as well as missing features and bugs. All contributions and forks are appreciated.
 
 use std::cmp::min;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryInto;
-use std::fs::File;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher};
 use std::io::prelude::*;
-use std::io::{Error, ErrorKind, Result};
-use std::net::{Shutdown, TcpStream};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::{Cursor, Error, ErrorKind, Result, SeekFrom};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use sha2::Digest;
 
 type Hash = [u8; 32];
+type PublicKey = [u8; 32];
+type OfferCallback = Box<dyn Fn(usize, String, usize) + Send>;
+type Metadata = Vec<(String, String)>;
+type MetaCallback = Box<dyn Fn(Metadata, String) + Send>;
+type PartCallback = Box<dyn Fn(&[u8]) + Send>;
+type SpaceQuery = Box<dyn Fn(&Path) -> Option<u64> + Send>;
+type BatchCallback = Box<dyn Fn(usize, u64, u64) + Send>;
+type TransformCallback = Box<dyn Fn(&str) -> String + Send>;
+type Connection = (SocketAddr, SocketAddr);
+type MemoryFiles = HashMap<PathBuf, (Vec<u8>, Option<u32>)>;
+
+const MAX_MSG_SIZE: usize = 1024 * 1024;
+const MAX_CHUNKED_SIZE: usize = 64 * 1024 * 1024;
+const MAX_NAME_LEN: usize = 4096;
+const MAX_MSG_DIGITS: usize = 7; // enough for MAX_MSG_SIZE
+const MAX_BANNER_LEN: usize = 64;
+const MAX_META_LEN: usize = 4096;
+const MAX_NAME_TRIES: usize = 1000; // numbered variants tried before giving up
+const NAME_SUFFIX_LEN: usize = 12; // room for `.partial` and `_999`
+
+/// Instruction: a message (`!`).
+pub const INSTR_MESSAGE: u8 = 33;
+/// Instruction: the banner identifying the sender's software (`"`).
+pub const INSTR_BANNER: u8 = 34;
+/// Instruction: a file (`#`).
+pub const INSTR_FILE: u8 = 35;
+/// Instruction: the end of a chunked message (`$`).
+pub const INSTR_CHUNKS_END: u8 = 36;
+/// Instruction: a file whose hash is declared up front (`%`).
+pub const INSTR_FILE_HASH_FIRST: u8 = 37;
+/// Instruction: the sender's public key (`&`).
+pub const INSTR_PUBKEY: u8 = 38;
+/// Instruction: the permissions of the next file (`'`).
+pub const INSTR_FILE_MODE: u8 = 39;
+/// Instruction: a ping (`(`).
+pub const INSTR_PING: u8 = 40;
+/// Instruction: the answer to a ping (`)`).
+pub const INSTR_PONG: u8 = 41;
+/// Instruction: a message to be acknowledged by the receiving application (`*`).
+pub const INSTR_ACKED_MESSAGE: u8 = 42;
+/// Instruction: a chunk of a chunked message (`+`).
+pub const INSTR_CHUNK: u8 = 43;
+/// Instruction: a message with metadata (`;`).
+pub const INSTR_META_MESSAGE: u8 = 59;
+/// Instruction: a file offer is declined (`<`).
+pub const INSTR_OFFER_DECLINED: u8 = 60;
+/// Instruction: the hash of a message or file (`=`).
+pub const INSTR_HASH: u8 = 61;
+/// Instruction: a file offer is accepted (`>`).
+pub const INSTR_OFFER_ACCEPTED: u8 = 62;
+/// Instruction: a file offer (`?`).
+pub const INSTR_OFFER: u8 = 63;
+/// Instruction: the sender's capabilities (`@`).
+pub const INSTR_CAPABILITIES: u8 = 64;
+/// Instruction: a query of the receiver's limits (`[`).
+pub const INSTR_LIMITS_QUERY: u8 = 91;
+/// Instruction: the sender's limits (`]`).
+pub const INSTR_LIMITS: u8 = 93;
+/// Instruction: the acknowledgement of a message (`^`).
+pub const INSTR_ACK: u8 = 94;
+/// Instruction: a compressed message (`_`).
+pub const INSTR_COMPRESSED_MESSAGE: u8 = 95;
+/// Instruction: this connection is a side channel (`{`).
+pub const INSTR_SIDE_CHANNEL_JOIN: u8 = 123;
+/// Instruction: a side channel will be opened (`}`).
+pub const INSTR_SIDE_CHANNEL_ANNOUNCE: u8 = 125;
+/// Instruction: the signature of the preceding hash (`~`).
+pub const INSTR_SIGNATURE: u8 = 126;
+
+/// All instructions, which can be remapped using `Talker::remap_instructions`.
+const INSTRUCTIONS: [u8; 24] = [
+    INSTR_MESSAGE,
+    INSTR_BANNER,
+    INSTR_FILE,
+    INSTR_CHUNKS_END,
+    INSTR_FILE_HASH_FIRST,
+    INSTR_PUBKEY,
+    INSTR_FILE_MODE,
+    INSTR_PING,
+    INSTR_PONG,
+    INSTR_ACKED_MESSAGE,
+    INSTR_CHUNK,
+    INSTR_META_MESSAGE,
+    INSTR_OFFER_DECLINED,
+    INSTR_HASH,
+    INSTR_OFFER_ACCEPTED,
+    INSTR_OFFER,
+    INSTR_CAPABILITIES,
+    INSTR_LIMITS_QUERY,
+    INSTR_LIMITS,
+    INSTR_ACK,
+    INSTR_COMPRESSED_MESSAGE,
+    INSTR_SIDE_CHANNEL_JOIN,
+    INSTR_SIDE_CHANNEL_ANNOUNCE,
+    INSTR_SIGNATURE,
+];
+
+static TRANSFERS: AtomicUsize = AtomicUsize::new(0);
+static TRANSFER_IDS: AtomicUsize = AtomicUsize::new(0);
+
+/// The connections (by local and peer address) that `read_once` is currently reading from.
+static READING: Mutex<Vec<Connection>> = Mutex::new(Vec::new());
+
+/// Generates the name of a transfer file from the current time and a process-wide counter, so that names are unique even if two transfers start within the same clock tick.
+fn transfer_name(prefix: &str, suffix: &str) -> String {
+    format!(
+        "{}{}_{}{}",
+        prefix,
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos(),
+        TRANSFERS.fetch_add(1, Ordering::Relaxed),
+        suffix
+    )
+}
+
+/// Returns a random number that is the same for the entire process, suitable for `Talker::handshake_nonce`.
+pub fn process_nonce() -> u64 {
+    static NONCE: OnceLock<u64> = OnceLock::new();
+
+    *NONCE.get_or_init(|| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos(),
+        );
+
+        hasher.finish()
+    })
+}
+
+/// Formats a hash as the conventional lowercase hex digest of 64 characters, as printed e.g. by `sha256sum`.
+pub fn hash_hex(h: &Hash) -> String {
+    h.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Computes how many octets sending `msg` using `Talker::send` puts on the wire in total: the instruction, the decimal length, the newline, the message itself and the hash frame of 33 octets sent back by the peer. Header checksums and signatures, if enabled, add 1 and 65 octets, respectively.
+pub fn wire_size(msg: &str) -> usize {
+    1 + msg.len().to_string().len() + 1 + msg.len() + 33
+}
+
+/// Like `wire_size`, but for a file of `len` octets sent using `Talker::send_reader` or `Talker::send_stream`. The file is followed by our hash frame, and the peer sends one back, i.e. 66 octets of hashes in total.
+pub fn file_wire_size(len: u64) -> u64 {
+    1 + len.to_string().len() as u64 + 1 + len + 33 + 33
+}
+
+/// Runs `op`, retrying it up to `retries` times as long as it fails with `ErrorKind::Interrupted`.
+fn retry_interrupted<T, F: FnMut() -> Result<T>>(retries: usize, mut op: F) -> Result<T> {
+    let mut attempts = 0;
+
+    loop {
+        match op() {
+            Err(ref e) if e.kind() == ErrorKind::Interrupted && attempts < retries => attempts += 1,
+            res => return res,
+        }
+    }
+}
+
+/// The OS error codes with which a write can fail because it is too large for the socket, such as `EMSGSIZE` and `ENOBUFS`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const WRITE_TOO_LARGE: &[i32] = &[90, 105];
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+const WRITE_TOO_LARGE: &[i32] = &[40, 55];
+#[cfg(windows)]
+const WRITE_TOO_LARGE: &[i32] = &[10040, 10055];
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    windows
+)))]
+const WRITE_TOO_LARGE: &[i32] = &[];
+
+/// Writes some of `buf` to `w`, but at most `limit` octets at once. If the write is rejected as too large, it is retried with half as many octets, down to a single octet, and `limit` is lowered accordingly so that subsequent writes start out smaller. Returns the number of octets written.
+fn write_some<W: Write>(w: &mut W, buf: &[u8], limit: &mut usize) -> Result<usize> {
+    loop {
+        let len = min(buf.len(), *limit);
+
+        match w.write(&buf[..len]) {
+            Err(ref e)
+                if len > 1
+                    && (e.kind() == ErrorKind::OutOfMemory
+                        || e.raw_os_error()
+                            .is_some_and(|code| WRITE_TOO_LARGE.contains(&code))) =>
+            {
+                *limit = len / 2;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Computes the hash of everything that can be read from `r`.
+fn hash_reader<T: Read>(r: &mut T) -> Result<Hash> {
+    let mut hasher = sha2::Sha256::new();
+    let mut buf = [0; 1024];
+
+    loop {
+        let n = r.read(&mut buf)?;
+
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Computes the hash of a file, e.g. to compare a received file against the hash declared by the peer.
+pub fn hash_file<P: AsRef<Path>>(path: P) -> Result<Hash> {
+    hash_file_in(&RealFileSystem, path)
+}
+
+/// Computes the hash of a file in the given `FileSystem`, e.g. of a file received by a `Talker` with a custom `file_system`.
+pub fn hash_file_in<P: AsRef<Path>>(fs: &dyn FileSystem, path: P) -> Result<Hash> {
+    hash_reader(&mut fs.open(path.as_ref())?.reader)
+}
+
+/// Shortens `name` to at most `max` octets, keeping its extension (if any and if it fits). Names are cut at character boundaries, so the result may be slightly shorter.
+fn truncate_name(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+
+    let (stem, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot < max => name.split_at(dot),
+        _ => (name, ""),
+    };
+
+    let mut end = max - ext.len();
+
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{}", &stem[..end], ext)
+}
+
+/// The error returned by blocking methods when `Talker::should_continue` stops reading.
+fn stopped() -> Error {
+    Error::new(ErrorKind::Interrupted, "Reading stopped by should_continue")
+}
+
+/// Returns `name` with `_n` inserted before its extension (if any), e.g. `transfer_1.bin` becomes `transfer_1_2.bin` for `n = 2`.
+fn numbered(name: &str, n: usize) -> String {
+    let path = Path::new(name);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    let numbered = match path.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, n),
+    };
+
+    path.with_file_name(numbered).to_string_lossy().into_owned()
+}
+
+/// Creates the file in which a transfer to `name` is staged until it has been verified, named like `name` but ending in `.partial`. An existing file is never overwritten, a numbered name is used instead. Returns the name of the staging file along with the file itself.
+fn create_partial(fs: &dyn FileSystem, name: &str) -> (String, Result<Box<dyn TransferFile>>) {
+    let mut partial = String::new();
+
+    for n in 0..MAX_NAME_TRIES {
+        partial = match n {
+            0 => format!("{}.partial", name),
+            _ => format!("{}.partial", numbered(name, n)),
+        };
+
+        match fs.create_new(Path::new(&partial)) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            res => return (partial, res),
+        }
+    }
+
+    let e = Error::new(ErrorKind::AlreadyExists, "No unused file name");
+
+    (partial, Err(e))
+}
+
+/// Moves the verified staging file `partial` to `name`. An existing file is never replaced, a numbered variant of `name` is used instead. Returns the name the file was moved to.
+fn place_file(fs: &dyn FileSystem, partial: &str, name: &str) -> Result<String> {
+    for n in 0..MAX_NAME_TRIES {
+        let target = match n {
+            0 => name.to_string(),
+            _ => numbered(name, n),
+        };
+
+        match fs.rename_new(Path::new(partial), Path::new(&target)) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            res => return res.map(|_| target),
+        }
+    }
+
+    Err(Error::new(ErrorKind::AlreadyExists, "No unused file name"))
+}
+
+/// Checks whether a file name provided by the peer can safely be used as the name of a file in a directory of our choosing.
+fn safe_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['\0', '/', '\\'])
+}
+
+/// Decompresses a compressed message. Returns `None` if the message is not valid or would exceed `max` octets once decompressed.
+#[cfg(feature = "compress")]
+fn inflate(compressed: &[u8], max: usize) -> Option<Vec<u8>> {
+    let mut msg = Vec::new();
+
+    flate2::read::DeflateDecoder::new(compressed)
+        .take(max as u64 + 1)
+        .read_to_end(&mut msg)
+        .ok()?;
+
+    if msg.len() > max {
+        return None;
+    }
+
+    Some(msg)
+}
+
+/// Without the `compress` feature, compressed messages are not accepted in the first place.
+#[cfg(not(feature = "compress"))]
+fn inflate(_: &[u8], _: usize) -> Option<Vec<u8>> {
+    None
+}
+
+/// Allocates a file at `size` octets and maps it into memory for writing.
+#[cfg(feature = "memmap")]
+fn map_file(fp: &File, size: usize) -> Result<memmap2::MmapMut> {
+    fp.set_len(size as u64)?;
+
+    // the file was created for this transfer and is not modified by anyone else
+    unsafe { memmap2::MmapMut::map_mut(fp) }
+}
+
+/// Returns the octet that is sent for `instr` according to `map` (see `Talker::remap_instructions`).
+fn remapped(map: &[(u8, u8)], instr: u8) -> u8 {
+    map.iter()
+        .find(|&&(from, _)| from == instr)
+        .map_or(instr, |&(_, wire)| wire)
+}
+
+/// Checks whether a remapping of instructions only remaps instructions, each at most once, and whether all instructions remain distinguishable.
+fn valid_remapping(map: &[(u8, u8)]) -> bool {
+    let mut seen = [false; 256];
+
+    for (i, &(instr, _)) in map.iter().enumerate() {
+        if !INSTRUCTIONS.contains(&instr) || map[..i].iter().any(|&(from, _)| from == instr) {
+            return false;
+        }
+    }
+
+    INSTRUCTIONS.iter().all(|&instr| {
+        let wire = usize::from(remapped(map, instr));
+
+        !std::mem::replace(&mut seen[wire], true)
+    })
+}
+
+/// Checks whether `buf` plausibly starts with the frame of a message or file: an instruction followed by a length and a newline. `instr` is the instruction that the first octet stands for.
+fn plausible_frame(instr: Option<u8>, buf: &[u8]) -> bool {
+    if !matches!(
+        instr,
+        Some(
+            INSTR_MESSAGE
+                | INSTR_FILE
+                | INSTR_FILE_HASH_FIRST
+                | INSTR_ACKED_MESSAGE
+                | INSTR_CHUNK
+                | INSTR_META_MESSAGE
+                | INSTR_COMPRESSED_MESSAGE
+        )
+    ) {
+        return false;
+    }
+
+    let digits = buf[1..].iter().take_while(|c| c.is_ascii_digit()).count();
+
+    digits > 0 && buf.get(1 + digits) == Some(&b'\n')
+}
+
+/// Checks whether `banner` is acceptable as a banner (see `Talker::banner`).
+fn valid_banner(banner: &[u8]) -> bool {
+    !banner.is_empty()
+        && banner.len() <= MAX_BANNER_LEN
+        && banner.iter().all(|c| c.is_ascii_graphic() || *c == b' ')
+}
+
+/// Splits the payload of a message with metadata into the key-value pairs and the body. The payload starts with the length of the metadata block, followed by a newline and the block itself, which consists of `key=value` lines. Returns `None` if the payload is malformed.
+fn split_meta(payload: &[u8]) -> Option<(Metadata, &[u8])> {
+    let newline = payload.iter().take(5).position(|&c| c == b'\n')?;
+    let len: usize = std::str::from_utf8(&payload[..newline])
+        .ok()?
+        .parse()
+        .ok()?;
+
+    if len > MAX_META_LEN || payload.len() < newline + 1 + len {
+        return None;
+    }
+
+    let (meta, body) = payload[newline + 1..].split_at(len);
+    let meta = std::str::from_utf8(meta).ok()?;
+    let mut pairs = Vec::new();
+
+    for line in meta.lines() {
+        let (key, value) = line.split_once('=')?;
+
+        if key.is_empty() || line.contains(char::is_control) {
+            return None;
+        }
+
+        pairs.push((key.to_string(), value.to_string()));
+    }
+
+    Some((pairs, body))
+}
+
+/// A feature of the protocol that a peer may or may not support. Capabilities are exchanged using `Talker::exchange_capabilities`, so that we can avoid sending instructions the peer would not understand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// Messages that are acknowledged by the peer's application (`send_acked`).
+    AckedMessages = 1,
+    /// Messages sent in chunks (`send_chunked`).
+    ChunkedMessages = 2,
+    /// Files that are offered before they are sent (`offer_file`).
+    FileOffers = 4,
+    /// Files whose hash is declared up front (`send_reader_hash_first`).
+    HashFirst = 8,
+    /// Verification of signatures (requires the `sign` feature).
+    Signatures = 16,
+    /// Messages with metadata (`send_meta`).
+    Metadata = 32,
+    /// Pings that are answered automatically (`measure_rtt`).
+    Ping = 64,
+    /// Compressed messages (requires the `compress` feature, see `Talker::compress_above`).
+    Compression = 128,
+    /// Queries of the limits on what is accepted (`query_limits`).
+    Limits = 256,
+    /// Hashes are not exchanged (see `Talker::verify`). Unlike the other capabilities, this is only announced if verification is disabled.
+    Unverified = 512,
+    /// Side channels, i.e. additional connections for file transfers (`announce_side_channel`).
+    SideChannels = 1024,
+    /// Permissions (Unix mode bits) of files, which are applied by the receiver on Unix (`send_file`).
+    FileModes = 2048,
+}
+
+impl Capability {
+    /// Returns the set of capabilities that we support, as a bitmask.
+    pub fn ours() -> u64 {
+        let caps = Capability::AckedMessages as u64
+            | Capability::ChunkedMessages as u64
+            | Capability::FileOffers as u64
+            | Capability::HashFirst as u64
+            | Capability::Metadata as u64
+            | Capability::Ping as u64
+            | Capability::Limits as u64
+            | Capability::SideChannels as u64
+            | Capability::FileModes as u64;
+
+        #[cfg(feature = "sign")]
+        let caps = caps | Capability::Signatures as u64;
+
+        #[cfg(feature = "compress")]
+        let caps = caps | Capability::Compression as u64;
+
+        caps
+    }
+}
+
+/// A snapshot of where a `Talker` stands in the protocol, as reported by `Talker::state`. Meant for diagnosing connections that misbehave, e.g. a desynchronized stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TalkerState {
+    /// Nothing is pending, the next instruction from the peer is awaited.
+    Idle,
+    /// The peer's hashes of this many messages or files that we sent have not been received yet.
+    AwaitingHash(usize),
+    /// Part of a chunked message has been received (this many octets), the rest is yet to come.
+    ReadingChunks(usize),
+    /// An instruction has been read ahead (e.g. by `expect_hash`) and is processed by the next call to `read_once`.
+    Queued(u8),
+    /// The connection has been closed.
+    Closed,
+}
+
+/// How control characters in received messages are treated before they are delivered (see `Talker::sanitize`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sanitize {
+    /// Messages are delivered as received.
+    Off,
+    /// Control characters other than newlines and tabs are removed.
+    Strip,
+    /// Control characters other than newlines and tabs are replaced with escape sequences such as `\r` or `\u{1b}`, so that they are visible.
+    Escape,
+}
+
+/// A file that has been checked and hashed by `prepare_file`, but not sent yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileOffer {
+    /// The path of the file.
+    pub path: PathBuf,
+    /// The name of the file, without the directory.
+    pub name: String,
+    /// The size of the file in octets.
+    pub size: u64,
+    /// The hash of the file.
+    pub hash: Hash,
+}
+
+/// Checks that a file is readable and determines its name, size and hash without sending anything. This allows an app to confirm a (possibly long) transfer with the user before calling `Talker::send_prepared`.
+pub fn prepare_file<P: AsRef<Path>>(path: P) -> Result<FileOffer> {
+    prepare_file_in(&RealFileSystem, path)
+}
+
+/// Like `prepare_file`, but for a file in the given `FileSystem`, which should be the `file_system` of the `Talker` that sends it.
+pub fn prepare_file_in<P: AsRef<Path>>(fs: &dyn FileSystem, path: P) -> Result<FileOffer> {
+    let path = path.as_ref();
+    let mut fp = fs.open(path)?;
+
+    let size = fp.len;
+    let hash = hash_reader(&mut fp.reader)?;
+
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    Ok(FileOffer {
+        path: path.to_path_buf(),
+        name,
+        size,
+        hash,
+    })
+}
+
+/// A file that has been received completely, as recorded by `Talker::received_files`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReceivedFile {
+    /// Where the file has been stored.
+    pub name: String,
+    /// The size of the file in octets.
+    pub size: usize,
+    /// The hash the peer has sent along with the file, if any.
+    pub peer_hash: Option<Hash>,
+    /// The hash we have computed over the received file, unless hashes have been skipped because neither peer verifies (see `Talker::verify`).
+    pub our_hash: Option<Hash>,
+}
+
+/// The limits on what a peer accepts, as reported by `Talker::query_limits`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerLimits {
+    /// The size of the largest message accepted, in octets.
+    pub max_msg_size: usize,
+    /// The size of the largest file accepted, in octets, if limited (see `Talker::max_file_size`).
+    pub max_file_size: Option<usize>,
+}
+
+/// A file transfer in progress, as reported by `Talker::active_transfers`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransferInfo {
+    /// Where the file is being stored, if it is being received. Files being sent have no name on the wire.
+    pub name: Option<String>,
+    /// Whether the file is being received (true) or sent (false).
+    pub incoming: bool,
+    /// The announced size of the file in octets.
+    pub size: u64,
+    /// The number of octets transferred so far.
+    pub done: u64,
+}
+
+/// A handle to the file transfers in progress on a `Talker`. It can be queried from other threads while the `Talker` itself is busy transferring a file.
+#[derive(Clone, Debug, Default)]
+pub struct TransferMonitor(Arc<Mutex<Vec<(usize, TransferInfo)>>>);
+
+impl TransferMonitor {
+    /// Returns the file transfers in progress.
+    pub fn active_transfers(&self) -> Vec<TransferInfo> {
+        self.lock().iter().map(|(_, info)| info.clone()).collect()
+    }
+
+    /// Registers a new transfer, which is removed once the returned `Transfer` is dropped.
+    fn start(&self, name: Option<String>, incoming: bool, size: u64) -> Transfer {
+        let id = TRANSFER_IDS.fetch_add(1, Ordering::Relaxed);
+
+        self.lock().push((
+            id,
+            TransferInfo {
+                name,
+                incoming,
+                size,
+                done: 0,
+            },
+        ));
+
+        Transfer {
+            monitor: self.clone(),
+            id,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<(usize, TransferInfo)>> {
+        // the list stays consistent even if a callback has panicked
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A transfer registered with a `TransferMonitor`.
+struct Transfer {
+    monitor: TransferMonitor,
+    id: usize,
+}
+
+impl Transfer {
+    fn progress(&self, done: u64) {
+        if let Some((_, info)) = self
+            .monitor
+            .lock()
+            .iter_mut()
+            .find(|(id, _)| *id == self.id)
+        {
+            info.done = done;
+        }
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.monitor.lock().retain(|(id, _)| *id != self.id);
+    }
+}
+
+/// A file opened for sending by a `FileSystem`.
+pub struct SourceFile {
+    /// The contents of the file.
+    pub reader: Box<dyn Read + Send>,
+    /// The size of the file in octets.
+    pub len: u64,
+    /// The permissions of the file (Unix mode bits), if known.
+    pub mode: Option<u32>,
+}
+
+/// A file being received, as created by a `FileSystem`.
+pub trait TransferFile: Write + Send {
+    /// Synchronizes the file to disk (see `Talker::file_sync`). Does nothing by default.
+    fn sync_all(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the file on disk, if any, e.g. so that it can be memory-mapped (see `Talker::mmap_above`).
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+impl TransferFile for File {
+    fn sync_all(&self) -> Result<()> {
+        File::sync_all(self)
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+/// The file operations by which a `Talker` sends and receives files (see `Talker::file_system`). Besides `RealFileSystem`, which is used by default, there is `MemoryFileSystem`, which keeps files in memory, e.g. to test transfers without touching the disk.
+pub trait FileSystem: Send {
+    /// Opens a file for sending.
+    fn open(&self, path: &Path) -> Result<SourceFile>;
+
+    /// Creates a file for receiving. Fails with `ErrorKind::AlreadyExists` if the file exists.
+    fn create_new(&self, path: &Path) -> Result<Box<dyn TransferFile>>;
+
+    /// Opens a file for appending received data, creating it if it does not exist.
+    fn append(&self, path: &Path) -> Result<Box<dyn TransferFile>>;
+
+    /// Creates a directory along with its parents.
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+
+    /// Moves a file, replacing `to` if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Moves a file, but fails with `ErrorKind::AlreadyExists` if `to` exists.
+    fn rename_new(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Removes a file.
+    fn remove_file(&self, path: &Path) -> Result<()>;
+
+    /// Sets the permissions (Unix mode bits) of a file.
+    fn set_mode(&self, path: &Path, mode: u32) -> Result<()>;
+}
+
+/// The `FileSystem` that operates on actual files, which is used by default. Permissions are only supported on Unix.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {
+    fn open(&self, path: &Path) -> Result<SourceFile> {
+        let fp = File::open(path)?;
+        let meta = fp.metadata()?;
+
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+
+            Some(meta.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        Ok(SourceFile {
+            reader: Box::new(fp),
+            len: meta.len(),
+            mode,
+        })
+    }
+
+    fn create_new(&self, path: &Path) -> Result<Box<dyn TransferFile>> {
+        let fp = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+
+        Ok(Box::new(fp))
+    }
+
+    fn append(&self, path: &Path) -> Result<Box<dyn TransferFile>> {
+        let fp = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(Box::new(fp))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn rename_new(&self, from: &Path, to: &Path) -> Result<()> {
+        // unlike renaming, linking fails if the target exists
+        match fs::hard_link(from, to) {
+            Ok(()) => fs::remove_file(from),
+            Err(_) if to.exists() => Err(Error::from(ErrorKind::AlreadyExists)),
+            Err(_) => {
+                // e.g. the file system does not support hard links
+                fs::rename(from, to)
+            }
+        }
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path)
+    }
+
+    #[cfg(unix)]
+    fn set_mode(&self, path: &Path, mode: u32) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    #[cfg(not(unix))]
+    fn set_mode(&self, _: &Path, _: u32) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A `FileSystem` that keeps files in memory rather than on disk, e.g. to test file transfers. Clones share the same files. Directories are not kept track of, so files can be stored under any path.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFileSystem(Arc<Mutex<MemoryFiles>>);
+
+impl MemoryFileSystem {
+    /// Returns an empty file system.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores a file with the given contents, e.g. so that it can be sent. A file at the same path is replaced.
+    pub fn insert<P: Into<PathBuf>>(&self, path: P, contents: Vec<u8>) {
+        self.lock().insert(path.into(), (contents, None));
+    }
+
+    /// Returns the contents of a file, if it exists.
+    pub fn contents<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        self.lock()
+            .get(path.as_ref())
+            .map(|(contents, _)| contents.clone())
+    }
+
+    /// Returns the permissions of a file, if it exists and they have been set.
+    pub fn mode<P: AsRef<Path>>(&self, path: P) -> Option<u32> {
+        self.lock().get(path.as_ref()).and_then(|&(_, mode)| mode)
+    }
+
+    /// Returns the paths of all files, in no particular order.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.lock().keys().cloned().collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryFiles> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A file being received into a `MemoryFileSystem`.
+struct MemoryFile {
+    files: MemoryFileSystem,
+    path: PathBuf,
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self.files.lock().get_mut(&self.path) {
+            Some((contents, _)) => {
+                contents.extend_from_slice(buf);
+
+                Ok(buf.len())
+            }
+            None => Err(Error::new(ErrorKind::NotFound, "File has been removed")),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl TransferFile for MemoryFile {}
+
+impl FileSystem for MemoryFileSystem {
+    fn open(&self, path: &Path) -> Result<SourceFile> {
+        let (contents, mode) = self
+            .lock()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No such file"))?;
+
+        Ok(SourceFile {
+            len: contents.len() as u64,
+            reader: Box::new(Cursor::new(contents)),
+            mode,
+        })
+    }
+
+    fn create_new(&self, path: &Path) -> Result<Box<dyn TransferFile>> {
+        let mut files = self.lock();
+
+        if files.contains_key(path) {
+            return Err(Error::from(ErrorKind::AlreadyExists));
+        }
+
+        files.insert(path.to_path_buf(), (Vec::new(), None));
+
+        Ok(Box::new(MemoryFile {
+            files: self.clone(),
+            path: path.to_path_buf(),
+        }))
+    }
+
+    fn append(&self, path: &Path) -> Result<Box<dyn TransferFile>> {
+        self.lock().entry(path.to_path_buf()).or_default();
+
+        Ok(Box::new(MemoryFile {
+            files: self.clone(),
+            path: path.to_path_buf(),
+        }))
+    }
+
+    fn create_dir_all(&self, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut files = self.lock();
+
+        let file = files
+            .remove(from)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No such file"))?;
+        files.insert(to.to_path_buf(), file);
+
+        Ok(())
+    }
+
+    fn rename_new(&self, from: &Path, to: &Path) -> Result<()> {
+        if self.lock().contains_key(to) {
+            return Err(Error::from(ErrorKind::AlreadyExists));
+        }
+
+        self.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.lock()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No such file"))
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> Result<()> {
+        match self.lock().get_mut(path) {
+            Some((_, m)) => {
+                *m = Some(mode);
+
+                Ok(())
+            }
+            None => Err(Error::new(ErrorKind::NotFound, "No such file")),
+        }
+    }
+}
+
+/// Marks a connection as being read from until dropped, so that concurrent reads through several `Talker`s on the same connection (e.g. on cloned streams) are detected instead of interleaving.
+struct ReadGuard(Option<Connection>);
+
+impl ReadGuard {
+    fn acquire(conn: Option<Connection>) -> Result<Self> {
+        if let Some(conn) = conn {
+            let mut reading = READING.lock().unwrap_or_else(|e| e.into_inner());
+
+            if reading.contains(&conn) {
+                return Err(Error::other("Connection is already being read from"));
+            }
+
+            reading.push(conn);
+        }
+
+        Ok(ReadGuard(conn))
+    }
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        if let Some(conn) = self.0 {
+            let mut reading = READING.lock().unwrap_or_else(|e| e.into_inner());
+            reading.retain(|c| *c != conn);
+        }
+    }
+}
+
+/// Describes what has been read by `Talker::read_once_detailed`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReadOutcome {
+    /// No instruction was available (only possible in non-blocking mode, e.g. when using `read_maybe`).
+    Nothing,
+
+    /// Reading was stopped by `should_continue` before anything was read.
+    Stopped,
+
+    /// A message was received.
+    Message(String),
+
+    /// A message was received that is identical to one received recently (see `duplicate_window`).
+    Duplicate(String),
+
+    /// A message with metadata (see `Talker::send_meta`) was received. Contains the key-value pairs and the body.
+    Meta(Metadata, String),
+
+    /// A file was received and stored under the given name.
+    File(String),
+
+    /// A file was received, but it does not match the hash declared by the peer. It is left under the given temporary name (ending in `.partial`, unless `file_append` is set) instead of being renamed to its final name.
+    HashMismatch(String),
+
+    /// A file transfer of the given size was announced, but rejected by `file_incoming`.
+    FileRejected(usize),
+
+    /// The peer tried to send a message of the given size, which is too large. For a chunked message that exceeds `max_chunked_size`, this is returned for every remaining chunk and for its end, with the number of octets dropped so far.
+    PayloadTooLarge(usize),
+
+    /// A hash was received from the peer outside of `expect_hash`, e.g. for a message sent through the outbox.
+    Hash(Hash),
+
+    /// The peer announced its public key.
+    PublicKey(PublicKey),
+
+    /// The peer offered a file. Contains the id of the offer, the name of the file and its size.
+    FileOffered(usize, String, usize),
+
+    /// The peer announced its capabilities as a bitmask (see `Capability`).
+    Capabilities(u64),
+
+    /// The peer sent a ping, which has been answered.
+    Ping,
+
+    /// The peer answered our ping.
+    Pong,
+
+    /// The peer asked for our limits, which have been sent.
+    LimitsQueried,
+
+    /// The peer reported its limits.
+    Limits(PeerLimits),
+
+    /// The peer announced that it will open a side channel with the given token (see `Talker::announce_side_channel`).
+    SideChannelAnnounced(u64),
+
+    /// The peer declared this connection to be the side channel with the given token (see `Talker::join_side_channel`).
+    SideChannel(u64),
+
+    /// The peer announced the permissions (Unix mode bits) of the file it sends next (see `Talker::send_file`).
+    FileMode(u32),
+
+    /// The peer identified its software with the given banner (see `Talker::banner`).
+    Banner(String),
+
+    /// The peer accepted our file offer with the given id.
+    OfferAccepted(usize),
+
+    /// The peer declined our file offer with the given id.
+    OfferDeclined(usize),
+
+    /// The peer acknowledged (true) or rejected (false) a message sent with `send_acked`.
+    Ack(bool),
+
+    /// A chunk of a chunked message of the given size was received.
+    MessageChunk(usize),
+
+    /// A message of the given size was delivered piece by piece through `Talker::msg_part`.
+    MessageStreamed(usize),
+
+    /// A chunked message was completed.
+    MessageStream(String),
+
+    /// The peer sent a message or file whose length could not be parsed.
+    Malformed,
+
+    /// The peer sent an invalid instruction.
+    Invalid(u8),
+}
 
 /// This struct contains the connection to one *talkers* peer. It must be constructed with `Talker::new(s)`, but the callbacks in the public fields can be set directly.
 pub struct Talker {
     s: TcpStream,
     queue: Option<u8>,
+    hashes_pending: usize,
+    conn: Option<Connection>,
+    max_msg_size: usize,
+    peer_limits: Option<PeerLimits>,
+    oversized: usize,
+    file_mode: Option<u32>,
+    our_instrs: Vec<(u8, u8)>,
+    peer_instrs: Vec<(u8, u8)>,
     closed: bool,
+    msg_stream: Vec<u8>,
+    chunks_discarded: Option<(usize, sha2::Sha256)>,
+    outbox: VecDeque<Vec<u8>>,
+    outbox_pos: usize,
+    next_offer: usize,
+    offers: Vec<(usize, usize)>,
+    offers_accepted: VecDeque<(usize, usize, Option<PathBuf>)>,
+    side_tokens: Vec<u64>,
+    side_of: Option<u64>,
+    destination: Option<PathBuf>,
+    batch: Option<(usize, u64, u64)>,
+    handshake_sent: bool,
+    handshake_rcvd: bool,
+    received: Vec<ReceivedFile>,
+    paused: bool,
+    close_requested: bool,
+    transfers: TransferMonitor,
+    received_total: usize,
+    last_activity: Instant,
+    peer_caps: u64,
+    recent: VecDeque<Hash>,
+
+    /// The maximum number of messages held by the outbox (see `try_enqueue`).
+    pub outbox_capacity: usize,
+
+    /// A string identifying our software (e.g. `talkers-bot/1.2`), which is sent right after our half of the handshake. It must consist of at most 64 printable ASCII characters. Peers that do not know about banners report it as an invalid instruction, so it should only be set if all peers are expected to understand it. By default, no banner is sent.
+    pub banner: Option<String>,
+
+    /// The banner sent by the peer to identify its software, if any (see `banner`).
+    pub peer_banner: Option<String>,
+
+    /// A name for this connection (e.g. `alice-laptop`) to tell connections apart in logs. It is shown in the `Debug` output, but never sent to the peer. It can be changed at any time. By default, there is no label.
+    pub label: Option<String>,
+
+    /// Invoked if the peer's half of the handshake is invalid, with the eight octets received instead, e.g. to find out what kind of client has connected.
+    pub bad_handshake: Option<Box<dyn Fn([u8; 8]) + Send>>,
+
+    /// Invoked once after both halves of the handshake (`perform_handshake` and `expect_handshake`) have succeeded.
+    pub on_ready: Option<Box<dyn Fn() + Send>>,
+
+    /// Checked whenever `read_once` is called, before anything is read. If it returns false, `read_once` returns right away without touching the connection (`ReadOutcome::Stopped`), e.g. to stop a read loop on shutdown. Methods that block until the peer replies, such as `exchange_capabilities`, fail with `ErrorKind::Interrupted` instead. By default, reading always continues.
+    pub should_continue: Option<Box<dyn Fn() -> bool + Send>>,
+
+    /// Invoked when the connection is closed.
+    pub chat_close: Option<Box<dyn Fn() + Send>>,
+
+    /// Invoked when the peer has closed the connection, e.g. while we were reading or before we could echo the hash of a payload.
+    pub peer_closed: Option<Box<dyn Fn() + Send>>,
+
+    /// Invoked when a new message is received.
+    pub msg_new: Option<Box<dyn Fn(String) + Send>>,
+
+    /// Invoked when a message sent with `send_acked` has been received, after `msg_new`. Must return a bool indicating whether or not the message is accepted, which is reported back to the peer. By default, all messages are accepted.
+    pub msg_accept: Box<dyn Fn(&str) -> bool + Send>,
+
+    /// Invoked when the peer has acknowledged (true) or rejected (false) a message sent with `send_acked`.
+    pub msg_acked: Option<Box<dyn Fn(bool) + Send>>,
+
+    /// Invoked instead of `msg_new` when a message is received that is identical to one of the last `duplicate_window` messages, e.g. because it was retransmitted after a reconnect.
+    pub msg_duplicate: Option<Box<dyn Fn(String) + Send>>,
+
+    /// Invoked instead of `msg_new` when a message with metadata (see `send_meta`) has been received. Called with the key-value pairs and the body.
+    pub msg_meta: Option<MetaCallback>,
+
+    /// Whether control characters in received messages are removed or escaped before the messages are delivered, which prevents a peer from spoofing terminal output with ANSI escape sequences or carriage returns. By default, messages are delivered as received (`Sanitize::Off`).
+    pub sanitize: Sanitize,
+
+    /// The number of recently received messages that are remembered (by their hash) to detect duplicates. By default, this is 0, i.e. duplicates are not detected.
+    pub duplicate_window: usize,
+
+    /// The directory in which received files are stored. It is created if it does not exist. If `None`, files are stored in the current working directory.
+    pub download_dir: Option<PathBuf>,
+
+    /// The file operations by which files are sent and received. By default, these operate on actual files (`RealFileSystem`). Other implementations, such as `MemoryFileSystem`, allow testing transfers without touching the disk.
+    pub file_system: Box<dyn FileSystem>,
+
+    /// The beginning of the names of received files (default: `transfer_`).
+    pub file_prefix: String,
+
+    /// The end of the names of received files, e.g. an extension such as `.bin` (default: empty).
+    pub file_suffix: String,
+
+    /// If set, received files are not stored under names of their own, but appended to a single file named `file_prefix` followed by `file_suffix` (e.g. to accumulate a log). By default, this is not set.
+    pub file_append: bool,
+
+    /// If set, received files are named after their SHA-256 hash (as a hex digest followed by `file_suffix`) once the transfer is complete, so that files with identical content are stored only once. A file of that name with different content is not replaced, a numbered name is used instead. This does not apply to `file_append` and to files accepted with `accept_file_to`. By default, this is not set.
+    pub hash_names: bool,
+
+    /// The maximum length of the names of received files and of the names of files offered by the peer, in octets. Longer names are shortened, keeping the extension. Room is left for the suffixes that may be appended while storing a file (`.partial` and a number that makes the name unique), so longer names are shortened to 12 octets less than this. Many file systems do not support longer names than the default of 255 octets.
+    pub max_file_name: usize,
+
+    /// If set, received files are synchronized to disk (`File::sync_all`) before `file_complete` is invoked, so that completion means that the file is actually on disk. This is expensive and therefore not done by default.
+    pub file_sync: bool,
+
+    /// The maximum number of payload octets (of messages and files) that may be received over the lifetime of the connection. Once the peer announces a payload that would exceed it, the connection is closed and `read_once` returns an Err variant. By default, there is no limit.
+    pub max_total_received: Option<usize>,
+
+    /// The size of the largest file that is accepted, in octets. Larger transfers are rejected without consulting `file_incoming`. The limit is reported to peers that ask for it using `query_limits`. By default, there is no limit.
+    pub max_file_size: Option<usize>,
+
+    /// The maximum time that receiving a single file may take. If a transfer takes longer (e.g. because the peer trickles data on purpose), it is aborted, `file_failed` is invoked and the partial file is removed (unless `file_append` is set, in which case what has been received is left in place). By default, there is no limit.
+    pub max_transfer_duration: Option<Duration>,
+
+    /// Invoked when a file transfer has been announced by the peer. Called with the announced size. Must return a bool indicating whether or not to accept the file transfer. By default, file transfers are not accepted (except in the example app).
+    pub file_incoming: Box<dyn Fn(usize) -> bool + Send>,
+
+    /// Invoked when the peer offers a file (see `offer_file`). Called with the id of the offer, the name of the file and its size. The offer can be accepted with `accept_file` or declined with `decline_file` at any later time, so the decision need not be made inside the callback.
+    pub file_offered: Option<OfferCallback>,
+
+    /// Whether the names of files offered by the peer are checked before they are passed on. Names that are not valid UTF-8, contain null octets or path separators, or refer to a directory (such as `..`) are replaced with a generated name (see `file_prefix`) and reported to `file_name_rejected`. Enabled by default.
+    pub check_file_names: bool,
+
+    /// Invoked when the name of a file offered by the peer has been rejected (see `check_file_names`). Called with the rejected name, in which invalid UTF-8 sequences have been replaced with U+FFFD.
+    pub file_name_rejected: Option<Box<dyn Fn(String) + Send>>,
+
+    /// Invoked when the peer has accepted one of our file offers. Called with the id of the offer.
+    pub offer_accepted: Option<Box<dyn Fn(usize) + Send>>,
+
+    /// Invoked when the peer has declined one of our file offers. Called with the id of the offer.
+    pub offer_declined: Option<Box<dyn Fn(usize) + Send>>,
+
+    /// Invoked when the peer sends the file of an offer we accepted (see `accept_file`) before that of an offer we accepted earlier, whose file is then no longer expected. Called with the id of the earlier offer.
+    pub offer_skipped: Option<Box<dyn Fn(usize) + Send>>,
+
+    /// Determines how many octets are available in the given directory (the download directory, or its nearest existing ancestor if it has not been created yet), so that transfers that would not fit can be rejected before anything is written. If it returns `None`, transfers are not checked. If a transfer is rejected for lack of space, `file_failed` is invoked with the name of the directory. By default, the available space is queried from the operating system if the `space` feature is enabled, and not checked otherwise.
+    pub free_space: SpaceQuery,
+
+    /// Invoked when a file transfer has failed. Called with the name of the transfer file and the error.
+    pub file_failed: Option<Box<dyn Fn(String, Error) + Send>>,
+
+    /// Invoked when a file transfer has succeeded and the file has been verified. Called with the name of the transfer file. Until then, files are written under a temporary name ending in `.partial` (unless `file_append` is set), so that incomplete transfers are never mistaken for complete ones.
+    pub file_complete: Option<Box<dyn Fn(String) + Send>>,
+
+    /// Invoked upon learning the intended hash of the file from the peer.
+    pub file_hash_by_peer: Option<Box<dyn Fn(String, Hash) + Send>>,
+
+    /// Invoked upon having calculated the hash of the received file.
+    pub file_our_hash: Option<Box<dyn Fn(String, Hash) + Send>>,
+
+    /// Invoked while sending a file, after each chunk has been written. Called with the total number of octets sent so far and the time elapsed since the transfer started, from which the throughput can be computed.
+    pub send_progress: Option<Box<dyn Fn(usize, Duration) + Send>>,
+
+    /// Invoked while sending a batch of files with `send_files`, after each chunk has been written. Called with the index of the file being sent, the total number of octets of all files sent so far and the total size of the batch.
+    pub batch_progress: Option<BatchCallback>,
+
+    /// Invoked while sending a file if writing a single chunk took longer than `send_blocked_after`, e.g. because the link is congested. Called with the time the write took.
+    pub send_blocked: Option<Box<dyn Fn(Duration) + Send>>,
+
+    /// The time after which a chunk write is reported via `send_blocked` (default: 1 second).
+    pub send_blocked_after: Duration,
+
+    /// Invoked after each read from the socket with the number of octets read, e.g. for bandwidth accounting.
+    pub bytes_read: Option<Box<dyn Fn(usize) + Send>>,
+
+    /// Invoked after each write to the socket with the number of octets written.
+    pub bytes_written: Option<Box<dyn Fn(usize) + Send>>,
+
+    /// How often a read that was interrupted (`ErrorKind::Interrupted`) is retried before giving up (default: 3).
+    pub interrupted_retries: usize,
+
+    /// Invoked with the hash of the message or file that we sent.
+    pub hash_of_sent: Option<Box<dyn Fn(Hash) + Send>>,
+
+    /// Invoked upon receiving a hash from the peer.
+    pub hash_rcvd: Option<Box<dyn Fn(Hash) + Send>>,
+
+    /// Whether the integrity of messages and files is verified using hashes, which the receiver sends back to the sender. On a fast and trusted network, this can be disabled to save the time and computation involved. Hashes are only skipped if both peers have disabled this before exchanging capabilities (see `exchange_capabilities`) and no signatures are in use; `expect_hash` then returns right away. Enabled by default.
+    pub verify: bool,
+
+    /// Invoked with the hash of each message or file that we received, after it has been sent back to the peer.
+    pub hash_of_rcvd: Option<Box<dyn Fn(Hash) + Send>>,
+
+    /// Invoked if the peer tried to send a message or file that is too large.
+    pub payload_too_large: Option<Box<dyn Fn(usize) + Send>>,
+
+    /// Invoked like `payload_too_large`, but with the announced size and the number of oversized payloads the peer has sent on this connection so far (including this one). Useful to disconnect repeat offenders.
+    pub oversized_attempt: Option<Box<dyn Fn(usize, usize) + Send>>,
+
+    /// Invoked if the peer sent an invalid instruction. Useful for debugging.
+    pub invalid_instr: Option<Box<dyn Fn(u8) + Send>>,
+
+    /// Invoked when `expect_hash` has received some other instruction instead of a hash and puts it into the internal queue. Useful for debugging.
+    pub on_queued: Option<Box<dyn Fn(u8) + Send>>,
+
+    /// Invoked when `read_once` takes an instruction from the internal queue. Useful for debugging.
+    pub on_dequeued: Option<Box<dyn Fn(u8) + Send>>,
+
+    /// Invoked if the peer sent a frame with a malformed header, e.g. with an invalid length or checksum. Called with the instruction. Useful for debugging.
+    pub malformed_frame: Option<Box<dyn Fn(u8) + Send>>,
+
+    /// A number sent along with our half of the handshake to detect connections to ourselves, e.g. due to a misconfigured address. If the peer's half of the handshake carries the same number, the handshake fails with `ErrorKind::ConnectionRefused`. Use `process_nonce` to detect connections within the same process. Both peers must agree on whether this is set. Disabled by default.
+    pub handshake_nonce: Option<u64>,
+
+    /// Whether frame headers carry a checksum (the XOR of all octets of the header). This detects desynchronization early, but both peers must agree on this setting. Disabled by default.
+    pub header_checksum: bool,
+
+    /// The maximum number of digits of a number in a frame header (default: 15, i.e. files of up to approx. 1000 TB). Longer numbers make the header malformed, so that absurd lengths are rejected before anything is read or allocated. For messages, at most 7 digits are accepted regardless.
+    pub max_header_digits: usize,
+
+    /// Invoked before `msg_new` (or `msg_stream_complete`) if the received message was not valid UTF-8, such that invalid sequences had to be replaced with U+FFFD. Called with the message as it will be delivered.
+    pub msg_lossy: Option<Box<dyn Fn(String) + Send>>,
+
+    /// Invoked when a chunk of a chunked message has been received. Each chunk is valid UTF-8 by itself if the peer sent valid UTF-8.
+    pub msg_chunk: Option<Box<dyn Fn(String) + Send>>,
+
+    /// If set, messages sent using `send` are not buffered and delivered through `msg_new`, but passed to this callback piece by piece as they arrive, followed by a call to `msg_end`. The pieces are raw octets and need not be valid UTF-8 individually. Messages with metadata or acknowledgement are delivered as usual.
+    pub msg_part: Option<PartCallback>,
+
+    /// Invoked once a message delivered through `msg_part` is complete.
+    pub msg_end: Option<Box<dyn Fn() + Send>>,
+
+    /// Invoked when a chunked message has been completed. Called with the entire message.
+    pub msg_stream_complete: Option<Box<dyn Fn(String) + Send>>,
+
+    /// The maximum size of a chunked message (see `send_chunked`) once reassembled, in octets. If the peer's chunks exceed it, the message is dropped: `read_once` returns `PayloadTooLarge` and discards the remaining chunks. By default, this is 64 MiB.
+    pub max_chunked_size: usize,
+
+    /// Invoked upon receiving the peer's public key.
+    pub pubkey_rcvd: Option<Box<dyn Fn(PublicKey) + Send>>,
+
+    /// The public key announced by the peer, if any. Once the peer has announced a public key, every message and file it sends must be followed by a signature frame.
+    pub peer_pubkey: Option<PublicKey>,
+
+    /// Invoked upon receiving the peer's capabilities, as a bitmask (see `Capability`).
+    pub capabilities_rcvd: Option<Box<dyn Fn(u64) + Send>>,
+
+    /// The key used to sign the hash of every message and file we send. If set, our public key must be announced to the peer with `send_pubkey` before sending anything.
+    #[cfg(feature = "sign")]
+    pub signing_key: Option<ed25519_dalek::SigningKey>,
+
+    /// Invoked with the hash of a received message or file whose signature could not be verified against `peer_pubkey`.
+    #[cfg(feature = "sign")]
+    pub signature_invalid: Option<Box<dyn Fn(Hash) + Send>>,
+
+    /// Invoked with the CRC32 of each received message or file, in addition to its hash. A CRC32 is cheap to compute and can serve as a quick check for obviously corrupt payloads.
+    #[cfg(feature = "crc")]
+    pub payload_crc: Option<Box<dyn Fn(u32) + Send>>,
+
+    /// Applied by `send` to every message before it is sent, e.g. to expand commands or translate. The transformed text is what is sent and hashed, and must not exceed 1048576 octets. By default, messages are sent as given.
+    pub send_transform: Option<TransformCallback>,
+
+    /// Messages sent using `send` that are longer than this many octets are compressed, provided that the peer supports `Capability::Compression` (see `exchange_capabilities`). The peer computes the hash over the original message. By default, messages are not compressed.
+    #[cfg(feature = "compress")]
+    pub compress_above: Option<usize>,
+
+    /// Received files of more than this many octets are allocated at their announced size up front and memory-mapped, so that their contents are read from the connection directly into the file rather than through a buffer. This saves copying for very large files. The hash is computed over the mapped region. Files received with `file_append` are never mapped. By default, no files are mapped.
+    #[cfg(feature = "memmap")]
+    pub mmap_above: Option<usize>,
+}
+
+impl Talker {
+    /// Constructs a new `Talker` instance from a TcpStream. The callbacks are set to "do nothing", and to reject file transfers.
+    pub fn new(s: TcpStream) -> Self {
+        // identifies the connection even if the stream has been cloned
+        let conn = s.local_addr().ok().zip(s.peer_addr().ok());
+
+        Talker {
+            s,
+            queue: None,
+            hashes_pending: 0,
+            conn,
+            max_msg_size: MAX_MSG_SIZE,
+            peer_limits: None,
+            oversized: 0,
+            file_mode: None,
+            our_instrs: Vec::new(),
+            peer_instrs: Vec::new(),
+            closed: false, // assumes that the connection is initially open
+            msg_stream: Vec::new(),
+            chunks_discarded: None,
+            outbox: VecDeque::new(),
+            outbox_pos: 0,
+            next_offer: 1,
+            offers: Vec::new(),
+            offers_accepted: VecDeque::new(),
+            destination: None,
+            side_tokens: Vec::new(),
+            side_of: None,
+            batch: None,
+            outbox_capacity: 64,
+            handshake_sent: false,
+            handshake_rcvd: false,
+            received: Vec::new(),
+            paused: false,
+            close_requested: false,
+            transfers: TransferMonitor::default(),
+            received_total: 0,
+            last_activity: Instant::now(),
+            peer_caps: 0,
+            recent: VecDeque::new(),
+            on_ready: None,
+            should_continue: None,
+            chat_close: None,
+            peer_closed: None,
+            msg_new: None,
+            msg_accept: Box::new(|_| true),
+            msg_acked: None,
+            msg_duplicate: None,
+            msg_meta: None,
+            sanitize: Sanitize::Off,
+            duplicate_window: 0,
+            download_dir: None,
+            file_system: Box::new(RealFileSystem),
+            file_prefix: String::from("transfer_"),
+            file_suffix: String::new(),
+            file_append: false,
+            hash_names: false,
+            max_file_name: 255,
+            file_sync: false,
+            max_total_received: None,
+            max_file_size: None,
+            max_transfer_duration: None,
+            file_incoming: Box::new(|_| false),
+            #[cfg(feature = "space")]
+            free_space: Box::new(|dir| fs2::available_space(dir).ok()),
+            #[cfg(not(feature = "space"))]
+            free_space: Box::new(|_| None),
+            file_offered: None,
+            check_file_names: true,
+            file_name_rejected: None,
+            offer_accepted: None,
+            offer_declined: None,
+            offer_skipped: None,
+            file_failed: None,
+            file_complete: None,
+            file_hash_by_peer: None,
+            file_our_hash: None,
+            send_progress: None,
+            batch_progress: None,
+            send_blocked: None,
+            send_blocked_after: Duration::from_secs(1),
+            bytes_read: None,
+            bytes_written: None,
+            interrupted_retries: 3,
+            hash_of_sent: None,
+            hash_rcvd: None,
+            hash_of_rcvd: None,
+            verify: true,
+            invalid_instr: None,
+            on_queued: None,
+            on_dequeued: None,
+            payload_too_large: None,
+            oversized_attempt: None,
+            malformed_frame: None,
+            handshake_nonce: None,
+            header_checksum: false,
+            max_header_digits: 15,
+            msg_lossy: None,
+            msg_chunk: None,
+            msg_part: None,
+            msg_end: None,
+            msg_stream_complete: None,
+            max_chunked_size: MAX_CHUNKED_SIZE,
+            pubkey_rcvd: None,
+            peer_pubkey: None,
+            banner: None,
+            peer_banner: None,
+            label: None,
+            bad_handshake: None,
+            capabilities_rcvd: None,
+            #[cfg(feature = "sign")]
+            signing_key: None,
+            #[cfg(feature = "sign")]
+            signature_invalid: None,
+            #[cfg(feature = "crc")]
+            payload_crc: None,
+            #[cfg(feature = "compress")]
+            compress_above: None,
+            #[cfg(feature = "memmap")]
+            mmap_above: None,
+            send_transform: None,
+        }
+    }
+
+    /// Shuts down the connection with a *talkers* peer. Afterwards, `read_once` returns an Err variant, even if the peer has sent more.
+    pub fn close(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        self.closed = true;
+
+        if let Some(ref f) = self.chat_close {
+            f();
+        }
+
+        self.s.shutdown(Shutdown::Both)
+    }
+
+    /// Requests the connection to be closed once the instruction that is currently being received has been processed. Unlike `close`, this does not interrupt a transfer in progress: `read_once` keeps processing what the peer has already sent and closes the connection as soon as nothing is pending, returning an Err variant from then on. Likewise, sending finishes a frame that has been sent only in part, then closes the connection instead of sending anything new.
+    pub fn request_close(&mut self) {
+        self.close_requested = true;
+    }
+
+    /// Closes the connection after `request_close`, once the frame that is currently being sent is complete. Always returns an Err variant, since nothing is to be read or sent anymore.
+    fn close_on_request<T>(&mut self) -> Result<T> {
+        self.flush_outbox()?;
+        self.close()?;
+
+        Err(Error::new(
+            ErrorKind::NotConnected,
+            "Connection closed on request",
+        ))
+    }
+
+    /// Sets the linger option (`SO_LINGER`) of the underlying socket. With `Some(duration)`, closing the socket waits for up to `duration` until pending data has been sent; with `None`, the operating system's default behavior applies.
+    pub fn set_linger(&self, dur: Option<Duration>) -> Result<()> {
+        socket2::SockRef::from(&self.s).set_linger(dur)
+    }
+
+    /// Returns the linger option of the underlying socket (see `set_linger`).
+    pub fn linger(&self) -> Result<Option<Duration>> {
+        socket2::SockRef::from(&self.s).linger()
+    }
+
+    /// Processes any instructions still in flight (e.g. the peer's hash of a file we just sent) until the peer has been quiet for `quiet`, and shuts down the connection afterwards. Unlike `close`, this ensures that a final hash is delivered to `hash_rcvd`.
+    pub fn close_after_drain(&mut self, quiet: Duration) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        self.s.set_nonblocking(false)?;
+        self.s.set_read_timeout(Some(quiet))?;
+
+        loop {
+            match self.read_once_detailed() {
+                Ok(ReadOutcome::Nothing | ReadOutcome::Stopped) | Err(_) => break,
+                Ok(_) => continue,
+            }
+        }
+
+        let _ = self.s.set_read_timeout(None);
+
+        self.close()
+    }
+
+    /// Reads from the *talkers* peer and checks whether the buffer read is a *talkers* handshake. Should be invoked if a connection was made with us.
+    pub fn expect_handshake(&mut self) -> Result<()> {
+        let mut buf = [0; 8];
+
+        self.recv_exact(&mut buf)?;
+
+        if &buf == b"/talkers" || &buf == b"/talkerm" {
+            let remapped = buf[7] == b'm';
+
+            if let Some(nonce) = self.handshake_nonce {
+                self.recv_exact(&mut buf)?;
+
+                if u64::from_be_bytes(buf) == nonce {
+                    return Err(Error::new(
+                        ErrorKind::ConnectionRefused,
+                        "Connected to self",
+                    ));
+                }
+            }
+
+            if remapped {
+                // the peer sends some instructions as other octets
+                let mut count = [0; 1];
+                self.recv_exact(&mut count)?;
+
+                let mut pairs = vec![0; 2 * usize::from(count[0])];
+                self.recv_exact(&mut pairs)?;
+
+                let map: Vec<(u8, u8)> = pairs.chunks(2).map(|p| (p[0], p[1])).collect();
+
+                if !valid_remapping(&map) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Invalid remapping of instructions",
+                    ));
+                }
+
+                self.peer_instrs = map;
+            }
+
+            if !self.handshake_rcvd {
+                self.handshake_rcvd = true;
+
+                if self.handshake_sent {
+                    self.ready();
+                }
+            }
+
+            Ok(())
+        } else {
+            if let Some(ref f) = self.bad_handshake {
+                f(buf);
+            }
+
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid handshake `{}`", buf.escape_ascii()),
+            ))
+        }
+    }
+
+    /// Performs our half of the *talkers* handshake with the peer. Should be invoked if we initiated the connection or if we received a handshake.
+    pub fn perform_handshake(&mut self) -> Result<()> {
+        let banner = match self.banner {
+            Some(ref banner) if !valid_banner(banner.as_bytes()) => {
+                return Err(Error::new(ErrorKind::InvalidInput, "Invalid banner"));
+            }
+            Some(ref banner) => {
+                let mut frame = self.header(INSTR_BANNER, banner.len());
+                frame.extend_from_slice(banner.as_bytes());
+                frame
+            }
+            None => Vec::new(),
+        };
+
+        if self.our_instrs.is_empty() {
+            self.send_all(b"/talkers")?;
+        } else {
+            self.send_all(b"/talkerm")?;
+        }
+
+        if let Some(nonce) = self.handshake_nonce {
+            self.send_all(&nonce.to_be_bytes())?;
+        }
+
+        if !self.our_instrs.is_empty() {
+            let mut map = vec![self.our_instrs.len() as u8];
+            map.extend(
+                self.our_instrs
+                    .iter()
+                    .flat_map(|&(instr, wire)| [instr, wire]),
+            );
+
+            self.send_all(&map)?;
+        }
+
+        self.send_all(&banner)?;
+
+        if !self.handshake_sent {
+            self.handshake_sent = true;
+
+            if self.handshake_rcvd {
+                self.ready();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends instructions as other octets than usual, e.g. to avoid octets that are reserved by a protocol over which *talkers* is tunneled. Each pair consists of an instruction (one of the `INSTR_` constants) and the octet that is sent for it instead. All instructions must remain distinguishable. The remapping is announced in our half of the handshake, so it must be set before the handshake; the peer then reads our instructions accordingly (and may remap its own independently). Peers that do not support remapping fail the handshake.
+    pub fn remap_instructions(&mut self, map: &[(u8, u8)]) -> Result<()> {
+        if self.handshake_sent {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Instructions cannot be remapped after the handshake",
+            ));
+        }
+
+        let map: Vec<(u8, u8)> = map
+            .iter()
+            .copied()
+            .filter(|&(instr, wire)| instr != wire)
+            .collect();
+
+        if !valid_remapping(&map) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Invalid remapping of instructions",
+            ));
+        }
+
+        self.our_instrs = map;
+
+        Ok(())
+    }
+
+    /// Returns the octet that we send for `instr`.
+    fn wire_of(&self, instr: u8) -> u8 {
+        remapped(&self.our_instrs, instr)
+    }
+
+    /// Returns the octet that the peer sends for `instr`.
+    fn peer_wire_of(&self, instr: u8) -> u8 {
+        remapped(&self.peer_instrs, instr)
+    }
+
+    /// Returns the instruction that an octet sent by the peer stands for, or `None` if the peer has remapped the instruction usually sent as this octet. Octets that are not instructions are returned unchanged.
+    fn instr_of(&self, wire: u8) -> Option<u8> {
+        if let Some(&(instr, _)) = self.peer_instrs.iter().find(|&&(_, w)| w == wire) {
+            Some(instr)
+        } else if self.peer_instrs.iter().any(|&(instr, _)| instr == wire) {
+            None
+        } else {
+            Some(wire)
+        }
+    }
+
+    /// Constructs a new `Talker` from a stream on which the connection has already been established by other means, e.g. a substream handed out by a multiplexer. No handshake is performed, and `is_established` holds right away.
+    pub fn new_handshaked(s: TcpStream) -> Self {
+        let mut t = Self::new(s);
+        t.handshake_sent = true;
+        t.handshake_rcvd = true;
+
+        t
+    }
+
+    /// Returns whether both halves of the handshake have been completed (or skipped using `new_handshaked`).
+    pub fn is_established(&self) -> bool {
+        self.handshake_sent && self.handshake_rcvd
+    }
+
+    /// Connects to `addr` and performs the handshake as the initiator. Returns a `Talker` that is ready to send. Note that `on_ready` has already been invoked at this point, so it is not of use with this method.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let mut t = Self::new(TcpStream::connect(addr)?);
+        t.handshake(true)?;
+
+        Ok(t)
+    }
+
+    /// Connects to `target` (e.g. an onion service such as `example.onion:50505`) through the SOCKS5 proxy at `proxy` (e.g. Tor on `127.0.0.1:9150`) and performs the handshake as the initiator, like `connect`. Requires the `socks` feature, which is enabled by default.
+    #[cfg(feature = "socks")]
+    pub fn connect_via_socks5(proxy: SocketAddr, target: &str) -> Result<Self> {
+        let s = socks::Socks5Stream::connect(proxy, target)?.into_inner();
+
+        let mut t = Self::new(s);
+        t.handshake(true)?;
+
+        Ok(t)
+    }
+
+    /// Accepts a connection from `listener` and performs the handshake as the acceptor. Returns a `Talker` that is ready to send.
+    pub fn accept(listener: &TcpListener) -> Result<Self> {
+        let mut t = Self::new(listener.accept()?.0);
+        t.handshake(false)?;
+
+        Ok(t)
+    }
+
+    /// Performs both halves of the *talkers* handshake in the right order: if we initiated the connection, our half is sent before the peer's half is expected, and vice versa. `expect_handshake` and `perform_handshake` remain available for advanced use.
+    pub fn handshake(&mut self, initiated_by_us: bool) -> Result<()> {
+        let res = if initiated_by_us {
+            self.perform_handshake()
+                .and_then(|_| self.expect_handshake())
+        } else {
+            self.expect_handshake()
+                .and_then(|_| self.perform_handshake())
+        };
+
+        res.map_err(|e| Error::new(e.kind(), format!("Handshake failed: {}", e)))
+    }
+
+    /// Invoked once both halves of the handshake have succeeded.
+    fn ready(&self) {
+        if let Some(ref f) = self.on_ready {
+            f();
+        }
+    }
+
+    /// Stops processing incoming instructions: until `resume` is called, `read_once` returns `Ok(false)` right away and leaves the socket untouched, so that anything the peer sends is buffered.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Resumes processing incoming instructions after `pause`.
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Returns whether reading is paused (see `pause`).
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Delivers received messages through a channel that holds at most `capacity` of them, replacing `msg_new`. The consumer drains the channel at its own pace. Once it is full, `read_once` blocks until there is room again, so that the peer is slowed down rather than messages piling up in memory. Messages are discarded once the receiver has been dropped.
+    pub fn inbox(&mut self, capacity: usize) -> Receiver<String> {
+        let (tx, rx) = mpsc::sync_channel(capacity);
+
+        self.msg_new = Some(Box::new(move |msg| {
+            // blocks while the inbox is full
+            let _ = tx.send(msg);
+        }));
+
+        rx
+    }
+
+    /// Reads precisely one instruction from the peer and process it accordingly. Fails (with `ErrorKind::Other`) if another `Talker` on the same connection (e.g. one constructed from a clone of the stream) is reading at the same time.
+    pub fn read_once(&mut self) -> Result<bool> {
+        self.read_once_detailed().map(|outcome| {
+            !matches!(
+                outcome,
+                ReadOutcome::Nothing | ReadOutcome::Stopped | ReadOutcome::Invalid(_)
+            )
+        })
+    }
+
+    /// Like `read_once`, but returns a description of what has been read. This allows simple synchronous callers to do without callbacks. Note that callbacks are invoked nonetheless.
+    pub fn read_once_detailed(&mut self) -> Result<ReadOutcome> {
+        if self.closed {
+            // anything still buffered from the peer is not processed anymore
+            return Err(Error::new(ErrorKind::NotConnected, "Connection closed"));
+        }
+
+        if let Some(ref f) = self.should_continue {
+            if !f() {
+                return Ok(ReadOutcome::Stopped);
+            }
+        }
+
+        if self.paused {
+            return Ok(ReadOutcome::Nothing);
+        }
+
+        let _reading = ReadGuard::acquire(self.conn)?;
+
+        if self.close_requested {
+            // finish what the peer has already started to send
+            let mut buf = [0; 1];
+            let read_timeout = self.s.read_timeout()?;
+
+            // a short timeout rather than non-blocking mode, which cannot be queried to restore it
+            self.s.set_read_timeout(Some(Duration::from_millis(1)))?;
+            let peeked = self.s.peek(&mut buf);
+            self.s.set_read_timeout(read_timeout)?;
+
+            if self.queue.is_none() && !matches!(peeked, Ok(n) if n > 0) {
+                return self.close_on_request();
+            }
+        }
+
+        let mut instr = [0; 1];
+
+        if let Some(ch) = self.queue.take() {
+            instr[0] = ch;
+
+            if let Some(ref f) = self.on_dequeued {
+                f(ch);
+            }
+        } else {
+            let s = &mut self.s;
+
+            let n = match retry_interrupted(self.interrupted_retries, || s.read(&mut instr[0..1])) {
+                Ok(m) => m,
+                Err(e) => match e.kind() {
+                    ErrorKind::WouldBlock => return Ok(ReadOutcome::Nothing),
+                    _ => return Err(e),
+                },
+            };
+
+            if n == 0 {
+                if let Some(ref f) = self.peer_closed {
+                    f();
+                }
+
+                return Err(Error::new(
+                    ErrorKind::NotConnected,
+                    "Lost connection with peer",
+                ));
+            }
+
+            self.count_read(n);
+            self.last_activity = Instant::now();
+        }
+
+        let instr = match self.instr_of(instr[0]) {
+            Some(instr) => instr,
+            None => {
+                // an instruction that the peer has remapped to another octet
+                if let Some(ref f) = &self.invalid_instr {
+                    f(instr[0]);
+                }
+
+                return Ok(ReadOutcome::Invalid(instr[0]));
+            }
+        };
+
+        if instr == INSTR_HASH {
+            // hash, e.g. of a message sent through the outbox
+            return self.read_hash().map(ReadOutcome::Hash);
+        } else if instr == INSTR_PUBKEY {
+            // public key
+            return self.read_pubkey().map(ReadOutcome::PublicKey);
+        } else if instr == INSTR_CHUNK {
+            // message chunk
+            return self.read_chunk();
+        } else if instr == INSTR_CHUNKS_END {
+            // end of chunked message
+            return self.finish_chunks();
+        } else if instr == INSTR_OFFER {
+            // file offer
+            return self.read_offer();
+        } else if instr == INSTR_OFFER_ACCEPTED || instr == INSTR_OFFER_DECLINED {
+            // offer accepted or declined
+            return self.read_offer_reply(instr == INSTR_OFFER_ACCEPTED);
+        } else if instr == INSTR_CAPABILITIES {
+            // capabilities
+            return self.read_capabilities();
+        } else if instr == INSTR_BANNER {
+            // banner
+            return self.read_banner();
+        } else if instr == INSTR_PING {
+            // ping
+            self.send_all(&[self.wire_of(INSTR_PONG)])?;
+
+            return Ok(ReadOutcome::Ping);
+        } else if instr == INSTR_PONG {
+            // pong
+            return Ok(ReadOutcome::Pong);
+        } else if instr == INSTR_LIMITS_QUERY {
+            // query of our limits
+            self.send_limits()?;
+
+            return Ok(ReadOutcome::LimitsQueried);
+        } else if instr == INSTR_LIMITS {
+            // limits of the peer
+            return self.read_limits();
+        } else if instr == INSTR_FILE_MODE {
+            // permissions of the next file
+            return self.read_file_mode();
+        } else if instr == INSTR_SIDE_CHANNEL_ANNOUNCE || instr == INSTR_SIDE_CHANNEL_JOIN {
+            // side channel announced or joined
+            return self.read_side_channel(instr);
+        } else if instr == INSTR_ACK {
+            // acknowledgement of a message
+            return self.read_ack();
+        } else if instr != INSTR_MESSAGE
+            && instr != INSTR_FILE
+            && instr != INSTR_FILE_HASH_FIRST
+            && instr != INSTR_ACKED_MESSAGE
+            && instr != INSTR_META_MESSAGE
+            && !(cfg!(feature = "compress") && instr == INSTR_COMPRESSED_MESSAGE)
+        {
+            // neither message nor file
+            if let Some(ref f) = &self.invalid_instr {
+                f(instr);
+            }
+
+            return Ok(ReadOutcome::Invalid(instr));
+        }
+
+        let hash_first = instr == INSTR_FILE_HASH_FIRST;
+        let is_file = instr == INSTR_FILE || hash_first;
+        let skip = self.skip_hashes();
+        let mut hasher = sha2::Sha256::new();
+        #[cfg(feature = "crc")]
+        let mut crc = crc32fast::Hasher::new();
+        let mut filen = String::new();
+        let mut received = None;
+        let mode = if is_file { self.file_mode.take() } else { None };
+        let outcome;
+
+        self.s.set_nonblocking(false)?;
+
+        let len = self.read_length(instr)?;
+
+        if let Some(n_bytes) = len {
+            self.charge_received(n_bytes)?;
+        }
+
+        match len {
+            None => outcome = ReadOutcome::Malformed,
+            Some(n_bytes) if is_file && !self.accept_incoming(n_bytes) => {
+                // file rejected, but the stream must be kept in sync
+                let hash = if hash_first {
+                    self.drain(32)?;
+                    self.drain(n_bytes)?
+                } else {
+                    let hash = self.drain(n_bytes)?;
+
+                    if !skip {
+                        self.drain(33)?;
+                    }
+
+                    hash
+                };
+                self.check_signature(&hash)?;
+
+                outcome = ReadOutcome::FileRejected(n_bytes);
+            }
+            Some(mut n_bytes) if is_file => {
+                let size = n_bytes;
+                let mut peer_hash = None;
+
+                if hash_first {
+                    // the peer has declared the hash of the file up front
+                    let mut declared = [0; 32];
+                    self.recv_exact(&mut declared)?;
+
+                    peer_hash = Some(declared);
+                }
+
+                let name = if self.file_append {
+                    format!("{}{}", self.file_prefix, self.file_suffix)
+                } else {
+                    transfer_name(&self.file_prefix, &self.file_suffix)
+                };
+                let name = truncate_name(&name, self.max_stored_name());
+
+                let destination = self.destination.take();
+                let append = self.file_append && destination.is_none();
+                let by_hash = self.hash_names && !append && destination.is_none() && !skip;
+
+                filen = match (destination, &self.download_dir) {
+                    (Some(dest), _) => {
+                        if let Some(parent) = dest.parent() {
+                            let _ = self.file_system.create_dir_all(parent);
+                        }
+
+                        dest.to_string_lossy().into_owned()
+                    }
+                    (None, Some(dir)) => {
+                        let _ = self.file_system.create_dir_all(dir);
+
+                        dir.join(name).to_string_lossy().into_owned()
+                    }
+                    (None, None) => name,
+                };
+
+                let mut fp = None;
+
+                let (partial, opened) = if append {
+                    let opened = self.file_system.append(Path::new(&filen));
+
+                    (filen.clone(), opened)
+                } else {
+                    // fresh files are staged under a temporary name until verified
+                    create_partial(&*self.file_system, &filen)
+                };
+
+                if let Ok(f) = opened {
+                    fp = Some(f);
+                } else if let Some(ref f) = self.file_failed {
+                    f(
+                        filen.clone(),
+                        Error::new(ErrorKind::PermissionDenied, "Could not open transfer file"),
+                    );
+                }
+
+                #[cfg(feature = "memmap")]
+                let mut mapping = match (self.mmap_above, &fp) {
+                    (Some(above), Some(f)) if size > above && !append => {
+                        f.as_file().and_then(|f| map_file(f, size).ok())
+                    }
+                    _ => None,
+                };
+                #[cfg(not(feature = "memmap"))]
+                let mut mapping: Option<Vec<u8>> = None; // never mapped without the feature
+
+                let transfer = self.transfers.start(Some(filen.clone()), true, size as u64);
+                let mut buf = [0; 1024];
+
+                let started = Instant::now();
+                let read_timeout = self.s.read_timeout()?;
+
+                while n_bytes > 0 {
+                    // read from stream
+                    let n = min(n_bytes, 1024);
+                    let offset = size - n_bytes;
+
+                    let chunk = match mapping {
+                        Some(ref mut map) => &mut map[offset..offset + n],
+                        None => &mut buf[..n],
+                    };
+
+                    let res = match self.max_transfer_duration {
+                        Some(max) => match max.checked_sub(started.elapsed()) {
+                            Some(left) if !left.is_zero() => {
+                                // a single read must not exceed the budget either
+                                self.s.set_read_timeout(Some(left))?;
+                                self.recv_exact(chunk)
+                            }
+                            _ => Err(Error::from(ErrorKind::TimedOut)),
+                        },
+                        None => self.recv_exact(chunk),
+                    };
+
+                    if let Err(e) = res {
+                        let timed_out = self
+                            .max_transfer_duration
+                            .is_some_and(|max| started.elapsed() >= max);
+
+                        let _ = self.s.set_read_timeout(read_timeout);
+
+                        let e = if timed_out {
+                            // the partial file is of no use, unless it is shared with earlier transfers
+                            drop(fp);
+
+                            if !append {
+                                let _ = self.file_system.remove_file(Path::new(&partial));
+                            }
+
+                            Error::new(ErrorKind::TimedOut, "File transfer took too long")
+                        } else {
+                            Error::new(e.kind(), "Connection lost during transfer")
+                        };
+
+                        if let Some(ref f) = self.file_failed {
+                            f(filen, Error::new(e.kind(), e.to_string()));
+                        }
+
+                        return Err(e);
+                    }
+
+                    n_bytes -= n;
+                    transfer.progress((size - n_bytes) as u64);
+
+                    let chunk = match mapping {
+                        Some(ref map) => &map[offset..offset + n],
+                        None => &buf[..n],
+                    };
+
+                    if let (None, Some(ref mut fp)) = (&mapping, &mut fp) {
+                        if fp.write_all(chunk).is_err() {
+                            if let Some(ref f) = self.file_failed {
+                                f(
+                                    filen.clone(),
+                                    Error::new(
+                                        ErrorKind::PermissionDenied,
+                                        "Could not write to transfer file",
+                                    ),
+                                );
+                            }
+                        }
+                    }
+
+                    if !skip {
+                        hasher.update(chunk);
+                    }
+                    #[cfg(feature = "crc")]
+                    crc.update(chunk);
+                }
+
+                if self.max_transfer_duration.is_some() {
+                    self.s.set_read_timeout(read_timeout)?;
+                }
+
+                #[cfg(feature = "memmap")]
+                if let (true, Some(ref map)) = (self.file_sync, &mapping) {
+                    // the mapping is written back before the file is synchronized
+                    let _ = map.flush();
+                }
+
+                drop(mapping);
+
+                if let (true, Some(ref fp)) = (self.file_sync, &fp) {
+                    if let Err(e) = fp.sync_all() {
+                        if let Some(ref f) = self.file_failed {
+                            f(
+                                filen.clone(),
+                                Error::new(e.kind(), "Could not sync transfer file"),
+                            );
+                        }
+                    }
+                }
+
+                if !hash_first && !skip {
+                    if let Ok(()) = self.recv_exact(&mut buf[..33]) {
+                        peer_hash = buf[1..33].try_into().ok();
+                    }
+                }
+
+                if let (Some(hash), Some(ref f)) = (peer_hash, &self.file_hash_by_peer) {
+                    f(filen.clone(), hash);
+                }
+
+                received = Some((size, peer_hash));
+
+                let our_hash: Hash = hasher.clone().finalize().into();
+
+                self.check_signature(&our_hash)?;
+
+                if !skip && peer_hash != Some(our_hash) {
+                    // the unverified file keeps its temporary name
+                    if let Some(ref f) = self.file_failed {
+                        f(
+                            partial.clone(),
+                            Error::new(
+                                ErrorKind::InvalidData,
+                                "File does not match the hash declared by the peer",
+                            ),
+                        );
+                    }
+
+                    filen = partial.clone();
+                    outcome = ReadOutcome::HashMismatch(partial.clone());
+                } else {
+                    if by_hash {
+                        // identical content ends up under the same name
+                        let name = format!("{}{}", hash_hex(&our_hash), self.file_suffix);
+
+                        filen = match self.download_dir {
+                            Some(ref dir) => dir.join(name).to_string_lossy().into_owned(),
+                            None => name,
+                        };
+                    }
+
+                    if fp.take().is_some() && partial != filen {
+                        let stored = by_hash
+                            && hash_file_in(&*self.file_system, &filen)
+                                .is_ok_and(|h| h == our_hash);
+
+                        let placed = if stored {
+                            // identical content is stored only once
+                            self.file_system
+                                .remove_file(Path::new(&partial))
+                                .map(|_| filen.clone())
+                        } else {
+                            place_file(&*self.file_system, &partial, &filen)
+                        };
+
+                        match placed {
+                            Ok(placed) => filen = placed,
+                            Err(e) => {
+                                if let Some(ref f) = self.file_failed {
+                                    f(
+                                        partial.clone(),
+                                        Error::new(e.kind(), "Could not rename transfer file"),
+                                    );
+                                }
+                            }
+                        }
+                    }
+
+                    if let (Some(mode), false) = (mode, append) {
+                        // no setuid, setgid or sticky bits
+                        let res = self.file_system.set_mode(Path::new(&filen), mode & 0o777);
+
+                        if let Err(e) = res {
+                            if let Some(ref f) = self.file_failed {
+                                f(
+                                    filen.clone(),
+                                    Error::new(e.kind(), "Could not set file permissions"),
+                                );
+                            }
+                        }
+                    }
+
+                    if let Some(ref f) = self.file_complete {
+                        f(filen.clone());
+                    }
+
+                    outcome = ReadOutcome::File(filen.clone());
+                }
+            }
+            Some(n_bytes)
+                if n_bytes <= self.max_msg_size
+                    && instr == INSTR_MESSAGE
+                    && self.msg_part.is_some() =>
+            {
+                let mut buf = [0; 1024];
+                let mut left = n_bytes;
+
+                while left > 0 {
+                    let n = min(left, 1024);
+                    self.recv_exact(&mut buf[..n])?;
+
+                    if !skip {
+                        hasher.update(&buf[..n]);
+                    }
+                    #[cfg(feature = "crc")]
+                    crc.update(&buf[..n]);
+
+                    if let Some(ref f) = &self.msg_part {
+                        f(&buf[..n]);
+                    }
+
+                    left -= n;
+                }
+
+                let hash = hasher.clone().finalize().into();
+                self.check_signature(&hash)?;
+
+                if let Some(ref f) = &self.msg_end {
+                    f();
+                }
+
+                outcome = ReadOutcome::MessageStreamed(n_bytes);
+            }
+            Some(n_bytes) if n_bytes <= self.max_msg_size => {
+                let mut msg = vec![0; n_bytes];
+
+                let received = match self.recv_exact(&mut msg) {
+                    Ok(()) if instr == INSTR_COMPRESSED_MESSAGE => inflate(&msg, self.max_msg_size),
+                    Ok(()) => Some(msg),
+                    Err(_) => None,
+                };
+
+                if let Some(msg) = received {
+                    // duplicates are detected by their hash
+                    if !skip || self.duplicate_window > 0 {
+                        hasher.update(&msg);
+                    }
+                    #[cfg(feature = "crc")]
+                    crc.update(&msg);
+
+                    let hash = hasher.clone().finalize().into();
+                    self.check_signature(&hash)?;
+
+                    // message finished
+                    if instr == INSTR_META_MESSAGE {
+                        outcome = match split_meta(&msg) {
+                            Some((pairs, body)) => {
+                                let body = self.decode(body.to_vec());
+
+                                if let Some(ref f) = &self.msg_meta {
+                                    f(pairs.clone(), body.clone());
+                                }
+
+                                ReadOutcome::Meta(pairs, body)
+                            }
+                            None => ReadOutcome::Malformed,
+                        };
+                    } else {
+                        let msg = self.decode(msg);
+
+                        if self.seen_recently(hash) {
+                            if let Some(ref f) = &self.msg_duplicate {
+                                f(msg.clone());
+                            }
+
+                            outcome = ReadOutcome::Duplicate(msg);
+                        } else {
+                            if let Some(ref f) = &self.msg_new {
+                                f(msg.clone());
+                            }
+
+                            outcome = ReadOutcome::Message(msg);
+                        }
+                    }
+                } else {
+                    outcome = ReadOutcome::Malformed;
+                }
+            }
+            Some(n_bytes) => {
+                // payload too large, but the stream must be kept in sync
+                let hash = self.drain(n_bytes)?;
+                self.check_signature(&hash)?;
+                self.too_large(n_bytes);
+
+                outcome = ReadOutcome::PayloadTooLarge(n_bytes);
+            }
+        }
+
+        let our_hash: Hash = hasher.finalize().into();
+
+        if skip {
+            // the peer does not expect a hash
+        } else if let Err(e) = self.send_all(&self.hash_frame(&our_hash)) {
+            // the peer may have closed the connection right after sending
+            if is_file {
+                if let Some(ref f) = self.file_failed {
+                    f(filen, Error::new(e.kind(), "Could not send hash to peer"));
+                }
+            }
+
+            if let Some(ref f) = self.peer_closed {
+                f();
+            }
+
+            return Err(e);
+        }
+
+        let delivered = matches!(
+            outcome,
+            ReadOutcome::Message(_)
+                | ReadOutcome::Duplicate(_)
+                | ReadOutcome::Meta(..)
+                | ReadOutcome::MessageStreamed(_)
+                | ReadOutcome::File(_)
+                | ReadOutcome::HashMismatch(_)
+        );
+
+        if delivered && !skip {
+            if let Some(ref f) = self.hash_of_rcvd {
+                f(our_hash);
+            }
+        }
+
+        #[cfg(feature = "crc")]
+        if delivered {
+            if let Some(ref f) = self.payload_crc {
+                f(crc.finalize());
+            }
+        }
+
+        if instr == INSTR_ACKED_MESSAGE {
+            // the peer wants to know whether we accept the message
+            let accepted = match outcome {
+                ReadOutcome::Message(ref msg) | ReadOutcome::Duplicate(ref msg) => {
+                    (self.msg_accept)(msg)
+                }
+                _ => false,
+            };
+
+            self.send_all(&[self.wire_of(INSTR_ACK), if accepted { b'1' } else { b'0' }])?;
+        }
+
+        if let Some((size, peer_hash)) = received {
+            self.received.push(ReceivedFile {
+                name: filen.clone(),
+                size,
+                peer_hash,
+                our_hash: if skip { None } else { Some(our_hash) },
+            });
+        }
+
+        if is_file && !skip {
+            if let Some(ref f) = &self.file_our_hash {
+                f(filen, our_hash);
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    /// Returns how long the names of received files may be before `.partial` or a number is appended to them, so that the result does not exceed `max_file_name`.
+    fn max_stored_name(&self) -> usize {
+        self.max_file_name.saturating_sub(NAME_SUFFIX_LEN).max(1)
+    }
+
+    /// Decides whether to accept an incoming file transfer of `n_bytes` octets. Transfers that would not fit on disk are rejected. Transfers for which we have accepted an offer of the same size are accepted right away (and stored at the destination given to `accept_file_to`, if any), others are decided upon by `file_incoming`. Accepted offers that the peer has passed over in favor of a later one are given up and reported to `offer_skipped`.
+    fn accept_incoming(&mut self, n_bytes: usize) -> bool {
+        let offered = self
+            .offers_accepted
+            .iter()
+            .position(|&(_, size, _)| size == n_bytes);
+
+        self.destination = None;
+
+        if let Some(pos) = offered {
+            for (id, _, _) in self.offers_accepted.drain(..pos) {
+                if let Some(ref f) = self.offer_skipped {
+                    f(id);
+                }
+            }
+
+            if let Some((_, _, dest)) = self.offers_accepted.pop_front() {
+                self.destination = dest;
+            }
+        }
+
+        if self.max_file_size.is_some_and(|max| n_bytes > max) {
+            return false;
+        }
+
+        let dir = match self.destination {
+            Some(ref dest) => dest
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new(".")),
+            None => self
+                .download_dir
+                .as_deref()
+                .unwrap_or_else(|| Path::new(".")),
+        };
+
+        // the directory may not have been created yet, but it will be on the same file system as its nearest existing ancestor
+        let existing = dir
+            .ancestors()
+            .find(|dir| dir.is_dir())
+            .unwrap_or_else(|| Path::new("."));
+
+        if (self.free_space)(existing).is_some_and(|free| n_bytes as u64 > free) {
+            if let Some(ref f) = self.file_failed {
+                f(
+                    dir.to_string_lossy().into_owned(),
+                    Error::other("Insufficient space for transfer"),
+                );
+            }
+
+            return false;
+        }
+
+        offered.is_some() || (self.file_incoming)(n_bytes)
+    }
+
+    /// Reads a file offer, which consists of the offer id, the size of the file and its name.
+    fn read_offer(&mut self) -> Result<ReadOutcome> {
+        self.s.set_nonblocking(false)?;
+
+        let (id, size, name_len) = match self.read_header(INSTR_OFFER, 3)? {
+            Some(h) if h[2] <= MAX_NAME_LEN => (h[0], h[1], h[2]),
+            _ => return Ok(ReadOutcome::Malformed),
+        };
+
+        let mut name = vec![0; name_len];
+        self.recv_exact(&mut name)?;
+
+        let name = match String::from_utf8(name) {
+            Ok(name) if !self.check_file_names || safe_file_name(&name) => {
+                truncate_name(&name, self.max_stored_name())
+            }
+            res => {
+                let name = match res {
+                    Ok(name) => name,
+                    Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                };
+
+                if let Some(ref f) = self.file_name_rejected {
+                    f(name);
+                }
+
+                truncate_name(
+                    &transfer_name(&self.file_prefix, &self.file_suffix),
+                    self.max_stored_name(),
+                )
+            }
+        };
+
+        self.offers.push((id, size));
+
+        if let Some(ref f) = self.file_offered {
+            f(id, name.clone(), size);
+        }
+
+        Ok(ReadOutcome::FileOffered(id, name, size))
+    }
+
+    /// Reads the peer's reply to one of our file offers.
+    fn read_offer_reply(&mut self, accepted: bool) -> Result<ReadOutcome> {
+        self.s.set_nonblocking(false)?;
+
+        let id = match self.read_length(if accepted {
+            INSTR_OFFER_ACCEPTED
+        } else {
+            INSTR_OFFER_DECLINED
+        })? {
+            Some(id) => id,
+            None => return Ok(ReadOutcome::Malformed),
+        };
+
+        if accepted {
+            if let Some(ref f) = self.offer_accepted {
+                f(id);
+            }
+
+            Ok(ReadOutcome::OfferAccepted(id))
+        } else {
+            if let Some(ref f) = self.offer_declined {
+                f(id);
+            }
+
+            Ok(ReadOutcome::OfferDeclined(id))
+        }
+    }
+
+    /// Reads a chunk of a chunked message and appends it to the message stream.
+    fn read_chunk(&mut self) -> Result<ReadOutcome> {
+        self.s.set_nonblocking(false)?;
+
+        let n_bytes = match self.read_length(INSTR_CHUNK)? {
+            Some(n) => n,
+            None => return Ok(ReadOutcome::Malformed),
+        };
+
+        self.charge_received(n_bytes)?;
+
+        if let Some((discarded, mut hasher)) = self.chunks_discarded.take() {
+            // the rest of a message that was too large
+            self.drain_into(n_bytes, &mut hasher)?;
+            self.chunks_discarded = Some((discarded + n_bytes, hasher));
+
+            return Ok(ReadOutcome::PayloadTooLarge(discarded + n_bytes));
+        }
+
+        if n_bytes > self.max_msg_size {
+            // chunk too large, the message is dropped along with the chunks still to come
+            self.discard_chunks(n_bytes)?;
+            self.too_large(n_bytes);
+
+            return Ok(ReadOutcome::PayloadTooLarge(n_bytes));
+        }
+
+        let total = self.msg_stream.len() + n_bytes;
+
+        if total > self.max_chunked_size {
+            // reassembled message too large, drop it along with the chunks still to come
+            self.discard_chunks(n_bytes)?;
+            self.too_large(total);
+
+            return Ok(ReadOutcome::PayloadTooLarge(total));
+        }
+
+        let mut chunk = vec![0; n_bytes];
+        self.recv_exact(&mut chunk)?;
+
+        if let Some(ref f) = &self.msg_chunk {
+            f(String::from_utf8_lossy(&chunk).into_owned());
+        }
+
+        self.msg_stream.extend_from_slice(&chunk);
+
+        Ok(ReadOutcome::MessageChunk(n_bytes))
+    }
+
+    /// Drops the chunked message being received, whose next chunk of `n_bytes` octets is read and discarded, as are the chunks still to come. The hash of the entire message is kept so that it can be confirmed to the peer once the message ends.
+    fn discard_chunks(&mut self, n_bytes: usize) -> Result<()> {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(&self.msg_stream);
+        self.drain_into(n_bytes, &mut hasher)?;
+
+        let total = self.msg_stream.len() + n_bytes;
+        self.msg_stream = Vec::new();
+        self.chunks_discarded = Some((total, hasher));
+
+        Ok(())
+    }
+
+    /// Completes a chunked message and sends its hash to the peer.
+    fn finish_chunks(&mut self) -> Result<ReadOutcome> {
+        self.s.set_nonblocking(false)?;
+
+        if let Some((discarded, hasher)) = self.chunks_discarded.take() {
+            // the message is not delivered, but the peer still expects a hash
+            let hash: Hash = hasher.finalize().into();
+            self.check_signature(&hash)?;
+
+            if !self.skip_hashes() {
+                self.send_all(&self.hash_frame(&hash))?;
+            }
+
+            return Ok(ReadOutcome::PayloadTooLarge(discarded));
+        }
+
+        let msg = std::mem::take(&mut self.msg_stream);
+        let hash = sha2::Sha256::digest(&msg).into();
+
+        self.check_signature(&hash)?;
+
+        let msg = self.decode(msg);
+
+        if let Some(ref f) = &self.msg_stream_complete {
+            f(msg.clone());
+        }
+
+        if !self.skip_hashes() {
+            self.send_all(&self.hash_frame(&hash))?;
+
+            if let Some(ref f) = self.hash_of_rcvd {
+                f(hash);
+            }
+        }
+
+        Ok(ReadOutcome::MessageStream(msg))
+    }
+
+    /// Converts a received message to a String, replacing invalid UTF-8 sequences. Invokes `msg_lossy` if any replacement was necessary.
+    fn decode(&self, msg: Vec<u8>) -> String {
+        let msg = match String::from_utf8(msg) {
+            Ok(msg) => msg,
+            Err(e) => {
+                let msg = String::from_utf8_lossy(e.as_bytes()).into_owned();
+
+                if let Some(ref f) = &self.msg_lossy {
+                    f(msg.clone());
+                }
+
+                msg
+            }
+        };
+
+        let harmful = |c: char| c.is_control() && c != '\n' && c != '\t';
+
+        match self.sanitize {
+            Sanitize::Off => msg,
+            Sanitize::Strip => msg.chars().filter(|&c| !harmful(c)).collect(),
+            Sanitize::Escape => msg
+                .chars()
+                .map(|c| {
+                    if harmful(c) {
+                        c.escape_default().to_string()
+                    } else {
+                        c.to_string()
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Reads and discards `n_bytes` octets from the peer. Returns the hash of the discarded octets.
+    fn drain(&mut self, n_bytes: usize) -> Result<Hash> {
+        let mut hasher = sha2::Sha256::new();
+        self.drain_into(n_bytes, &mut hasher)?;
+
+        Ok(hasher.finalize().into())
+    }
+
+    /// Like `drain`, but adds the discarded octets to `hasher`.
+    fn drain_into(&mut self, mut n_bytes: usize, hasher: &mut sha2::Sha256) -> Result<()> {
+        let mut buf = [0; 1024];
+
+        while n_bytes > 0 {
+            let n = min(n_bytes, 1024);
+
+            self.recv_exact(&mut buf[..n])?;
+            hasher.update(&buf[..n]);
+
+            n_bytes -= n;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the length of a framed payload, which is terminated by a space or newline. Returns `None` if the length is malformed.
+    fn read_length(&mut self, instr: u8) -> Result<Option<usize>> {
+        Ok(self.read_header(instr, 1)?.map(|numbers| numbers[0]))
+    }
+
+    /// Reads the `count` numbers of a frame header, which are separated by spaces and terminated by a space or newline. Returns `None` if the header is malformed.
+    fn read_header(&mut self, instr: u8, count: usize) -> Result<Option<Vec<usize>>> {
+        let mut ch = [0; 1];
+        let mut numbers = Vec::with_capacity(count);
+        let mut n_bytes: usize = 0;
+        let mut digits = 0;
+        let mut checksum = self.peer_wire_of(instr);
+
+        let max_digits = match instr {
+            INSTR_MESSAGE
+            | INSTR_ACKED_MESSAGE
+            | INSTR_CHUNK
+            | INSTR_META_MESSAGE
+            | INSTR_COMPRESSED_MESSAGE => min(self.max_header_digits, MAX_MSG_DIGITS), // messages
+            _ => min(self.max_header_digits, 18), // more digits could overflow
+        };
+
+        while numbers.len() < count {
+            self.recv_exact(&mut ch)?;
+            checksum ^= ch[0];
+
+            if (ch[0] == 10 || ch[0] == 32) && digits > 0 {
+                numbers.push(n_bytes);
+                n_bytes = 0;
+                digits = 0;
+
+                if ch[0] == 10 && numbers.len() < count {
+                    // header ended prematurely
+                    break;
+                }
+            } else if (48..=57).contains(&ch[0]) && digits < max_digits {
+                n_bytes *= 10;
+                n_bytes += usize::from(ch[0] - 48);
+                digits += 1;
+            } else {
+                break;
+            }
+        }
+
+        if numbers.len() < count {
+            self.malformed(instr);
+
+            return Ok(None);
+        }
+
+        if self.header_checksum {
+            self.recv_exact(&mut ch)?;
+
+            if ch[0] != checksum {
+                self.malformed(instr);
+
+                return Err(Error::new(ErrorKind::InvalidData, "Corrupted frame header"));
+            }
+        }
+
+        Ok(Some(numbers))
+    }
+
+    /// Adds a payload of `n_bytes` octets announced by the peer to the total received so far. If this would exceed `max_total_received`, the connection is closed and an Err variant is returned instead.
+    fn charge_received(&mut self, n_bytes: usize) -> Result<()> {
+        let total = self.received_total.saturating_add(n_bytes);
+
+        if self.max_total_received.is_some_and(|max| total > max) {
+            self.close()?;
+
+            return Err(Error::new(
+                ErrorKind::ConnectionAborted,
+                "Peer exceeded the limit on received data",
+            ));
+        }
+
+        self.received_total = total;
+
+        Ok(())
+    }
+
+    /// Reads exactly `buf.len()` octets from the peer and reports them to `bytes_read`.
+    fn recv_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.s.read_exact(buf)?;
+        self.count_read(buf.len());
+        self.last_activity = Instant::now();
+
+        Ok(())
+    }
+
+    /// Writes all of `buf` to the peer and reports it to `bytes_written`. If `pump_out` left a frame of the outbox partially written, that frame is completed first, so that `buf` does not end up in the middle of it. This blocks until everything has been written, even if the stream is non-blocking.
+    fn send_all(&mut self, buf: &[u8]) -> Result<()> {
+        if self.outbox_pos > 0 {
+            if let Some(frame) = self.outbox.pop_front() {
+                let pos = std::mem::replace(&mut self.outbox_pos, 0);
+                self.write_out(&frame[pos..])?;
+            }
+        }
+
+        self.write_out(buf)
+    }
+
+    /// Writes all of `buf` to the peer as `send_all` does, but without regard to the outbox.
+    fn write_out(&mut self, buf: &[u8]) -> Result<()> {
+        let mut rest = buf;
+        let mut blocked = false;
+        let mut limit = buf.len();
+
+        let res = loop {
+            if rest.is_empty() {
+                break Ok(());
+            }
+
+            match write_some(&mut self.s, rest, &mut limit) {
+                Ok(0) => break Err(Error::new(ErrorKind::WriteZero, "Could not write to peer")),
+                Ok(n) => rest = &rest[n..],
+                Err(e) if e.kind() == ErrorKind::WouldBlock && !blocked => {
+                    // e.g. when replying from `read_maybe` while the send buffer is full
+                    self.s.set_nonblocking(false)?;
+                    blocked = true;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+
+        if blocked {
+            self.s.set_nonblocking(true)?;
+        }
+
+        res?;
+        self.count_written(buf.len());
+        self.last_activity = Instant::now();
+
+        Ok(())
+    }
+
+    /// Records that the peer is to send back the hash of something we sent, and invokes `hash_of_sent`.
+    fn sent(&mut self, hash: Hash) {
+        if !self.skip_hashes() {
+            self.hashes_pending += 1;
+        }
+
+        if let Some(ref f) = self.hash_of_sent {
+            f(hash);
+        }
+    }
+
+    /// Invokes `bytes_read`.
+    fn count_read(&self, n: usize) {
+        if let Some(ref f) = self.bytes_read {
+            f(n);
+        }
+    }
+
+    /// Invokes `bytes_written`.
+    fn count_written(&self, n: usize) {
+        if let Some(ref f) = self.bytes_written {
+            f(n);
+        }
+    }
+
+    /// Invokes `malformed_frame`.
+    fn malformed(&self, instr: u8) {
+        if let Some(ref f) = self.malformed_frame {
+            f(instr);
+        }
+    }
+
+    /// Builds the header of a framed instruction, i.e. the instruction, the length of the payload and a newline. If `header_checksum` is set, a checksum follows.
+    fn header<U: std::fmt::Display>(&self, instr: u8, len: U) -> Vec<u8> {
+        let mut header = vec![self.wire_of(instr)];
+        header.extend_from_slice(format!("{}\n", len).as_bytes());
+
+        if self.header_checksum {
+            header.push(header.iter().fold(0, |acc, ch| acc ^ ch));
+        }
+
+        header
+    }
+
+    /// Reads the hash that follows a hash instruction and invokes `hash_rcvd`.
+    fn read_hash(&mut self) -> Result<Hash> {
+        self.s.set_nonblocking(false)?;
+
+        let mut hash = [0; 32];
+        self.recv_exact(&mut hash)?;
+        self.hashes_pending = self.hashes_pending.saturating_sub(1);
+
+        if let Some(ref f) = self.hash_rcvd {
+            f(hash);
+        }
+
+        Ok(hash)
+    }
+
+    /// Reads the peer's acknowledgement of a message sent with `send_acked`.
+    fn read_ack(&mut self) -> Result<ReadOutcome> {
+        self.s.set_nonblocking(false)?;
+
+        let mut ack = [0; 1];
+        self.recv_exact(&mut ack)?;
+
+        let accepted = match ack[0] {
+            b'1' => true,
+            b'0' => false,
+            _ => {
+                self.malformed(INSTR_ACK);
+
+                return Ok(ReadOutcome::Malformed);
+            }
+        };
+
+        if let Some(ref f) = self.msg_acked {
+            f(accepted);
+        }
+
+        Ok(ReadOutcome::Ack(accepted))
+    }
+
+    /// Returns whether a message with the given hash is among the last `duplicate_window` messages received, and remembers it otherwise.
+    fn seen_recently(&mut self, hash: Hash) -> bool {
+        if self.recent.contains(&hash) {
+            return true;
+        }
+
+        self.recent.push_back(hash);
+
+        while self.recent.len() > self.duplicate_window {
+            self.recent.pop_front();
+        }
+
+        false
+    }
+
+    /// Reads the capabilities announced by the peer.
+    fn read_capabilities(&mut self) -> Result<ReadOutcome> {
+        self.s.set_nonblocking(false)?;
+
+        let caps = match self.read_length(INSTR_CAPABILITIES)? {
+            Some(caps) => caps as u64,
+            None => return Ok(ReadOutcome::Malformed),
+        };
+
+        self.peer_caps = caps;
+
+        if let Some(ref f) = self.capabilities_rcvd {
+            f(caps);
+        }
+
+        Ok(ReadOutcome::Capabilities(caps))
+    }
+
+    /// Reads the limits reported by the peer: the maximum message size and the maximum file size, which is 0 if unlimited.
+    fn read_limits(&mut self) -> Result<ReadOutcome> {
+        self.s.set_nonblocking(false)?;
+
+        let limits = match self.read_header(INSTR_LIMITS, 2)? {
+            Some(h) => PeerLimits {
+                max_msg_size: h[0],
+                max_file_size: Some(h[1]).filter(|&size| size > 0),
+            },
+            None => return Ok(ReadOutcome::Malformed),
+        };
+
+        self.peer_limits = Some(limits);
+
+        Ok(ReadOutcome::Limits(limits))
+    }
+
+    /// Reads the permissions of the file that the peer sends next.
+    fn read_file_mode(&mut self) -> Result<ReadOutcome> {
+        self.s.set_nonblocking(false)?;
+
+        let mode = match self
+            .read_length(INSTR_FILE_MODE)?
+            .and_then(|mode| mode.try_into().ok())
+        {
+            Some(mode) => mode,
+            None => return Ok(ReadOutcome::Malformed),
+        };
+
+        self.file_mode = Some(mode);
+
+        Ok(ReadOutcome::FileMode(mode))
+    }
+
+    /// Reads the token of a side channel, which the peer either announces (`}`) or declares this connection to belong to (`{`).
+    fn read_side_channel(&mut self, instr: u8) -> Result<ReadOutcome> {
+        self.s.set_nonblocking(false)?;
+
+        let token = match self.read_length(instr)? {
+            Some(token) => token as u64,
+            None => return Ok(ReadOutcome::Malformed),
+        };
+
+        if instr == INSTR_SIDE_CHANNEL_ANNOUNCE {
+            self.side_tokens.push(token);
+
+            Ok(ReadOutcome::SideChannelAnnounced(token))
+        } else {
+            self.side_of = Some(token);
+
+            Ok(ReadOutcome::SideChannel(token))
+        }
+    }
+
+    /// Reads the banner identifying the peer's software. Banners that are too long or contain anything but printable ASCII characters are discarded.
+    fn read_banner(&mut self) -> Result<ReadOutcome> {
+        self.s.set_nonblocking(false)?;
+
+        let len = match self.read_length(INSTR_BANNER)? {
+            Some(len) => len,
+            None => return Ok(ReadOutcome::Malformed),
+        };
+
+        if len > MAX_BANNER_LEN {
+            // the stream must be kept in sync
+            self.drain(len)?;
+
+            return Ok(ReadOutcome::Malformed);
+        }
+
+        let mut banner = vec![0; len];
+        self.recv_exact(&mut banner)?;
+
+        if !valid_banner(&banner) {
+            return Ok(ReadOutcome::Malformed);
+        }
+
+        let banner = String::from_utf8_lossy(&banner).into_owned();
+        self.peer_banner = Some(banner.clone());
+
+        Ok(ReadOutcome::Banner(banner))
+    }
+
+    /// Reads a public key announced by the peer. The key must be framed with its length, which has to be 32.
+    fn read_pubkey(&mut self) -> Result<PublicKey> {
+        self.s.set_nonblocking(false)?;
+
+        if self.read_length(INSTR_PUBKEY)? != Some(32) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid public key length",
+            ));
+        }
+
+        let mut key = [0; 32];
+        self.recv_exact(&mut key)?;
+
+        self.peer_pubkey = Some(key);
+
+        if let Some(ref f) = self.pubkey_rcvd {
+            f(key);
+        }
+
+        Ok(key)
+    }
+
+    /// Returns whether hashes are neither computed nor exchanged, which requires that both peers have disabled `verify` and that no signatures are in use.
+    fn skip_hashes(&self) -> bool {
+        #[cfg(feature = "sign")]
+        if self.signing_key.is_some() {
+            return false;
+        }
+
+        !self.verify && self.peer_supports(Capability::Unverified) && self.peer_pubkey.is_none()
+    }
+
+    /// Reads the signature frame that follows a payload if the peer has announced a public key, and verifies it against the hash of the payload. Invokes `signature_invalid` if verification fails. Without the `sign` feature, the signature is consumed but not verified.
+    fn check_signature(&mut self, hash: &Hash) -> Result<()> {
+        let key = match self.peer_pubkey {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        let mut buf = [0; 65];
+        self.recv_exact(&mut buf)?;
+
+        if self.instr_of(buf[0]) != Some(INSTR_SIGNATURE) {
+            return Err(Error::new(ErrorKind::InvalidData, "Missing signature"));
+        }
+
+        #[cfg(feature = "sign")]
+        {
+            use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+            let sig = Signature::from_bytes(buf[1..65].try_into().unwrap());
+            let valid = VerifyingKey::from_bytes(&key)
+                .map(|k| k.verify(hash, &sig).is_ok())
+                .unwrap_or(false);
+
+            if !valid {
+                if let Some(ref f) = self.signature_invalid {
+                    f(*hash);
+                }
+            }
+        }
+
+        #[cfg(not(feature = "sign"))]
+        let _ = (key, hash);
+
+        Ok(())
+    }
+
+    /// Signs the hash of a payload with `signing_key` and sends the resulting signature frame. Does nothing if no signing key is configured.
+    fn send_signature(&mut self, hash: &Hash) -> Result<()> {
+        let frame = self.signature_frame(hash);
+
+        self.send_all(&frame)
+    }
+
+    /// Returns the signature frame for the hash of a payload, which is empty if no signing key is configured.
+    fn signature_frame(&self, hash: &Hash) -> Vec<u8> {
+        #[cfg(feature = "sign")]
+        {
+            use ed25519_dalek::Signer;
+
+            if let Some(ref key) = self.signing_key {
+                let mut frame = vec![self.wire_of(INSTR_SIGNATURE)];
+                frame.extend_from_slice(&key.sign(hash).to_bytes());
+
+                return frame;
+            }
+        }
+
+        #[cfg(not(feature = "sign"))]
+        let _ = hash;
+
+        Vec::new()
+    }
+
+    /// Tries to recover from a desynchronized stream, e.g. after a malformed frame, instead of closing the connection. Octets that have already arrived are discarded until one of them plausibly starts a message or file, i.e. it is followed by a length and a newline. This is a heuristic. Returns whether such a boundary has been found, in which case the next `read_once` processes the instruction found. Does not wait for more data to arrive.
+    pub fn resync(&mut self) -> Result<bool> {
+        self.queue = None;
+        self.msg_stream.clear();
+        self.chunks_discarded = None;
+
+        self.s.set_nonblocking(true)?;
+
+        let mut buf = [0; 32];
+
+        let found = loop {
+            let n = match self.s.peek(&mut buf) {
+                Ok(0) => break false,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break false,
+                Err(e) => {
+                    self.s.set_nonblocking(false)?;
+
+                    return Err(e);
+                }
+            };
+
+            if plausible_frame(self.instr_of(buf[0]), &buf[..n]) {
+                break true;
+            }
+
+            // discard a single octet and look again
+            self.recv_exact(&mut buf[..1])?;
+        };
+
+        self.s.set_nonblocking(false)?;
+
+        Ok(found)
+    }
+
+    /// Sets the TCP connection to non-blocking and invokes `read_once`. This has the effect that a instruction might be read from the peer or not. If one is read, it will be processed in blocking mode. If not, this function returns immediately without blocking. Useful if called in a loop. Note that each invocation reads and processes at most one instruction.
+    pub fn read_maybe(&mut self) -> Result<bool> {
+        self.s.set_nonblocking(true)?;
+
+        let ret = self.read_once();
+        self.s.set_nonblocking(false)?;
+
+        ret
+    }
+
+    /// Instructs the peer that a message will be forthcoming and transmits the message. The message is sent verbatim, i.e. trailing newlines are neither added nor removed (see `send_line`).
+    pub fn send(&mut self, msg: &str) -> Result<()> {
+        if self.close_requested {
+            return self.close_on_request();
+        }
+
+        let transformed = self.send_transform.as_ref().map(|f| f(msg));
+        let msg = transformed.as_deref().unwrap_or(msg);
+
+        if transformed.is_some() && msg.len() > self.peer_max_msg_size() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Message too large"));
+        }
+
+        #[cfg(feature = "compress")]
+        if self.compress_above.is_some_and(|above| msg.len() > above)
+            && self.peer_supports(Capability::Compression)
+        {
+            return self.send_compressed(msg);
+        }
+
+        self.send_message(INSTR_MESSAGE, msg)
+    }
+
+    /// Sends a message in compressed form. The hash is computed over the original message, and the peer's limit applies to it as well, since the peer rejects messages that inflate beyond it.
+    #[cfg(feature = "compress")]
+    fn send_compressed(&mut self, msg: &str) -> Result<()> {
+        use flate2::write::DeflateEncoder;
+
+        self.check_peer_limit(msg.len())?;
+        self.flush_outbox()?;
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(msg.as_bytes())?;
+        let compressed = encoder.finish()?;
+
+        let hash = sha2::Sha256::digest(msg.as_bytes()).into();
+
+        let mut frame = self.header(INSTR_COMPRESSED_MESSAGE, compressed.len());
+        frame.extend_from_slice(&compressed);
+        frame.extend_from_slice(&self.signature_frame(&hash));
+
+        self.send_all(&frame)?;
+
+        self.sent(hash);
+
+        Ok(())
+    }
+
+    /// Sends a message exactly as given, byte for byte. Unlike `send`, this neither applies `send_transform` nor compresses the message (see `compress_above`).
+    pub fn send_raw(&mut self, msg: &str) -> Result<()> {
+        self.send_message(INSTR_MESSAGE, msg)
+    }
+
+    /// Sends a message as a line, i.e. with exactly one trailing `\n`. Any trailing line breaks (`\n` or `\r\n`) of `msg` are replaced, so `send_line("hi")` and `send_line("hi\n")` both send `"hi\n"`. As with `send_raw`, neither `send_transform` nor compression is applied.
+    pub fn send_line(&mut self, msg: &str) -> Result<()> {
+        let mut line = msg.trim_end_matches(['\n', '\r']).to_string();
+        line.push('\n');
+
+        self.send_message(INSTR_MESSAGE, &line)
+    }
+
+    /// Sends a message that the peer's application is asked to acknowledge. Besides the hash, the peer replies whether it accepts the message (see `msg_accept`), which is reported via `msg_acked` once read.
+    pub fn send_acked(&mut self, msg: &str) -> Result<()> {
+        self.send_message(INSTR_ACKED_MESSAGE, msg)
+    }
+
+    fn send_message(&mut self, instr: u8, msg: &str) -> Result<()> {
+        if self.close_requested {
+            return self.close_on_request();
+        }
+
+        self.check_peer_limit(msg.len())?;
+        self.flush_outbox()?;
+
+        let hash = sha2::Sha256::digest(msg.as_bytes()).into();
+
+        // written at once, so that the header and the payload are not sent as separate packets
+        let mut frame = self.header(instr, msg.len());
+        frame.extend_from_slice(msg.as_bytes());
+        frame.extend_from_slice(&self.signature_frame(&hash));
+
+        self.send_all(&frame)?;
+
+        self.sent(hash);
+
+        Ok(())
+    }
+
+    /// Sends a message along with metadata, e.g. the id of the message it replies to. The key-value pairs are sent in a block ahead of the body and delivered to the peer via `msg_meta`. Keys must be non-empty and must not contain `=`, and neither keys nor values may contain control characters. The metadata block must not exceed 4096 octets. The peer must support `Capability::Metadata`.
+    pub fn send_meta(&mut self, pairs: &[(&str, &str)], body: &str) -> Result<()> {
+        let mut meta = String::new();
+
+        for (key, value) in pairs {
+            if key.is_empty()
+                || key.contains('=')
+                || key.contains(char::is_control)
+                || value.contains(char::is_control)
+            {
+                return Err(Error::new(ErrorKind::InvalidInput, "Invalid metadata"));
+            }
+
+            meta.push_str(&format!("{}={}\n", key, value));
+        }
+
+        if meta.len() > MAX_META_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "Metadata too large"));
+        }
+
+        self.send_message(
+            INSTR_META_MESSAGE,
+            &format!("{}\n{}{}", meta.len(), meta, body),
+        )
+    }
+
+    /// Offers a file to the peer without sending it. The peer is informed via `file_offered` and may accept or decline the offer at any later time, which we learn about via `offer_accepted` or `offer_declined`. Once the offer has been accepted, the file should be sent (e.g. using `send_reader`). Returns the id of the offer.
+    pub fn offer_file(&mut self, name: &str, size: usize) -> Result<usize> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "File name too long"));
+        }
+
+        self.flush_outbox()?;
+
+        let id = self.next_offer;
+        self.next_offer += 1;
+
+        let header = self.header(INSTR_OFFER, format!("{} {} {}", id, size, name.len()));
+        self.send_all(&header)?;
+        self.send_all(name.as_bytes())?;
+
+        Ok(id)
+    }
+
+    /// Accepts a file offered by the peer. The next file transfer of the offered size is then accepted without consulting `file_incoming`. Files are expected in the order in which their offers were accepted; if the peer sends a later one first, the offers accepted before it are given up (see `offer_skipped`).
+    pub fn accept_file(&mut self, id: usize) -> Result<()> {
+        self.accept_offer(id, None)
+    }
+
+    /// Like `accept_file`, but the file is stored at `dest` instead of under a name of its own in `download_dir`. Missing parent directories are created. An existing file at `dest` is not replaced, a numbered variant of `dest` is used instead.
+    pub fn accept_file_to(&mut self, id: usize, dest: PathBuf) -> Result<()> {
+        self.accept_offer(id, Some(dest))
+    }
+
+    /// Accepts a file offered by the peer, to be stored at `dest` if given.
+    fn accept_offer(&mut self, id: usize, dest: Option<PathBuf>) -> Result<()> {
+        let (id, size) = self.take_offer(id)?;
+
+        self.flush_outbox()?;
+        self.send_all(&self.header(INSTR_OFFER_ACCEPTED, id))?;
+
+        self.offers_accepted.push_back((id, size, dest));
+
+        Ok(())
+    }
+
+    /// Declines a file offered by the peer.
+    pub fn decline_file(&mut self, id: usize) -> Result<()> {
+        self.take_offer(id)?;
+
+        self.flush_outbox()?;
+        self.send_all(&self.header(INSTR_OFFER_DECLINED, id))
+    }
+
+    /// Announces to the peer that we will open a side channel, i.e. a second connection to the peer on which a file can be sent while messages keep flowing on this one. Returns the token with which the new connection must be joined to this one (see `join_side_channel`). The peer must support `Capability::SideChannels`.
+    pub fn announce_side_channel(&mut self) -> Result<u64> {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos(),
+        );
+
+        // fits into the 15 digits accepted in frame headers by default
+        let token = hasher.finish() & ((1 << 48) - 1);
+
+        self.flush_outbox()?;
+        self.send_all(&self.header(INSTR_SIDE_CHANNEL_ANNOUNCE, token))?;
+
+        Ok(token)
+    }
+
+    /// Declares this connection to be the side channel with the given token, which has been announced on the main connection using `announce_side_channel`. Should be called right after the handshake.
+    pub fn join_side_channel(&mut self, token: u64) -> Result<()> {
+        self.flush_outbox()?;
+        self.send_all(&self.header(INSTR_SIDE_CHANNEL_JOIN, token))
+    }
+
+    /// Returns the token of the side channel that the peer declared this connection to be, if any. The main connection to which it belongs can be found using `claim_side_channel`.
+    pub fn side_channel(&self) -> Option<u64> {
+        self.side_of
+    }
+
+    /// Checks whether the peer has announced a side channel with the given token on this connection. If so, the token is consumed and true is returned, so that each announced side channel can be joined only once.
+    pub fn claim_side_channel(&mut self, token: u64) -> bool {
+        match self.side_tokens.iter().position(|&t| t == token) {
+            Some(pos) => {
+                self.side_tokens.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes a pending offer by its id.
+    fn take_offer(&mut self, id: usize) -> Result<(usize, usize)> {
+        match self.offers.iter().position(|&(i, _)| i == id) {
+            Some(pos) => Ok(self.offers.remove(pos)),
+            None => Err(Error::new(ErrorKind::NotFound, "No such offer")),
+        }
+    }
+
+    /// Puts a message into the outbox instead of sending it right away. Returns `Ok(false)` if the outbox already holds `outbox_capacity` messages. Queued messages are sent by `pump_out`, or before anything else is sent. The peer's hashes for queued messages are received by `read_once`.
+    pub fn try_enqueue(&mut self, msg: &str) -> Result<bool> {
+        if msg.len() > MAX_MSG_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "Message too large"));
+        }
+
+        self.check_peer_limit(msg.len())?;
+
+        if self.outbox.len() >= self.outbox_capacity {
+            return Ok(false);
+        }
+
+        let hash = sha2::Sha256::digest(msg.as_bytes()).into();
+
+        let mut frame = self.header(INSTR_MESSAGE, msg.len());
+        frame.extend_from_slice(msg.as_bytes());
+        frame.extend_from_slice(&self.signature_frame(&hash));
+
+        self.outbox.push_back(frame);
+
+        self.sent(hash);
+
+        Ok(true)
+    }
+
+    /// Writes as much of the outbox to the peer as possible without blocking. Returns the number of messages that have been sent completely.
+    pub fn pump_out(&mut self) -> Result<usize> {
+        self.s.set_nonblocking(true)?;
+
+        let mut sent = 0;
+        let mut res = Ok(());
+
+        while let Some(frame) = self.outbox.front() {
+            match self.s.write(&frame[self.outbox_pos..]) {
+                Ok(0) => {
+                    res = Err(Error::new(ErrorKind::WriteZero, "Could not write to peer"));
+                    break;
+                }
+                Ok(n) => {
+                    self.count_written(n);
+                    self.last_activity = Instant::now();
+                    self.outbox_pos += n;
+
+                    if self.outbox_pos == frame.len() {
+                        self.outbox.pop_front();
+                        self.outbox_pos = 0;
+                        sent += 1;
+                    }
+                }
+                Err(e) => {
+                    match e.kind() {
+                        ErrorKind::WouldBlock => (),
+                        ErrorKind::Interrupted => continue,
+                        _ => res = Err(e),
+                    }
+                    break;
+                }
+            }
+        }
+
+        self.s.set_nonblocking(false)?;
+
+        res.map(|_| sent)
+    }
+
+    /// Sends everything that remains in the outbox, blocking if necessary. Called before sending anything else that should not overtake the messages queued before it; a frame that is only partially written is completed by every write in any case (see `send_all`).
+    fn flush_outbox(&mut self) -> Result<()> {
+        while let Some(frame) = self.outbox.pop_front() {
+            let pos = std::mem::replace(&mut self.outbox_pos, 0);
+
+            self.s.set_nonblocking(false)?;
+            self.write_out(&frame[pos..])?;
+        }
+
+        Ok(())
+    }
+
+    /// Announces our capabilities to the peer and blocks until the peer's capabilities have been received. Should be called by both sides, e.g. right after the handshake. Other instructions received in the meantime are processed as usual.
+    pub fn exchange_capabilities(&mut self) -> Result<u64> {
+        self.announce_capabilities()?;
+
+        loop {
+            match self.read_once_detailed()? {
+                ReadOutcome::Capabilities(caps) => return Ok(caps),
+                ReadOutcome::Stopped => return Err(stopped()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Like `exchange_capabilities`, but waits for at most `wait` for the peer's capabilities, e.g. for peers that may not support capabilities at all. Returns `None` if they have not arrived in time, in which case the peer is assumed to support nothing (until its capabilities arrive after all).
+    pub fn exchange_capabilities_within(&mut self, wait: Duration) -> Result<Option<u64>> {
+        self.announce_capabilities()?;
+
+        let read_timeout = self.s.read_timeout()?;
+        let deadline = Instant::now() + wait;
+
+        let res = loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+
+            if left.is_zero() {
+                break Ok(None);
+            }
+
+            if let Err(e) = self.s.set_read_timeout(Some(left)) {
+                break Err(e);
+            }
+
+            match self.read_once_detailed() {
+                Ok(ReadOutcome::Capabilities(caps)) => break Ok(Some(caps)),
+                Ok(ReadOutcome::Stopped) => break Err(stopped()),
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            }
+        };
+
+        self.s.set_read_timeout(read_timeout)?;
+
+        res
+    }
+
+    /// Sends our capabilities to the peer.
+    fn announce_capabilities(&mut self) -> Result<()> {
+        self.flush_outbox()?;
+        let caps = if self.verify {
+            Capability::ours()
+        } else {
+            Capability::ours() | Capability::Unverified as u64
+        };
+
+        self.send_all(&self.header(INSTR_CAPABILITIES, caps))?;
+
+        self.s.set_nonblocking(false)
+    }
+
+    /// Sends a ping to the peer and blocks until it has been answered. Returns the round-trip time. Instructions received in the meantime are processed as by `read_once`. The peer must support `Capability::Ping`.
+    pub fn measure_rtt(&mut self) -> Result<Duration> {
+        self.flush_outbox()?;
+
+        let start = Instant::now();
+        self.send_all(&[self.wire_of(INSTR_PING)])?;
+
+        self.s.set_nonblocking(false)?;
+
+        loop {
+            match self.read_once_detailed()? {
+                ReadOutcome::Pong => return Ok(start.elapsed()),
+                ReadOutcome::Stopped => return Err(stopped()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns how long ago anything was last sent to or received from the peer.
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    /// Sends a ping if nothing has been sent or received for at least `interval`, e.g. to keep a Tor circuit or NAT mapping alive during idle periods. Meant to be called regularly, e.g. alongside `read_maybe`, which processes the pong. Returns whether a ping was sent. Nothing is sent if the peer does not support `Capability::Ping`.
+    pub fn heartbeat(&mut self, interval: Duration) -> Result<bool> {
+        if self.idle_for() < interval || !self.peer_supports(Capability::Ping) {
+            return Ok(false);
+        }
+
+        self.flush_outbox()?;
+        self.send_all(&[self.wire_of(INSTR_PING)])?;
+
+        Ok(true)
+    }
+
+    /// Returns the number of payloads the peer has sent on this connection that were too large.
+    pub fn oversized_attempts(&self) -> usize {
+        self.oversized
+    }
+
+    /// Records a payload that was too large and reports it to the callbacks.
+    fn too_large(&mut self, n_bytes: usize) {
+        self.oversized += 1;
+
+        if let Some(ref f) = &self.payload_too_large {
+            f(n_bytes);
+        }
+
+        if let Some(ref f) = &self.oversized_attempt {
+            f(n_bytes, self.oversized);
+        }
+    }
+
+    /// Returns the size of the largest message that is accepted, in octets (by default, 1048576).
+    pub fn max_msg_size(&self) -> usize {
+        self.max_msg_size
+    }
+
+    /// Changes the size of the largest message that is accepted, e.g. to tighten it under load. Larger messages (and chunks) received from then on are rejected as `ReadOutcome::PayloadTooLarge`. The size cannot exceed 1048576 octets. If `notify` is set and the peer supports `Capability::Limits`, the new limits are sent to the peer, which then refuses to send larger messages.
+    pub fn set_max_msg_size(&mut self, size: usize, notify: bool) -> Result<()> {
+        self.max_msg_size = min(size, MAX_MSG_SIZE);
+
+        if notify && self.peer_supports(Capability::Limits) {
+            self.flush_outbox()?;
+            self.send_limits()?;
+        }
+
+        Ok(())
+    }
+
+    /// Reports our limits to the peer, either as the reply to a query or unsolicited after they have changed.
+    fn send_limits(&mut self) -> Result<()> {
+        let limits = format!("{} {}", self.max_msg_size, self.max_file_size.unwrap_or(0));
+
+        self.send_all(&self.header(INSTR_LIMITS, limits))
+    }
+
+    /// Returns the size of the largest message the peer accepts, as far as we know.
+    fn peer_max_msg_size(&self) -> usize {
+        self.peer_limits.map_or(MAX_MSG_SIZE, |limits| {
+            min(limits.max_msg_size, MAX_MSG_SIZE)
+        })
+    }
+
+    /// Fails if a message of `len` octets exceeds the limit reported by the peer.
+    fn check_peer_limit(&self, len: usize) -> Result<()> {
+        if len > self.peer_max_msg_size() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Message exceeds the limit of the peer",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Asks the peer for the limits on what it accepts and blocks until it has replied, e.g. to check whether a large file would be accepted before sending it. Instructions received in the meantime are processed as by `read_once`. The peer must support `Capability::Limits`.
+    pub fn query_limits(&mut self) -> Result<PeerLimits> {
+        self.flush_outbox()?;
+        self.send_all(&[self.wire_of(INSTR_LIMITS_QUERY)])?;
+
+        self.s.set_nonblocking(false)?;
+
+        loop {
+            match self.read_once_detailed()? {
+                ReadOutcome::Limits(limits) => return Ok(limits),
+                ReadOutcome::Stopped => return Err(stopped()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns whether the peer has announced that it supports `cap`. Before capabilities have been exchanged, the peer is assumed to support nothing.
+    pub fn peer_supports(&self, cap: Capability) -> bool {
+        self.peer_caps & cap as u64 != 0
+    }
+
+    /// Announces our public key (e.g. an ed25519 public key) to the peer. The peer stores it in `peer_pubkey`.
+    pub fn send_pubkey(&mut self, key: &PublicKey) -> Result<()> {
+        self.flush_outbox()?;
+        self.send_all(&self.header(INSTR_PUBKEY, key.len()))?;
+        self.send_all(key)
+    }
+
+    /// Sends a message of arbitrary size as a sequence of chunks of at most `chunk_size` octets each (but no more than 1048576 octets). Chunks never split a UTF-8 code point, so each chunk is valid UTF-8 by itself. The peer reassembles the message in memory without touching the disk and sends the hash of the entire message.
+    pub fn send_chunked(&mut self, msg: &str, chunk_size: usize) -> Result<()> {
+        if self.close_requested {
+            return self.close_on_request();
+        }
+
+        self.flush_outbox()?;
+
+        let chunk_size = chunk_size.clamp(1, self.peer_max_msg_size());
+        let mut rest = msg;
+
+        while !rest.is_empty() {
+            let mut end = min(chunk_size, rest.len());
+
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+
+            if end == 0 {
+                // chunk_size is smaller than the next code point
+                end = rest.char_indices().nth(1).map_or(rest.len(), |(i, _)| i);
+            }
+
+            self.send_all(&self.header(INSTR_CHUNK, end))?;
+            self.send_all(&rest.as_bytes()[..end])?;
+
+            rest = &rest[end..];
+        }
+
+        self.send_all(&[self.wire_of(INSTR_CHUNKS_END)])?;
+
+        let hash = sha2::Sha256::digest(msg.as_bytes()).into();
+        self.send_signature(&hash)?;
+
+        self.sent(hash);
+
+        Ok(())
+    }
+
+    /// Send a stream to the peer. While this method technically accepts all streams that implement `Read`, *talkers* currently only has dedicated support for files.
+    pub fn send_stream<T, U>(&mut self, stream: &mut T, len: U) -> Result<()>
+    where
+        T: Read,
+        U: std::fmt::Display,
+    {
+        self.stream_out(stream, len, None).map(|_| ())
+    }
+
+    /// Like `send_stream`, but returns the hash of the data sent and the number of payload octets that were actually read from `stream` and transmitted. If the stream delivered less (or more) than the announced `len`, the count differs from it, which the caller can check.
+    pub fn send_stream_counted<T, U>(&mut self, stream: &mut T, len: U) -> Result<(Hash, u64)>
+    where
+        T: Read,
+        U: std::fmt::Display,
+    {
+        self.stream_out(stream, len, None)
+    }
+
+    /// Sends the remainder of a seekable stream (e.g. a file) to the peer. Unlike with `send_stream`, the length need not be specified, as it is determined by seeking to the end of the stream and back. Returns the hash of the data sent.
+    pub fn send_reader<T: Read + Seek>(&mut self, r: &mut T) -> Result<Hash> {
+        let pos = r.stream_position()?;
+        let end = r.seek(SeekFrom::End(0))?;
+        r.seek(SeekFrom::Start(pos))?;
+
+        self.stream_out(r, end.saturating_sub(pos), None)
+            .map(|(hash, _)| hash)
+    }
+
+    /// Like `send_reader`, but the stream is hashed before sending and the hash is transmitted ahead of the data. The peer then knows the expected hash as soon as the transfer is complete and reports a mismatch as `ReadOutcome::HashMismatch`. Returns the hash of the data sent.
+    pub fn send_reader_hash_first<T: Read + Seek>(&mut self, r: &mut T) -> Result<Hash> {
+        let pos = r.stream_position()?;
+        let hash = hash_reader(r)?;
+        let end = r.stream_position()?;
+        r.seek(SeekFrom::Start(pos))?;
+
+        self.stream_out(r, end - pos, Some(hash))
+            .map(|(hash, _)| hash)
+    }
+
+    /// Sends a file prepared by `prepare_file`. Its hash is declared up front, as with `send_reader_hash_first`, so it is not computed again before sending. Fails if the file has changed since it was prepared, in which case the peer reports a mismatch as well.
+    pub fn send_prepared(&mut self, offer: &FileOffer) -> Result<Hash> {
+        if self.file_system.open(&offer.path)?.len != offer.size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "File has changed since it was prepared",
+            ));
+        }
+
+        self.send_file_checked(&offer.path, offer.hash)?;
+
+        Ok(offer.hash)
+    }
+
+    /// Sends a file to the peer, like `send_reader`. If the peer supports `Capability::FileModes`, the file's permissions are sent ahead of it, so that e.g. executables remain executable (on Unix). Returns the hash of the file sent.
+    pub fn send_file<P: AsRef<Path>>(&mut self, path: P) -> Result<Hash> {
+        let file = self.file_system.open(path.as_ref())?;
+
+        self.send_source(file, None).map(|(hash, _)| hash)
+    }
+
+    /// Sends a file opened through `file_system`, announcing its permissions first if they are known and the peer supports this. Returns the hash of the data sent and its length.
+    fn send_source(&mut self, file: SourceFile, declared: Option<Hash>) -> Result<(Hash, u64)> {
+        if let (Some(mode), true) = (file.mode, self.peer_supports(Capability::FileModes)) {
+            self.flush_outbox()?;
+            self.send_all(&self.header(INSTR_FILE_MODE, mode))?;
+        }
+
+        self.stream_out(&mut file.reader.take(file.len), file.len, declared)
+    }
+
+    /// Sends a file that is expected to have the hash `expected`, e.g. because it has been sent before. The hash is declared up front, as with `send_reader_hash_first`, and checked while sending. If the file does not match (e.g. due to local corruption), an error is returned and the peer reports the file as `ReadOutcome::HashMismatch`.
+    pub fn send_file_checked<P: AsRef<Path>>(&mut self, path: P, expected: Hash) -> Result<()> {
+        let file = self.file_system.open(path.as_ref())?;
+
+        let (hash, _) = self.send_source(file, Some(expected))?;
+
+        if hash != expected {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "File does not match the expected hash",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Sends several files in sequence, reporting the progress of the whole batch to `batch_progress`. All files are opened before anything is sent. If a file cannot be opened or sent, the batch stops with an error naming that file. Returns the hashes of the files sent.
+    pub fn send_files(&mut self, paths: &[PathBuf]) -> Result<Vec<Hash>> {
+        let failed = |path: &Path, e: Error| {
+            Error::new(
+                e.kind(),
+                format!("Could not send `{}`: {}", path.display(), e),
+            )
+        };
+
+        let mut files = Vec::with_capacity(paths.len());
+
+        for path in paths {
+            files.push(self.file_system.open(path).map_err(|e| failed(path, e))?);
+        }
+
+        let total = files.iter().map(|file| file.len).sum();
+        let mut hashes = Vec::with_capacity(files.len());
+        let mut before = 0;
+
+        for (index, file) in files.into_iter().enumerate() {
+            self.batch = Some((index, before, total));
+            let res = self.send_source(file, None);
+            self.batch = None;
+
+            let (hash, sent) = res.map_err(|e| failed(&paths[index], e))?;
+
+            hashes.push(hash);
+            before += sent;
+        }
+
+        Ok(hashes)
+    }
+
+    /// Announces a file of length `len` to the peer and transmits it from `stream`. If `declared` is given, it is sent ahead of the data instead of sending the hash afterwards. Returns the hash of the data sent and its length.
+    fn stream_out<T, U>(
+        &mut self,
+        stream: &mut T,
+        len: U,
+        declared: Option<Hash>,
+    ) -> Result<(Hash, u64)>
+    where
+        T: Read,
+        U: std::fmt::Display,
+    {
+        if self.close_requested {
+            return self.close_on_request();
+        }
+
+        self.flush_outbox()?;
+
+        let size = len.to_string().parse().unwrap_or(0);
+        let mut hasher = sha2::Sha256::new();
+        let mut buf = [0; 1024];
+
+        if let Some(declared) = declared {
+            self.send_all(&self.header(INSTR_FILE_HASH_FIRST, len))?;
+            self.send_all(&declared)?;
+        } else {
+            self.send_all(&self.header(INSTR_FILE, len))?;
+        }
+
+        let transfer = self.transfers.start(None, false, size);
+        let start = Instant::now();
+        let mut sent = 0;
+
+        loop {
+            let n = retry_interrupted(self.interrupted_retries, || stream.read(&mut buf))?;
+
+            if n == 0 {
+                break;
+            }
+            let before = Instant::now();
+            self.send_all(&buf[..n])?;
+            let took = before.elapsed();
+
+            if took > self.send_blocked_after {
+                if let Some(ref f) = self.send_blocked {
+                    f(took);
+                }
+            }
+
+            hasher.update(&buf[..n]);
+
+            sent += n;
+            transfer.progress(sent as u64);
+
+            if let Some(ref f) = self.send_progress {
+                f(sent, start.elapsed());
+            }
+
+            if let (Some((index, before, total)), Some(ref f)) = (self.batch, &self.batch_progress)
+            {
+                f(index, before + sent as u64, total);
+            }
+        }
+
+        let hash: Hash = hasher.finalize().into();
+
+        if declared.is_none() && !self.skip_hashes() {
+            self.send_all(&self.hash_frame(&hash))?;
+        }
+
+        self.send_signature(&hash)?;
+
+        self.sent(hash);
+
+        Ok((hash, sent as u64))
+    }
+
+    /// Returns the file transfers in progress. As the `Talker` is busy during a transfer, this is mostly of use through a `transfer_monitor` held by another thread.
+    pub fn active_transfers(&self) -> Vec<TransferInfo> {
+        self.transfers.active_transfers()
+    }
+
+    /// Returns a handle through which the file transfers in progress can be queried from other threads.
+    pub fn transfer_monitor(&self) -> TransferMonitor {
+        self.transfers.clone()
+    }
+
+    /// Returns the files that have been received completely so far. The list grows with every transfer, so long-running callers should `clear_received_files` from time to time.
+    pub fn received_files(&self) -> &[ReceivedFile] {
+        &self.received
+    }
+
+    /// Forgets about all files received so far.
+    pub fn clear_received_files(&mut self) {
+        self.received.clear();
+    }
+
+    /// Returns a snapshot of where this `Talker` stands in the protocol, e.g. whether the peer's hash of something we sent is still pending. If several apply, the state listed last in `TalkerState` is reported.
+    pub fn state(&self) -> TalkerState {
+        if self.closed {
+            TalkerState::Closed
+        } else if let Some(instr) = self.queue {
+            TalkerState::Queued(instr)
+        } else if !self.msg_stream.is_empty() {
+            TalkerState::ReadingChunks(self.msg_stream.len())
+        } else if self.hashes_pending > 0 {
+            TalkerState::AwaitingHash(self.hashes_pending)
+        } else {
+            TalkerState::Idle
+        }
+    }
+
+    /// Sends a hash to the peer, as is done automatically for every message or file received. Meant for custom protocols that decide themselves what to confirm and when; the peer can read the hash using `recv_hash`.
+    pub fn send_hash(&mut self, hash: &Hash) -> Result<()> {
+        self.flush_outbox()?;
+
+        self.send_all(&self.hash_frame(hash))
+    }
+
+    /// Blocks until a hash has been received and returns it, e.g. one sent by the peer using `send_hash`. Unlike `expect_hash`, this does not depend on whether hashes are exchanged automatically. If some other instruction is received instead, it is queued for `read_once` as with `expect_hash` and an Err variant is returned.
+    pub fn recv_hash(&mut self) -> Result<Hash> {
+        self.s.set_nonblocking(false)?;
+
+        let mut instr = [0; 1];
+        self.recv_exact(&mut instr)?;
+
+        if self.instr_of(instr[0]) == Some(INSTR_HASH) {
+            return self.read_hash();
+        }
+
+        self.queue = Some(instr[0]);
+
+        if let Some(ref f) = self.on_queued {
+            f(instr[0]);
+        }
+
+        Err(Error::new(ErrorKind::InvalidData, "No hash transmitted"))
+    }
+
+    /// Returns the frame in which `hash` is sent to the peer.
+    fn hash_frame(&self, hash: &Hash) -> Vec<u8> {
+        let mut frame = vec![self.wire_of(INSTR_HASH)];
+        frame.extend_from_slice(hash);
+
+        frame
+    }
+
+    /// Blocks until a hash has been received. If no hash, but some other instruction, is received, that instruction is written into an internal queue so that it can be processed by subsequent calls to `read_once`. Returns `Ok(())` if a hash was received and an Err variant if not.
+    pub fn expect_hash(&mut self) -> Result<()> {
+        if self.skip_hashes() {
+            return Ok(());
+        }
+
+        self.s.set_nonblocking(false)?;
+
+        let mut buf = [0; 33];
+
+        if let Ok(()) = self.recv_exact(&mut buf[..1]) {
+            if self.instr_of(buf[0]) == Some(INSTR_HASH) {
+                self.recv_exact(&mut buf[1..])?;
+                self.hashes_pending = self.hashes_pending.saturating_sub(1);
+
+                if let Some(ref f) = self.hash_rcvd {
+                    f(buf[1..33].try_into().unwrap());
+                }
+
+                return Ok(());
+            } else {
+                self.queue = Some(buf[0]);
+
+                if let Some(ref f) = self.on_queued {
+                    f(buf[0]);
+                }
+            }
+        }
+
+        Err(Error::other("No hash transmitted"))
+    }
+}
+
+impl std::fmt::Debug for Talker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Talker")
+            .field("label", &self.label)
+            .field("peer", &self.s.peer_addr().ok())
+            .field("state", &self.state())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Returns two connected streams on the loopback interface.
+    fn raw_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+
+        (a, b)
+    }
+
+    /// Returns two connected `Talker`s on the loopback interface that have completed the handshake. The first one initiated the connection.
+    fn pair() -> (Talker, Talker) {
+        pair_with(|_| {}, |_| {})
+    }
+
+    /// Like `pair`, but lets the caller set up each `Talker` before the handshake.
+    fn pair_with<F, G>(setup_a: F, setup_b: G) -> (Talker, Talker)
+    where
+        F: FnOnce(&mut Talker),
+        G: FnOnce(&mut Talker) + Send + 'static,
+    {
+        let (a, b) = raw_pair();
+
+        let b = thread::spawn(move || {
+            let mut b = Talker::new(b);
+            setup_b(&mut b);
+            b.handshake(false).unwrap();
+
+            b
+        });
+
+        let mut a = Talker::new(a);
+        setup_a(&mut a);
+        a.handshake(true).unwrap();
+
+        (a, b.join().unwrap())
+    }
+
+    fn hash_of(data: &[u8]) -> Hash {
+        sha2::Sha256::digest(data).into()
+    }
+
+    /// Returns an empty directory for the test `name` to store files in.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("talkers-{}-{}", name, std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn pubkey_round_trips() {
+        let (mut a, mut b) = pair();
+
+        let rcvd = Arc::new(Mutex::new(None));
+        let rcvd2 = Arc::clone(&rcvd);
+        b.pubkey_rcvd = Some(Box::new(move |key| *rcvd2.lock().unwrap() = Some(key)));
+
+        a.send_pubkey(&[7; 32]).unwrap();
+
+        assert!(b.read_once().unwrap());
+        assert_eq!(b.peer_pubkey, Some([7; 32]));
+        assert_eq!(*rcvd.lock().unwrap(), Some([7; 32]));
+    }
+
+    #[test]
+    fn pubkey_of_wrong_length_is_rejected() {
+        let (mut a, mut b) = pair();
+
+        let frame = a.header(INSTR_PUBKEY, 31);
+        a.send_all(&frame).unwrap();
+        a.send_all(&[7; 31]).unwrap();
+
+        assert_eq!(b.read_once().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(b.peer_pubkey, None);
+    }
+
+    #[cfg(feature = "sign")]
+    #[test]
+    fn signatures_are_verified() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[3; 32]);
+        let (mut a, mut b) = pair();
+
+        let invalid = Arc::new(Mutex::new(Vec::new()));
+        let invalid2 = Arc::clone(&invalid);
+        b.signature_invalid = Some(Box::new(move |hash| invalid2.lock().unwrap().push(hash)));
+
+        let msgs = Arc::new(Mutex::new(Vec::new()));
+        let msgs2 = Arc::clone(&msgs);
+        b.msg_new = Some(Box::new(move |msg| msgs2.lock().unwrap().push(msg)));
+
+        a.signing_key = Some(key.clone());
+        a.send_pubkey(&key.verifying_key().to_bytes()).unwrap();
+        b.read_once().unwrap();
+
+        a.send("hello").unwrap();
+        b.read_once().unwrap();
+        a.expect_hash().unwrap();
+        assert_eq!(*msgs.lock().unwrap(), ["hello"]);
+        assert!(invalid.lock().unwrap().is_empty());
+
+        // the payload is tampered with after signing
+        let signed: Hash = sha2::Sha256::digest(b"hello").into();
+        let mut frame = a.header(INSTR_MESSAGE, 5);
+        frame.extend_from_slice(b"jello");
+        a.send_all(&frame).unwrap();
+        a.send_signature(&signed).unwrap();
+
+        b.read_once().unwrap();
+
+        let tampered: Hash = sha2::Sha256::digest(b"jello").into();
+        assert_eq!(*invalid.lock().unwrap(), vec![tampered]);
+    }
+
+    #[test]
+    fn read_once_detailed_describes_outcome() {
+        let (mut a, mut b) = pair();
+
+        let hashes = Arc::new(Mutex::new(Vec::new()));
+        let hashes2 = Arc::clone(&hashes);
+        a.hash_rcvd = Some(Box::new(move |hash| hashes2.lock().unwrap().push(hash)));
+
+        a.send("hello").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("hello".to_string())
+        );
+        a.expect_hash().unwrap();
+
+        // files are rejected by default
+        a.send_stream(&mut Cursor::new(b"data".to_vec()), 4)
+            .unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::FileRejected(4)
+        );
+        a.expect_hash().unwrap();
+
+        b.file_incoming = Box::new(|_| true);
+        a.send_stream(&mut Cursor::new(b"data".to_vec()), 4)
+            .unwrap();
+
+        match b.read_once_detailed().unwrap() {
+            ReadOutcome::File(name) => {
+                assert_eq!(fs::read(&name).unwrap(), b"data");
+                fs::remove_file(name).unwrap();
+            }
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+        a.expect_hash().unwrap();
+
+        b.set_max_msg_size(4, false).unwrap();
+        a.send("hello").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::PayloadTooLarge(5)
+        );
+
+        assert_eq!(
+            hashes.lock().unwrap()[..3],
+            [hash_of(b"hello"), hash_of(b""), hash_of(b"data")]
+        );
+    }
+
+    #[test]
+    fn chunked_message_reassembles() {
+        let (mut a, mut b) = pair();
+
+        // multi-octet code points, so that chunk boundaries fall inside of some
+        let msg = "aé€😀".repeat(3 * 1024 * 1024 / 10);
+        let sent = msg.clone();
+
+        let sender = thread::spawn(move || {
+            a.send_chunked(&sent, 1000).unwrap();
+            a.expect_hash().unwrap();
+        });
+
+        let chunks = Arc::new(Mutex::new(0));
+        let chunks2 = Arc::clone(&chunks);
+        b.msg_chunk = Some(Box::new(move |chunk| {
+            assert!(!chunk.contains('\u{fffd}'));
+            *chunks2.lock().unwrap() += 1;
+        }));
+
+        let received = loop {
+            match b.read_once_detailed().unwrap() {
+                ReadOutcome::MessageChunk(_) => {}
+                ReadOutcome::MessageStream(msg) => break msg,
+                outcome => panic!("unexpected outcome: {:?}", outcome),
+            }
+        };
+
+        sender.join().unwrap();
+
+        assert!(received == msg);
+        assert!(*chunks.lock().unwrap() > 3000);
+    }
+
+    #[test]
+    fn chunked_message_beyond_limit_is_dropped() {
+        let (mut a, mut b) = pair();
+        b.max_chunked_size = 10;
+
+        a.send_chunked("0123456789abcdef", 4).unwrap();
+
+        // four chunks and the end of the message
+        let outcomes: Vec<_> = (0..5).map(|_| b.read_once_detailed().unwrap()).collect();
+
+        assert_eq!(
+            outcomes,
+            vec![
+                ReadOutcome::MessageChunk(4),
+                ReadOutcome::MessageChunk(4),
+                ReadOutcome::PayloadTooLarge(12),
+                ReadOutcome::PayloadTooLarge(16),
+                ReadOutcome::PayloadTooLarge(16),
+            ]
+        );
+        // the peer is not left waiting for the hash
+        a.expect_hash().unwrap();
+
+        // the next message is not affected
+        a.send_chunked("short", 4).unwrap();
+        b.read_once_detailed().unwrap();
+        b.read_once_detailed().unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::MessageStream("short".to_string())
+        );
+        a.expect_hash().unwrap();
+
+        // a single chunk beyond the message limit drops the rest as well
+        b.max_chunked_size = 64;
+        b.set_max_msg_size(4, false).unwrap();
+        let mut frame = a.header(INSTR_CHUNK, 2);
+        frame.extend_from_slice(b"ab");
+        frame.extend_from_slice(&a.header(INSTR_CHUNK, 6));
+        frame.extend_from_slice(b"cdefgh");
+        frame.extend_from_slice(&a.header(INSTR_CHUNK, 2));
+        frame.extend_from_slice(b"ij");
+        frame.push(INSTR_CHUNKS_END);
+        a.send_all(&frame).unwrap();
+
+        let outcomes: Vec<_> = (0..4).map(|_| b.read_once_detailed().unwrap()).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ReadOutcome::MessageChunk(2),
+                ReadOutcome::PayloadTooLarge(6),
+                ReadOutcome::PayloadTooLarge(10),
+                ReadOutcome::PayloadTooLarge(10),
+            ]
+        );
+        assert_eq!(a.recv_hash().unwrap(), hash_of(b"abcdefghij"));
+    }
+
+    #[cfg(feature = "sign")]
+    #[test]
+    fn signed_chunked_message_beyond_limit_is_dropped() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[3; 32]);
+        let (mut a, mut b) = pair();
+        b.max_chunked_size = 10;
+
+        let invalid = Arc::new(Mutex::new(0));
+        let count = Arc::clone(&invalid);
+        b.signature_invalid = Some(Box::new(move |_| *count.lock().unwrap() += 1));
+
+        a.signing_key = Some(key.clone());
+        a.send_pubkey(&key.verifying_key().to_bytes()).unwrap();
+        b.read_once_detailed().unwrap();
+
+        a.send_chunked("0123456789abcdef", 4).unwrap();
+        for _ in 0..5 {
+            b.read_once_detailed().unwrap();
+        }
+        a.expect_hash().unwrap();
+
+        // the signature has been consumed along with the message
+        a.send("hello").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("hello".to_string())
+        );
+        a.expect_hash().unwrap();
+        assert_eq!(*invalid.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn on_ready_fires_once_after_handshake() {
+        let readies = Arc::new(AtomicUsize::new(0));
+        let readies2 = Arc::clone(&readies);
+
+        let (mut a, _b) = pair_with(
+            move |a| {
+                a.on_ready = Some(Box::new(move || {
+                    readies2.fetch_add(1, Ordering::Relaxed);
+                }))
+            },
+            |_| {},
+        );
+        assert_eq!(readies.load(Ordering::Relaxed), 1);
+
+        // a repeated half of the handshake does not count
+        a.perform_handshake().unwrap();
+        assert_eq!(readies.load(Ordering::Relaxed), 1);
+
+        let (s, mut other) = raw_pair();
+        other.write_all(b"/chatter").unwrap();
+
+        let failures = Arc::new(AtomicUsize::new(0));
+        let failures2 = Arc::clone(&failures);
+
+        let mut t = Talker::new(s);
+        t.on_ready = Some(Box::new(move || {
+            failures2.fetch_add(1, Ordering::Relaxed);
+        }));
+
+        assert!(t.handshake(false).is_err());
+        assert_eq!(failures.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn handshake_completes_in_both_roles() {
+        let (a, b) = raw_pair();
+        let mut a = Talker::new(a);
+        let mut b = Talker::new(b);
+        assert!(!a.handshake_sent && !b.handshake_rcvd);
+
+        let acceptor = thread::spawn(move || {
+            b.handshake(false).unwrap();
+            b
+        });
+
+        a.handshake(true).unwrap();
+        let mut b = acceptor.join().unwrap();
+
+        assert!(a.handshake_sent && a.handshake_rcvd);
+        assert!(b.handshake_sent && b.handshake_rcvd);
+
+        b.send("hi").unwrap();
+        assert_eq!(
+            a.read_once_detailed().unwrap(),
+            ReadOutcome::Message("hi".to_string())
+        );
+    }
+
+    #[test]
+    fn transfer_names_are_distinct() {
+        let names: Vec<String> = (0..100)
+            .map(|_| transfer_name("transfer_", ".bin"))
+            .collect();
+
+        for (i, name) in names.iter().enumerate() {
+            assert!(name.starts_with("transfer_") && name.ends_with(".bin"));
+            assert!(!names[i + 1..].contains(name));
+        }
+
+        let (mut a, mut b) = pair();
+        b.file_incoming = Box::new(|_| true);
+
+        a.send_stream(&mut Cursor::new(b"one".to_vec()), 3).unwrap();
+        a.send_stream(&mut Cursor::new(b"two".to_vec()), 3).unwrap();
+
+        let mut files = Vec::new();
+        for _ in 0..2 {
+            match b.read_once_detailed().unwrap() {
+                ReadOutcome::File(name) => files.push(name),
+                outcome => panic!("unexpected outcome: {:?}", outcome),
+            }
+        }
+
+        assert_ne!(files[0], files[1]);
+        assert_eq!(fs::read(&files[0]).unwrap(), b"one");
+        assert_eq!(fs::read(&files[1]).unwrap(), b"two");
+
+        for name in files {
+            fs::remove_file(name).unwrap();
+        }
+    }
+
+    #[test]
+    fn send_reader_determines_length() {
+        let dir = temp_dir("reader");
+        let (mut a, mut b) = pair();
+        b.file_incoming = Box::new(|_| true);
+
+        // only what follows the current position is sent
+        let mut cursor = Cursor::new(b"skipped, sent".to_vec());
+        cursor.set_position(9);
+        assert_eq!(a.send_reader(&mut cursor).unwrap(), hash_of(b"sent"));
+
+        let path = dir.join("file");
+        fs::write(&path, b"from a file").unwrap();
+        let hash = a.send_reader(&mut File::open(&path).unwrap()).unwrap();
+        assert_eq!(hash, hash_of(b"from a file"));
+
+        for expected in [&b"sent"[..], b"from a file"] {
+            match b.read_once_detailed().unwrap() {
+                ReadOutcome::File(name) => {
+                    assert_eq!(fs::read(&name).unwrap(), expected);
+                    fs::remove_file(name).unwrap();
+                }
+                outcome => panic!("unexpected outcome: {:?}", outcome),
+            }
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn lossy_messages_are_reported() {
+        let (mut a, mut b) = pair();
+
+        let lossy = Arc::new(Mutex::new(Vec::new()));
+        let lossy2 = Arc::clone(&lossy);
+        b.msg_lossy = Some(Box::new(move |msg| lossy2.lock().unwrap().push(msg)));
+
+        a.send("valid ✓").unwrap();
+        b.read_once().unwrap();
+        assert!(lossy.lock().unwrap().is_empty());
+
+        a.s.write_all(&[b'!', b'2', b'\n', 0xff, b'A']).unwrap();
+
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("\u{fffd}A".to_string())
+        );
+        assert_eq!(*lossy.lock().unwrap(), vec!["\u{fffd}A".to_string()]);
+    }
+
+    #[test]
+    fn outbox_is_bounded_and_pumped() {
+        let (mut a, mut b) = pair();
+        a.outbox_capacity = 2;
+
+        assert!(a.try_enqueue("one").unwrap());
+        assert!(a.try_enqueue("two").unwrap());
+        assert!(!a.try_enqueue("three").unwrap());
+
+        assert_eq!(a.pump_out().unwrap(), 2);
+        assert!(a.try_enqueue("three").unwrap());
+        assert_eq!(a.pump_out().unwrap(), 1);
+
+        for msg in ["one", "two", "three"] {
+            assert_eq!(
+                b.read_once_detailed().unwrap(),
+                ReadOutcome::Message(msg.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn replies_do_not_interleave_with_outbox() {
+        let (mut a, mut b) = pair();
+
+        // more than the socket buffers hold, so that the outbox cannot be written at once
+        let msgs: Vec<String> = (0..8).map(|i| i.to_string().repeat(1_000_000)).collect();
+
+        for msg in &msgs {
+            assert!(a.try_enqueue(msg).unwrap());
+        }
+
+        assert!(a.pump_out().unwrap() < 8);
+        assert!(a.outbox_pos > 0);
+
+        b.send("ping").unwrap();
+
+        let reader = thread::spawn(move || {
+            (0..9)
+                .map(|_| b.read_once_detailed().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        // the hash is written while a message is partially sent
+        assert_eq!(
+            a.read_once_detailed().unwrap(),
+            ReadOutcome::Message("ping".to_string())
+        );
+        a.flush_outbox().unwrap();
+
+        let outcomes = reader.join().unwrap();
+        assert!(outcomes.contains(&ReadOutcome::Hash(hash_of(b"ping"))));
+
+        let received: Vec<_> = outcomes
+            .into_iter()
+            .filter(|outcome| !matches!(outcome, ReadOutcome::Hash(_)))
+            .collect();
+        let expected: Vec<_> = msgs.into_iter().map(ReadOutcome::Message).collect();
+        assert!(received == expected);
+    }
+
+    #[test]
+    fn corrupted_header_is_detected() {
+        let (mut a, mut b) = pair();
+        a.header_checksum = true;
+        b.header_checksum = true;
+
+        let malformed = Arc::new(Mutex::new(Vec::new()));
+        let malformed2 = Arc::clone(&malformed);
+        b.malformed_frame = Some(Box::new(move |instr| {
+            malformed2.lock().unwrap().push(instr)
+        }));
+
+        a.send("intact").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("intact".to_string())
+        );
+
+        // the length is corrupted in transit: 5 instead of 6
+        let mut frame = a.header(INSTR_MESSAGE, 6);
+        frame[1] = b'5';
+        frame.extend_from_slice(b"broken");
+        a.send_all(&frame).unwrap();
+
+        let e = b.read_once_detailed().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(*malformed.lock().unwrap(), vec![INSTR_MESSAGE]);
+    }
+
+    #[test]
+    fn close_after_drain_delivers_final_hash() {
+        let (mut a, mut b) = pair();
+
+        let rcvd = Arc::new(Mutex::new(Vec::new()));
+        let rcvd2 = Arc::clone(&rcvd);
+        a.hash_rcvd = Some(Box::new(move |hash| rcvd2.lock().unwrap().push(hash)));
+
+        a.send("bye").unwrap();
+
+        // the peer echoes the hash and closes right away
+        b.read_once().unwrap();
+        b.close().unwrap();
+
+        a.close_after_drain(Duration::from_millis(200)).unwrap();
+
+        assert_eq!(*rcvd.lock().unwrap(), vec![hash_of(b"bye")]);
+        assert_eq!(a.s.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn offers_are_decided_later() {
+        let dir = temp_dir("offers");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+
+        let accepted = a.offer_file("accepted.txt", 3).unwrap();
+        let declined = a.offer_file("declined.txt", 4).unwrap();
+
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::FileOffered(accepted, "accepted.txt".to_string(), 3)
+        );
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::FileOffered(declined, "declined.txt".to_string(), 4)
+        );
+
+        // the decision is made while other traffic goes on
+        a.send("still there?").unwrap();
+        b.read_once().unwrap();
+        a.expect_hash().unwrap();
+
+        b.decline_file(declined).unwrap();
+        b.accept_file(accepted).unwrap();
+
+        assert_eq!(
+            a.read_once_detailed().unwrap(),
+            ReadOutcome::OfferDeclined(declined)
+        );
+        assert_eq!(
+            a.read_once_detailed().unwrap(),
+            ReadOutcome::OfferAccepted(accepted)
+        );
+
+        // `file_incoming` is not consulted for accepted offers
+        a.send_reader(&mut Cursor::new(b"abc".to_vec())).unwrap();
+
+        match b.read_once_detailed().unwrap() {
+            ReadOutcome::File(name) => assert_eq!(fs::read(name).unwrap(), b"abc"),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skipped_offers_are_given_up() {
+        let dir = temp_dir("offers-skipped");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+
+        let skipped = Arc::new(Mutex::new(Vec::new()));
+        let skipped2 = Arc::clone(&skipped);
+        b.offer_skipped = Some(Box::new(move |id| skipped2.lock().unwrap().push(id)));
+
+        let first = a.offer_file("first.txt", 3).unwrap();
+        let second = a.offer_file("second.txt", 4).unwrap();
+        b.read_once().unwrap();
+        b.read_once().unwrap();
+        b.accept_file(first).unwrap();
+        b.accept_file(second).unwrap();
+
+        // the file of the second offer arrives first, so the first one is not waited for
+        a.send_reader(&mut Cursor::new(b"abcd".to_vec())).unwrap();
+
+        match b.read_once_detailed().unwrap() {
+            ReadOutcome::File(name) => assert_eq!(fs::read(name).unwrap(), b"abcd"),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+        assert_eq!(*skipped.lock().unwrap(), [first]);
+        assert!(b.offers_accepted.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn send_progress_is_monotonic() {
+        let dir = temp_dir("progress");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
+
+        let totals = Arc::new(Mutex::new(Vec::new()));
+        let totals2 = Arc::clone(&totals);
+        a.send_progress = Some(Box::new(move |total, _| {
+            totals2.lock().unwrap().push(total)
+        }));
+
+        a.send_reader(&mut Cursor::new(vec![1; 10_000])).unwrap();
+        b.read_once().unwrap();
+
+        let totals = totals.lock().unwrap();
+        assert!(totals.len() > 1);
+        assert!(totals.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(totals.last(), Some(&10_000));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn peer_closing_before_hash_echo_is_an_error() {
+        let dir = temp_dir("closing");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
+
+        let closed = Arc::new(AtomicUsize::new(0));
+        let closed2 = Arc::clone(&closed);
+        b.peer_closed = Some(Box::new(move || {
+            closed2.fetch_add(1, Ordering::Relaxed);
+        }));
+
+        a.send_reader(&mut Cursor::new(b"last words".to_vec()))
+            .unwrap();
+        drop(a);
+        thread::sleep(Duration::from_millis(50));
+
+        // echoing the hash may or may not fail yet, but must not panic
+        let first = b.read_once_detailed();
+        let second = b.read_once_detailed();
+
+        assert!(first.is_err() || second.is_err());
+        assert!(closed.load(Ordering::Relaxed) > 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn received_files_are_registered() {
+        let dir = temp_dir("registry");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
+
+        a.send_reader(&mut Cursor::new(b"first".to_vec())).unwrap();
+        a.send_reader(&mut Cursor::new(b"second!".to_vec()))
+            .unwrap();
+        b.read_once().unwrap();
+        b.read_once().unwrap();
+
+        let files = b.received_files();
+        assert_eq!(files.len(), 2);
+
+        for (file, data) in files.iter().zip([&b"first"[..], b"second!"]) {
+            assert_eq!(fs::read(&file.name).unwrap(), data);
+            assert_eq!(file.size, data.len());
+            assert_eq!(file.peer_hash, Some(hash_of(data)));
+            assert_eq!(file.our_hash, Some(hash_of(data)));
+        }
+
+        b.clear_received_files();
+        assert!(b.received_files().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn acked_messages_are_acknowledged() {
+        let (mut a, mut b) = pair();
+        b.msg_accept = Box::new(|msg| msg != "reject me");
+
+        let acks = Arc::new(Mutex::new(Vec::new()));
+        let acks2 = Arc::clone(&acks);
+        a.msg_acked = Some(Box::new(move |ack| acks2.lock().unwrap().push(ack)));
+
+        a.send_acked("accept me").unwrap();
+        a.send_acked("reject me").unwrap();
+        b.read_once().unwrap();
+        b.read_once().unwrap();
+
+        let outcomes: Vec<_> = (0..4).map(|_| a.read_once_detailed().unwrap()).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ReadOutcome::Hash(hash_of(b"accept me")),
+                ReadOutcome::Ack(true),
+                ReadOutcome::Hash(hash_of(b"reject me")),
+                ReadOutcome::Ack(false),
+            ]
+        );
+        assert_eq!(*acks.lock().unwrap(), vec![true, false]);
+    }
+
+    #[test]
+    fn hash_hex_matches_sha256sum() {
+        assert_eq!(
+            hash_hex(&hash_of(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn declared_hash_mismatch_is_detected() {
+        let dir = temp_dir("declared");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
+
+        let hash = a
+            .send_reader_hash_first(&mut Cursor::new(b"data".to_vec()))
+            .unwrap();
+        assert_eq!(hash, hash_of(b"data"));
+        assert!(matches!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::File(_)
+        ));
+
+        // a frame declaring a hash that does not match the data
+        let mut frame = a.header(INSTR_FILE_HASH_FIRST, 4);
+        frame.extend_from_slice(&hash_of(b"other"));
+        frame.extend_from_slice(b"data");
+        a.send_all(&frame).unwrap();
+
+        assert!(matches!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::HashMismatch(_)
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn slow_writes_are_reported() {
+        let blocked = Arc::new(Mutex::new(Vec::new()));
+        let reported = Arc::clone(&blocked);
+
+        let (mut a, mut b) = pair();
+        a.send_blocked_after = Duration::from_millis(50);
+        a.send_blocked = Some(Box::new(move |took| reported.lock().unwrap().push(took)));
+
+        // the peer only starts reading once the socket buffers are full
+        let reader = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            b.read_once_detailed().unwrap()
+        });
+
+        let len = 32 * 1024 * 1024;
+        a.send_reader(&mut Cursor::new(vec![0; len])).unwrap();
+
+        assert_eq!(reader.join().unwrap(), ReadOutcome::FileRejected(len));
+
+        let blocked = blocked.lock().unwrap();
+        assert!(!blocked.is_empty());
+        assert!(blocked.iter().all(|took| *took > Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn connect_and_accept_are_ready_to_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let peer = thread::spawn(move || {
+            let mut b = Talker::accept(&listener).unwrap();
+            let outcome = b.read_once_detailed().unwrap();
+            b.send("pong").unwrap();
+
+            outcome
+        });
+
+        let mut a = Talker::connect(addr).unwrap();
+        a.send("ping").unwrap();
+        a.expect_hash().unwrap();
+        assert_eq!(
+            a.read_once_detailed().unwrap(),
+            ReadOutcome::Message("pong".to_string())
+        );
+
+        assert_eq!(
+            peer.join().unwrap(),
+            ReadOutcome::Message("ping".to_string())
+        );
+    }
+
+    #[test]
+    fn prepared_files_are_sent_as_prepared() {
+        let dir = temp_dir("prepared");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.join("in"));
+        b.file_incoming = Box::new(|_| true);
+
+        let path = dir.join("notes.txt");
+        fs::write(&path, b"prepared contents").unwrap();
+
+        let offer = prepare_file(&path).unwrap();
+        assert_eq!(offer.name, "notes.txt");
+        assert_eq!(offer.size, 17);
+        assert_eq!(offer.hash, hash_of(b"prepared contents"));
+
+        assert_eq!(a.send_prepared(&offer).unwrap(), offer.hash);
+        match b.read_once_detailed().unwrap() {
+            ReadOutcome::File(name) => assert_eq!(fs::read(name).unwrap(), b"prepared contents"),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+
+        // the file must not change between preparing and sending it
+        fs::write(&path, b"changed").unwrap();
+        assert!(a.send_prepared(&offer).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn handshaked_talkers_exchange_messages() {
+        let (a, b) = raw_pair();
+        let mut a = Talker::new_handshaked(a);
+        let mut b = Talker::new_handshaked(b);
+        assert!(a.is_established() && b.is_established());
+        assert!(!Talker::new(raw_pair().0).is_established());
+
+        a.send("no handshake").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("no handshake".to_string())
+        );
+        a.expect_hash().unwrap();
+    }
+
+    #[test]
+    fn byte_totals_match_wire() {
+        let (mut a, mut b) = pair();
+
+        let counters: Vec<Arc<AtomicUsize>> = (0..4).map(|_| Arc::default()).collect();
+        let counter = |i: usize| {
+            let c = Arc::clone(&counters[i]);
+            Some(Box::new(move |n| {
+                c.fetch_add(n, Ordering::Relaxed);
+            }) as Box<dyn Fn(usize) + Send>)
+        };
+        a.bytes_written = counter(0);
+        a.bytes_read = counter(1);
+        b.bytes_written = counter(2);
+        b.bytes_read = counter(3);
+
+        a.send("hello").unwrap();
+        b.read_once().unwrap();
+        a.expect_hash().unwrap();
+
+        let totals: Vec<usize> = counters.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+
+        // `!5\nhello` one way and the hash frame the other
+        assert_eq!(totals, [8, 33, 33, 8]);
+    }
+
+    #[test]
+    fn lines_end_with_one_newline() {
+        let (mut a, mut b) = pair();
+
+        for (sent, expected) in [("hi", "hi\n"), ("hi\n", "hi\n"), ("hi\r\n\n", "hi\n")] {
+            a.send_line(sent).unwrap();
+            assert_eq!(
+                b.read_once_detailed().unwrap(),
+                ReadOutcome::Message(expected.to_string())
+            );
+            a.expect_hash().unwrap();
+        }
+
+        for raw in ["hi", "hi\r\n\n", " \t"] {
+            a.send_raw(raw).unwrap();
+            assert_eq!(
+                b.read_once_detailed().unwrap(),
+                ReadOutcome::Message(raw.to_string())
+            );
+            a.expect_hash().unwrap();
+        }
+    }
+
+    #[test]
+    fn paused_reads_resume_in_order() {
+        let (mut a, mut b) = pair();
+
+        b.pause();
+        a.send("first").unwrap();
+        a.send("second").unwrap();
+        assert_eq!(b.read_once_detailed().unwrap(), ReadOutcome::Nothing);
+
+        b.resume();
+        for msg in ["first", "second"] {
+            assert_eq!(
+                b.read_once_detailed().unwrap(),
+                ReadOutcome::Message(msg.to_string())
+            );
+        }
+    }
+
+    /// A reader that fails with `ErrorKind::Interrupted` before every read from `inner`, `interruptions` times in a row.
+    struct Interrupting<R> {
+        inner: R,
+        interruptions: usize,
+        left: usize,
+    }
+
+    impl<R: Read> Read for Interrupting<R> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.left > 0 {
+                self.left -= 1;
+
+                return Err(Error::new(ErrorKind::Interrupted, "interrupted"));
+            }
+
+            self.left = self.interruptions;
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn interrupted_reads_are_retried() {
+        let dir = temp_dir("interrupted");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
+
+        let mut r = Interrupting {
+            inner: Cursor::new(b"despite interruptions".to_vec()),
+            interruptions: 1,
+            left: 1,
+        };
+        a.send_stream(&mut r, 21).unwrap();
+
+        match b.read_once_detailed().unwrap() {
+            ReadOutcome::File(name) => {
+                assert_eq!(fs::read(name).unwrap(), b"despite interruptions")
+            }
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+
+        // more interruptions in a row than retries allowed
+        let mut r = Interrupting {
+            inner: Cursor::new(b"data".to_vec()),
+            interruptions: 4,
+            left: 4,
+        };
+        let err = a.send_stream(&mut r, 4).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Exchanges the capabilities of both `Talker`s of a pair.
+    fn exchange_capabilities(a: &mut Talker, b: Talker) -> Talker {
+        let b = thread::spawn(move || {
+            let mut b = b;
+            b.exchange_capabilities().unwrap();
+
+            b
+        });
+        a.exchange_capabilities().unwrap();
+
+        b.join().unwrap()
+    }
+
+    #[test]
+    fn capabilities_are_exchanged() {
+        let (mut a, b) = pair();
+        assert!(!a.peer_supports(Capability::AckedMessages));
+
+        let b = exchange_capabilities(&mut a, b);
+        assert_eq!(
+            (a.peer_caps, b.peer_caps),
+            (Capability::ours(), Capability::ours())
+        );
+
+        for cap in [
+            Capability::AckedMessages,
+            Capability::ChunkedMessages,
+            Capability::FileOffers,
+            Capability::HashFirst,
+        ] {
+            assert!(a.peer_supports(cap) && b.peer_supports(cap));
+        }
+        assert_eq!(
+            a.peer_supports(Capability::Signatures),
+            cfg!(feature = "sign")
+        );
+    }
+
+    #[test]
+    fn duplicates_within_window_are_reported() {
+        let new = Arc::new(AtomicUsize::new(0));
+        let duplicates = Arc::new(AtomicUsize::new(0));
+
+        let (mut a, mut b) = pair();
+        b.duplicate_window = 2;
+        let count = Arc::clone(&new);
+        b.msg_new = Some(Box::new(move |_| {
+            count.fetch_add(1, Ordering::Relaxed);
+        }));
+        let count = Arc::clone(&duplicates);
+        b.msg_duplicate = Some(Box::new(move |_| {
+            count.fetch_add(1, Ordering::Relaxed);
+        }));
+
+        a.send("again").unwrap();
+        a.send("again").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("again".to_string())
+        );
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Duplicate("again".to_string())
+        );
+
+        assert_eq!(new.load(Ordering::Relaxed), 1);
+        assert_eq!(duplicates.load(Ordering::Relaxed), 1);
+
+        // only the last two messages are remembered
+        for msg in ["one", "two", "again"] {
+            a.send(msg).unwrap();
+            assert_eq!(
+                b.read_once_detailed().unwrap(),
+                ReadOutcome::Message(msg.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn file_names_have_prefix_and_suffix() {
+        let dir = temp_dir("affixes");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
+        b.file_prefix = "received-".to_string();
+        b.file_suffix = ".dat".to_string();
+
+        a.send_reader(&mut Cursor::new(b"data".to_vec())).unwrap();
+
+        match b.read_once_detailed().unwrap() {
+            ReadOutcome::File(name) => {
+                let name = Path::new(&name).file_name().unwrap().to_str().unwrap();
+
+                assert!(name.starts_with("received-") && name.ends_with(".dat"));
+            }
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checked_sends_detect_corruption() {
+        let dir = temp_dir("checked");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.join("in"));
+        b.file_incoming = Box::new(|_| true);
+
+        let path = dir.join("file");
+        fs::write(&path, b"original").unwrap();
+        a.send_file_checked(&path, hash_of(b"original")).unwrap();
+        assert!(matches!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::File(_)
+        ));
+
+        fs::write(&path, b"corrupted").unwrap();
+        let err = a
+            .send_file_checked(&path, hash_of(b"original"))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::HashMismatch(_)
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn linger_is_set_on_socket() {
+        let (a, _b) = pair();
+
+        a.set_linger(Some(Duration::from_secs(3))).unwrap();
+        assert_eq!(a.linger().unwrap(), Some(Duration::from_secs(3)));
+
+        a.set_linger(None).unwrap();
+        assert_eq!(a.linger().unwrap(), None);
+    }
+
+    #[test]
+    fn wire_sizes_are_computed_by_hand() {
+        // `!`, `12`, `\n`, the message and the hash frame
+        assert_eq!(wire_size("hello, world"), 1 + 2 + 1 + 12 + 33);
+        // `#`, `1000`, `\n`, the file and both hash frames
+        assert_eq!(file_wire_size(1000), 1 + 4 + 1 + 1000 + 33 + 33);
+
+        // compared with what is actually written
+        let (mut a, mut b) = pair();
+        let written = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&written);
+        a.bytes_written = Some(Box::new(move |n| {
+            count.fetch_add(n, Ordering::Relaxed);
+        }));
+
+        a.send("hello, world").unwrap();
+        b.read_once().unwrap();
+        assert_eq!(written.load(Ordering::Relaxed), 1 + 2 + 1 + 12);
+    }
+
+    #[test]
+    fn appended_files_are_concatenated() {
+        let dir = temp_dir("append");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
+        b.file_append = true;
+        b.file_prefix = "log".to_string();
+        b.file_suffix = ".txt".to_string();
+
+        for part in [&b"first\n"[..], b"second\n"] {
+            a.send_reader(&mut Cursor::new(part.to_vec())).unwrap();
+            assert!(matches!(
+                b.read_once_detailed().unwrap(),
+                ReadOutcome::File(_)
+            ));
+        }
+
+        assert_eq!(fs::read(dir.join("log.txt")).unwrap(), b"first\nsecond\n");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn interleaved_instructions_are_queued() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let (mut a, mut b) = pair();
+        let log = Arc::clone(&events);
+        a.on_queued = Some(Box::new(move |instr| {
+            log.lock().unwrap().push(("queued", instr))
+        }));
+        let log = Arc::clone(&events);
+        a.on_dequeued = Some(Box::new(move |instr| {
+            log.lock().unwrap().push(("dequeued", instr))
+        }));
+
+        // the peer's message arrives before the hash of ours
+        b.send("interleaved").unwrap();
+        a.send("mine").unwrap();
+        b.read_once().unwrap();
+
+        assert!(a.expect_hash().is_err());
+        assert_eq!(*events.lock().unwrap(), [("queued", b'!')]);
+
+        assert_eq!(
+            a.read_once_detailed().unwrap(),
+            ReadOutcome::Message("interleaved".to_string())
+        );
+        assert_eq!(
+            *events.lock().unwrap(),
+            [("queued", b'!'), ("dequeued", b'!')]
+        );
+
+        a.expect_hash().unwrap();
+    }
+
+    #[test]
+    fn slow_transfers_are_aborted() {
+        let dir = temp_dir("slow");
+
+        for append in [false, true] {
+            let (mut a, mut b) = pair();
+            b.download_dir = Some(dir.clone());
+            b.file_incoming = Box::new(|_| true);
+            b.file_append = append;
+            b.max_transfer_duration = Some(Duration::from_millis(200));
+
+            if append {
+                a.send_reader(&mut Cursor::new(b"earlier".to_vec()))
+                    .unwrap();
+                b.read_once().unwrap();
+            }
+
+            // only part of the announced file ever arrives
+            let mut frame = a.header(INSTR_FILE, 100);
+            frame.extend_from_slice(&[0; 10]);
+            a.send_all(&frame).unwrap();
+
+            let start = Instant::now();
+            let err = b.read_once_detailed().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert!(start.elapsed() < Duration::from_secs(2));
+
+            let files: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+
+            if append {
+                // the file shared with earlier transfers is kept
+                assert_eq!(files.len(), 1);
+                assert!(fs::read(dir.join("transfer_"))
+                    .unwrap()
+                    .starts_with(b"earlier"));
+            } else {
+                assert!(files.is_empty());
+            }
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn payload_crc_matches_reference() {
+        let crcs = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&crcs);
+
+        let (mut a, mut b) = pair();
+        b.payload_crc = Some(Box::new(move |crc| log.lock().unwrap().push(crc)));
+
+        // the check value of CRC-32/ISO-HDLC
+        a.send("123456789").unwrap();
+        b.read_once().unwrap();
+
+        assert_eq!(*crcs.lock().unwrap(), [0xcbf4_3926]);
+    }
+
+    #[test]
+    fn overlong_lengths_are_malformed() {
+        let (mut a, mut b) = pair();
+        b.max_header_digits = 7;
+
+        a.send_all(b"#1234567890\n").unwrap();
+        assert_eq!(b.read_once_detailed().unwrap(), ReadOutcome::Malformed);
+
+        // for messages, the cap applies regardless
+        let (mut a, mut b) = pair();
+        a.send_all(b"!1234567890\n").unwrap();
+        assert_eq!(b.read_once_detailed().unwrap(), ReadOutcome::Malformed);
+    }
+
+    #[test]
+    fn requested_close_lets_transfer_complete() {
+        let dir = temp_dir("request-close");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
+
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let sender = thread::spawn(move || a.send_reader(&mut Cursor::new(sent)));
+
+        // the transfer is under way
+        thread::sleep(Duration::from_millis(50));
+        b.request_close();
+
+        match b.read_once_detailed().unwrap() {
+            ReadOutcome::File(name) => assert!(fs::read(name).unwrap() == data),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+        assert_eq!(sender.join().unwrap().unwrap(), hash_of(&data));
+
+        let err = b.read_once_detailed().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn requested_close_keeps_reading_blocking() {
+        let (mut a, mut b) = pair();
+
+        // only the header and part of the message have arrived
+        let frame = a.header(INSTR_MESSAGE, 5);
+        a.send_all(&frame).unwrap();
+        a.send_all(b"he").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            a.send_all(b"llo").unwrap();
+
+            a
+        });
+
+        b.request_close();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("hello".to_string())
+        );
+        let mut a = sender.join().unwrap();
+        a.expect_hash().unwrap();
+
+        let err = b.read_once_detailed().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn requested_close_ends_sending() {
+        let (mut a, mut b) = pair();
+        a.request_close();
+
+        for res in [
+            a.send("hello"),
+            a.send_raw("hello"),
+            a.send_chunked("hello", 2),
+            a.send_reader(&mut Cursor::new(b"hello".to_vec()))
+                .map(|_| ()),
+        ] {
+            assert_eq!(res.unwrap_err().kind(), ErrorKind::NotConnected);
+        }
+
+        // the peer sees the connection end without receiving anything
+        let err = b.read_once_detailed().unwrap_err();
+        assert_eq!(err.to_string(), "Lost connection with peer");
+    }
+
+    #[test]
+    fn control_characters_are_sanitized() {
+        let msg = "\x1b[2Jspoofed\rline\n\tend";
+
+        for (mode, expected) in [
+            (Sanitize::Off, msg),
+            (Sanitize::Strip, "[2Jspoofedline\n\tend"),
+            (Sanitize::Escape, "\\u{1b}[2Jspoofed\\rline\n\tend"),
+        ] {
+            let (mut a, mut b) = pair();
+            b.sanitize = mode;
+
+            a.send(msg).unwrap();
+            assert_eq!(
+                b.read_once_detailed().unwrap(),
+                ReadOutcome::Message(expected.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn counted_sends_report_octets_read() {
+        let (mut a, mut b) = pair();
+
+        let data = vec![7; 100];
+        let (hash, count) = a
+            .send_stream_counted(&mut Cursor::new(data.clone()), 100)
+            .unwrap();
+        assert_eq!((hash, count), (hash_of(&data), 100));
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::FileRejected(100)
+        );
+
+        // the reader delivers less than announced, which the caller has to check
+        let (_, count) = a
+            .send_stream_counted(&mut Cursor::new(vec![7; 60]), 100)
+            .unwrap();
+        assert_eq!(count, 60);
+    }
+
+    #[test]
+    fn banners_round_trip() {
+        let (mut a, mut b) = pair_with(|a| a.banner = Some("talkers-test/1.0".to_string()), |_| {});
+
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Banner("talkers-test/1.0".to_string())
+        );
+        assert_eq!(b.peer_banner.as_deref(), Some("talkers-test/1.0"));
+
+        // overlong banners are neither sent nor accepted
+        a.banner = Some("x".repeat(MAX_BANNER_LEN + 1));
+        let err = a.perform_handshake().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let mut frame = a.header(INSTR_BANNER, MAX_BANNER_LEN + 1);
+        frame.extend_from_slice("x".repeat(MAX_BANNER_LEN + 1).as_bytes());
+        a.send_all(&frame).unwrap();
+        assert_eq!(b.read_once_detailed().unwrap(), ReadOutcome::Malformed);
+        assert_eq!(b.peer_banner.as_deref(), Some("talkers-test/1.0"));
+
+        // and the stream is still in sync
+        a.send("after").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("after".to_string())
+        );
+    }
+
+    /// A `MemoryFileSystem` that counts how often received files are synchronized.
+    struct SyncCounting(MemoryFileSystem, Arc<AtomicUsize>);
+
+    /// A file of `SyncCounting`.
+    struct SyncCountingFile(Box<dyn TransferFile>, Arc<AtomicUsize>);
+
+    impl Write for SyncCountingFile {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl TransferFile for SyncCountingFile {
+        fn sync_all(&self) -> Result<()> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+
+            self.0.sync_all()
+        }
+    }
+
+    impl FileSystem for SyncCounting {
+        fn open(&self, path: &Path) -> Result<SourceFile> {
+            self.0.open(path)
+        }
+
+        fn create_new(&self, path: &Path) -> Result<Box<dyn TransferFile>> {
+            let fp = self.0.create_new(path)?;
+
+            Ok(Box::new(SyncCountingFile(fp, Arc::clone(&self.1))))
+        }
+
+        fn append(&self, path: &Path) -> Result<Box<dyn TransferFile>> {
+            let fp = self.0.append(path)?;
+
+            Ok(Box::new(SyncCountingFile(fp, Arc::clone(&self.1))))
+        }
+
+        fn create_dir_all(&self, path: &Path) -> Result<()> {
+            self.0.create_dir_all(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+            self.0.rename(from, to)
+        }
+
+        fn rename_new(&self, from: &Path, to: &Path) -> Result<()> {
+            self.0.rename_new(from, to)
+        }
+
+        fn remove_file(&self, path: &Path) -> Result<()> {
+            self.0.remove_file(path)
+        }
+
+        fn set_mode(&self, path: &Path, mode: u32) -> Result<()> {
+            self.0.set_mode(path, mode)
+        }
+    }
+
+    #[test]
+    fn files_are_synced_if_requested() {
+        for file_sync in [false, true] {
+            let syncs = Arc::new(AtomicUsize::new(0));
+            let files = MemoryFileSystem::new();
+
+            let (mut a, mut b) = pair();
+            b.file_system = Box::new(SyncCounting(files.clone(), Arc::clone(&syncs)));
+            b.file_incoming = Box::new(|_| true);
+            b.file_sync = file_sync;
+
+            a.send_reader(&mut Cursor::new(b"synced".to_vec())).unwrap();
+
+            match b.read_once_detailed().unwrap() {
+                ReadOutcome::File(name) => assert_eq!(files.contents(name).unwrap(), b"synced"),
+                outcome => panic!("unexpected outcome: {:?}", outcome),
+            }
+            assert_eq!(syncs.load(Ordering::Relaxed), usize::from(file_sync));
+        }
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        let (mut a, mut b) = pair();
+
+        a.send_meta(&[("reply-to", "42"), ("lang", "en=GB")], "body\nwith lines")
+            .unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Meta(
+                vec![
+                    ("reply-to".to_string(), "42".to_string()),
+                    ("lang".to_string(), "en=GB".to_string()),
+                ],
+                "body\nwith lines".to_string()
+            )
+        );
+        a.expect_hash().unwrap();
+
+        // invalid keys are refused before anything is sent
+        assert!(a.send_meta(&[("a=b", "c")], "body").is_err());
+        assert!(a.send_meta(&[("", "c")], "body").is_err());
+    }
+
+    #[test]
+    fn headers_are_parsed_from_socket() {
+        let (mut a, mut b) = pair();
+
+        a.send_all(b"12 345\n").unwrap();
+        assert_eq!(b.read_header(INSTR_OFFER, 2).unwrap(), Some(vec![12, 345]));
+
+        // the header ends before all numbers have been read
+        a.send_all(b"12\n").unwrap();
+        assert_eq!(b.read_header(INSTR_OFFER, 2).unwrap(), None);
+
+        a.send_all(b"1x\n").unwrap();
+        assert_eq!(b.read_header(INSTR_FILE, 1).unwrap(), None);
+
+        // framing around the header still works
+        let (mut a, mut b) = pair_with(|a| a.header_checksum = true, |b| b.header_checksum = true);
+        for msg in ["one", "two"] {
+            a.send(msg).unwrap();
+            assert_eq!(
+                b.read_once_detailed().unwrap(),
+                ReadOutcome::Message(msg.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn total_received_is_limited() {
+        let (mut a, mut b) = pair();
+        b.max_total_received = Some(10);
+
+        for _ in 0..2 {
+            a.send("12345").unwrap();
+            assert_eq!(
+                b.read_once_detailed().unwrap(),
+                ReadOutcome::Message("12345".to_string())
+            );
+            a.expect_hash().unwrap();
+        }
+
+        a.send("x").unwrap();
+        let err = b.read_once_detailed().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+
+        // the connection has been closed
+        assert!(b.read_once().is_err());
+        assert!(a.expect_hash().is_err());
+    }
+
+    #[test]
+    fn shared_nonces_fail_handshake() {
+        for (nonce_a, nonce_b, connected) in [(7, 7, false), (7, 8, true)] {
+            let (a, b) = raw_pair();
+
+            let b = thread::spawn(move || {
+                let mut b = Talker::new(b);
+                b.handshake_nonce = Some(nonce_b);
+
+                b.handshake(false).map(|_| ())
+            });
+
+            let mut a = Talker::new(a);
+            a.handshake_nonce = Some(nonce_a);
+            assert_eq!(a.handshake(true).is_ok(), connected);
+
+            // the acceptor sees the nonce first and hangs up
+            match b.join().unwrap() {
+                Ok(()) => assert!(connected),
+                Err(e) => assert!(!connected && e.kind() == ErrorKind::ConnectionRefused),
+            }
+        }
+
+        assert_eq!(process_nonce(), process_nonce());
+    }
+
+    #[test]
+    fn streamed_messages_reassemble() {
+        let parts = Arc::new(Mutex::new(Vec::new()));
+        let ended = Arc::new(AtomicUsize::new(0));
+        let echoed = Arc::new(Mutex::new(None));
+
+        let (mut a, mut b) = pair();
+        let log = Arc::clone(&parts);
+        b.msg_part = Some(Box::new(move |part| {
+            log.lock().unwrap().push(part.to_vec())
+        }));
+        let count = Arc::clone(&ended);
+        b.msg_end = Some(Box::new(move || {
+            count.fetch_add(1, Ordering::Relaxed);
+        }));
+        let log = Arc::clone(&echoed);
+        a.hash_rcvd = Some(Box::new(move |hash| *log.lock().unwrap() = Some(hash)));
+
+        let msg = "piece by piece ".repeat(200);
+        a.send(&msg).unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::MessageStreamed(msg.len())
+        );
+        a.expect_hash().unwrap();
+
+        let parts = parts.lock().unwrap();
+        assert!(parts.len() > 1);
+        assert_eq!(parts.concat(), msg.as_bytes());
+        assert_eq!(ended.load(Ordering::Relaxed), 1);
+        assert_eq!(*echoed.lock().unwrap(), Some(hash_of(msg.as_bytes())));
+    }
+
+    #[test]
+    fn unsafe_offered_names_are_replaced() {
+        let rejected = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&rejected);
+
+        let (mut a, mut b) = pair();
+        b.file_name_rejected = Some(Box::new(move |name| log.lock().unwrap().push(name)));
+
+        for name in ["../etc/passwd", "nul\0byte", "..", "fine.txt"] {
+            a.offer_file(name, 1).unwrap();
+
+            match b.read_once_detailed().unwrap() {
+                ReadOutcome::FileOffered(_, offered, _) if name == "fine.txt" => {
+                    assert_eq!(offered, name)
+                }
+                ReadOutcome::FileOffered(_, offered, _) => {
+                    assert!(offered.starts_with(&b.file_prefix))
+                }
+                outcome => panic!("unexpected outcome: {:?}", outcome),
+            }
+        }
+
+        assert_eq!(
+            *rejected.lock().unwrap(),
+            ["../etc/passwd", "nul\0byte", ".."]
+        );
+    }
+
+    #[test]
+    fn round_trip_time_is_measured() {
+        let (mut a, mut b) = pair();
+
+        let peer = thread::spawn(move || b.read_once_detailed().unwrap());
+        let rtt = a.measure_rtt().unwrap();
+
+        assert_eq!(peer.join().unwrap(), ReadOutcome::Ping);
+        assert!(rtt > Duration::ZERO && rtt < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn files_beyond_free_space_are_rejected() {
+        let dir = temp_dir("space");
+        let queried = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&queried);
+
+        let (mut a, mut b) = pair();
+        // the download directory does not exist yet
+        b.download_dir = Some(dir.join("not/yet"));
+        b.file_incoming = Box::new(|_| true);
+        b.free_space = Box::new(move |dir| {
+            log.lock().unwrap().push(dir.to_path_buf());
+            Some(10)
+        });
+
+        a.send_reader(&mut Cursor::new(vec![0; 11])).unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::FileRejected(11)
+        );
+
+        a.send_reader(&mut Cursor::new(vec![0; 10])).unwrap();
+        assert!(matches!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::File(_)
+        ));
+
+        // the space is queried where the directory is going to be created
+        assert_eq!(queried.lock().unwrap()[0], dir);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "space")]
+    #[test]
+    fn free_space_is_queried_from_system() {
+        let (a, _b) = pair();
+
+        assert!((a.free_space)(&std::env::temp_dir()).is_some_and(|free| free > 0));
+    }
+
+    #[cfg(feature = "compress")]
+    #[test]
+    fn compressed_messages_round_trip() {
+        let (mut a, b) = pair();
+        let mut b = exchange_capabilities(&mut a, b);
+        a.compress_above = Some(100);
+
+        let written = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&written);
+        a.bytes_written = Some(Box::new(move |n| {
+            count.fetch_add(n, Ordering::Relaxed);
+        }));
+        let echoed = Arc::new(Mutex::new(None));
+        let log = Arc::clone(&echoed);
+        a.hash_rcvd = Some(Box::new(move |hash| *log.lock().unwrap() = Some(hash)));
+
+        let msg = "compressible ".repeat(1000);
+        a.send(&msg).unwrap();
+        assert!(written.load(Ordering::Relaxed) < msg.len() / 10);
+
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message(msg.clone())
+        );
+        a.expect_hash().unwrap();
+        assert_eq!(*echoed.lock().unwrap(), Some(hash_of(msg.as_bytes())));
+
+        // the peer's limit applies to the message before compression
+        b.set_max_msg_size(1000, true).unwrap();
+        assert!(matches!(
+            a.read_once_detailed().unwrap(),
+            ReadOutcome::Limits(_)
+        ));
+
+        let before = written.load(Ordering::Relaxed);
+        let err = a.send(&msg).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(written.load(Ordering::Relaxed), before);
+    }
+
+    #[test]
+    fn receive_progress_is_reported() {
+        let dir = temp_dir("progress");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
+        let monitor = b.transfer_monitor();
+
+        let data = vec![1; 10_000];
+        let mut frame = a.header(INSTR_FILE, data.len());
+        frame.extend_from_slice(&data[..4096]);
+        a.send_all(&frame).unwrap();
+
+        let receiver = thread::spawn(move || b.read_once_detailed().unwrap());
+
+        // the rest of the file has not been sent yet, and it is read in pieces of 1024 octets
+        let mut progress = None;
+        for _ in 0..200 {
+            if let Some(info) = monitor.active_transfers().pop() {
+                if info.done == 4096 {
+                    progress = Some(info);
+                    break;
+                }
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+        let info = progress.unwrap();
+        assert!(info.incoming);
+        assert_eq!(info.size, 10_000);
+
+        a.send_all(&data[4096..]).unwrap();
+        a.send_all(&a.hash_frame(&hash_of(&data))).unwrap();
+
+        assert!(matches!(receiver.join().unwrap(), ReadOutcome::File(_)));
+        assert!(monitor.active_transfers().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-    /// Invoked when the connection is closed.
-    pub chat_close: Option<Box<dyn Fn() + Send>>,
+    #[test]
+    fn overlong_names_are_truncated() {
+        assert_eq!(truncate_name("short.txt", 20), "short.txt");
+        assert_eq!(truncate_name("abcdefghij.txt", 10), "abcdef.txt");
+        // multi-octet characters are not cut in half
+        assert_eq!(truncate_name("ääääää.txt", 9), "ää.txt");
+        // extensions that do not fit are not kept
+        assert_eq!(truncate_name("a.verylongextension", 5), "a.ver");
 
-    /// Invoked when a new message is received.
-    pub msg_new: Option<Box<dyn Fn(String) + Send>>,
+        let (mut a, mut b) = pair();
+        b.max_file_name = 32;
 
-    /// Invoked when a file transfer has been announced by the peer. Called with the announced size. Must return a bool indicating whether or not to accept the file transfer. By default, file transfers are not accepted (except in the example app).
-    pub file_incoming: Box<dyn Fn(usize) -> bool + Send>,
+        a.offer_file(&format!("{}.tar.gz", "x".repeat(100)), 1)
+            .unwrap();
 
-    /// Invoked when a file transfer has failed. Called with the name of the transfer file and the error.
-    pub file_failed: Option<Box<dyn Fn(String, Error) + Send>>,
+        match b.read_once_detailed().unwrap() {
+            ReadOutcome::FileOffered(_, name, _) => {
+                // room is left for `.partial` and a number
+                assert_eq!(name, format!("{}.gz", "x".repeat(17)));
+            }
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+    }
 
-    /// Invoked when a file transfer has succeeded. Called with the name of the transfer file.
-    pub file_complete: Option<Box<dyn Fn(String) + Send>>,
+    #[test]
+    fn limits_are_queried() {
+        let (mut a, b) = pair();
+        let mut b = exchange_capabilities(&mut a, b);
+        b.max_file_size = Some(5000);
+        b.set_max_msg_size(1000, false).unwrap();
 
-    /// Invoked upon learning the intended hash of the file from the peer.
-    pub file_hash_by_peer: Option<Box<dyn Fn(String, Hash) + Send>>,
+        let peer = thread::spawn(move || b.read_once_detailed().unwrap());
+        assert_eq!(
+            a.query_limits().unwrap(),
+            PeerLimits {
+                max_msg_size: 1000,
+                max_file_size: Some(5000),
+            }
+        );
+        peer.join().unwrap();
 
-    /// Invoked upon having calculated the hash of the received file.
-    pub file_our_hash: Option<Box<dyn Fn(String, Hash) + Send>>,
+        // the limit applies before anything is sent
+        assert_eq!(
+            a.send(&"x".repeat(1001)).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
 
-    /// Invoked with the hash of the message or file that we sent.
-    pub hash_of_sent: Option<Box<dyn Fn(Hash) + Send>>,
+    #[test]
+    fn unverified_talkers_exchange_no_hashes() {
+        let dir = temp_dir("unverified");
+        let (mut a, mut b) = pair();
+        a.verify = false;
+        b.verify = false;
+        let mut b = exchange_capabilities(&mut a, b);
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
 
-    /// Invoked upon receiving a hash from the peer.
-    pub hash_rcvd: Option<Box<dyn Fn(Hash) + Send>>,
+        let counters: Vec<Arc<AtomicUsize>> = (0..2).map(|_| Arc::default()).collect();
+        let counter = |i: usize| {
+            let c = Arc::clone(&counters[i]);
+            Some(Box::new(move |n| {
+                c.fetch_add(n, Ordering::Relaxed);
+            }) as Box<dyn Fn(usize) + Send>)
+        };
+        a.bytes_written = counter(0);
+        b.bytes_written = counter(1);
 
-    /// Invoked if the peer tried to send a message or file that is too large.
-    pub payload_too_large: Option<Box<dyn Fn(usize) + Send>>,
+        a.send("hello").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("hello".to_string())
+        );
+        a.expect_hash().unwrap();
 
-    /// Invoked if the peer sent an invalid instruction. Useful for debugging.
-    pub invalid_instr: Option<Box<dyn Fn(u8) + Send>>,
-}
+        a.send_reader(&mut Cursor::new(b"data".to_vec())).unwrap();
+        assert!(matches!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::File(_)
+        ));
 
-impl Talker {
-    /// Constructs a new `Talker` instance from a TcpStream. The callbacks are set to "do nothing", and to reject file transfers.
-    pub fn new(s: TcpStream) -> Self {
-        Talker {
-            s,
-            queue: None,
-            closed: false, // assumes that the connection is initially open
-            chat_close: None,
-            msg_new: None,
-            file_incoming: Box::new(|_| false),
-            file_failed: None,
-            file_complete: None,
-            file_hash_by_peer: None,
-            file_our_hash: None,
-            hash_of_sent: None,
-            hash_rcvd: None,
-            invalid_instr: None,
-            payload_too_large: None,
-        }
+        // the frames themselves, without any hashes in either direction
+        assert_eq!(
+            counters[0].load(Ordering::Relaxed),
+            wire_size("hello") - 33 + file_wire_size(4) as usize - 66
+        );
+        assert_eq!(counters[1].load(Ordering::Relaxed), 0);
+
+        let files = b.received_files();
+        assert_eq!(files[0].our_hash, None);
+        assert_eq!(files[0].peer_hash, None);
+
+        fs::remove_dir_all(dir).unwrap();
     }
 
-    /// Shuts down the connection with a *talkers* peer.
-    pub fn close(&mut self) -> Result<()> {
-        if self.closed {
-            return Ok(());
-        } else if let Some(ref f) = self.chat_close {
-            f();
-            self.closed = true;
+    #[test]
+    fn resync_skips_garbage() {
+        let (mut a, mut b) = pair();
+
+        // near misses: instructions that are not followed by a plausible length
+        a.send_all(b"!!garbage#5x\n").unwrap();
+        a.send("hello").unwrap();
+
+        while !b.resync().unwrap() {
+            thread::sleep(Duration::from_millis(10));
         }
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("hello".to_string())
+        );
+        a.expect_hash().unwrap();
+    }
 
-        self.s.shutdown(Shutdown::Both)
+    #[test]
+    fn foreign_client_bytes_are_surfaced() {
+        let (mut client, b) = raw_pair();
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+
+        let seen = Arc::new(Mutex::new(None));
+        let mut b = Talker::new(b);
+        let s = Arc::clone(&seen);
+        b.bad_handshake = Some(Box::new(move |buf| {
+            *s.lock().unwrap() = Some(buf);
+        }));
+
+        let err = b.handshake(false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("GET / HT"));
+        assert_eq!(*seen.lock().unwrap(), Some(*b"GET / HT"));
     }
 
-    /// Reads from the *talkers* peer and checks whether the buffer read is a *talkers* handshake. Should be invoked if a connection was made with us.
-    pub fn expect_handshake(&mut self) -> Result<()> {
-        let mut buf = [0; 8];
+    #[test]
+    fn files_are_staged_until_complete() {
+        let names = |dir: &Path| -> Vec<PathBuf> {
+            fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .collect()
+        };
 
-        self.s.read_exact(&mut buf)?;
+        let dir = temp_dir("staged");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
 
-        if &buf == b"/talkers" {
-            Ok(())
-        } else {
-            Err(Error::new(ErrorKind::InvalidData, "Invalid handshake"))
-        }
+        a.send_reader(&mut Cursor::new(b"data".to_vec())).unwrap();
+        let filen = match b.read_once_detailed().unwrap() {
+            ReadOutcome::File(filen) => PathBuf::from(filen),
+            outcome => panic!("unexpected {:?}", outcome),
+        };
+        assert_eq!(names(&dir), vec![filen.clone()]);
+        assert_eq!(filen.extension(), None);
+        fs::remove_file(filen).unwrap();
+
+        // the peer goes away halfway through the data
+        let mut frame = a.header(INSTR_FILE, 8);
+        frame.extend_from_slice(b"data");
+        a.send_all(&frame).unwrap();
+        drop(a);
+
+        let _ = b.read_once_detailed();
+        let left = names(&dir);
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].extension().unwrap(), "partial");
+
+        // the staging suffix fits within the limit
+        b.max_file_name = 20;
+        assert!(b.max_stored_name() + ".partial_999".len() <= 20);
+
+        fs::remove_dir_all(dir).unwrap();
     }
 
-    /// Performs our half of the *talkers* handshake with the peer. Should be invoked if we initiated the connection or if we received a handshake.
-    pub fn perform_handshake(&mut self) -> Result<()> {
-        self.s.write_all(b"/talkers")
+    #[test]
+    fn heartbeat_pings_idle_peers() {
+        let interval = Duration::from_millis(100);
+        let (mut a, b) = pair();
+        let mut b = exchange_capabilities(&mut a, b);
+
+        assert!(!a.heartbeat(interval).unwrap());
+        thread::sleep(interval);
+        assert!(a.heartbeat(interval).unwrap());
+
+        // the peer answers the ping
+        b.read_once().unwrap();
+        a.read_once().unwrap();
+
+        // sending resets the idle time
+        thread::sleep(interval);
+        a.send("hello").unwrap();
+        assert!(!a.heartbeat(interval).unwrap());
     }
 
-    /// Reads precisely one instruction from the peer and process it accordingly.
-    pub fn read_once(&mut self) -> Result<bool> {
-        let mut instr = [0; 1];
+    #[test]
+    fn capabilities_are_not_awaited_forever() {
+        let (mut a, _b) = pair();
 
-        if let Some(ch) = self.queue {
-            instr[0] = ch;
-        } else {
-            let n = match self.s.read(&mut instr[0..1]) {
-                Ok(m) => m,
-                Err(e) => match e.kind() {
-                    ErrorKind::WouldBlock => return Ok(false),
-                    _ => return Err(e),
-                },
-            };
+        let started = Instant::now();
+        assert_eq!(
+            a.exchange_capabilities_within(Duration::from_millis(100))
+                .unwrap(),
+            None
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
 
-            if n == 0 {
-                return Err(Error::new(
-                    ErrorKind::NotConnected,
-                    "Lost connection with peer",
-                )); // todo?
+    #[test]
+    fn accepted_files_land_at_destination() {
+        let dir = temp_dir("destination");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.join("downloads"));
+
+        let id = a.offer_file("report.txt", 3).unwrap();
+        assert!(matches!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::FileOffered(..)
+        ));
+
+        let dest = dir.join("elsewhere").join("kept.txt");
+        b.accept_file_to(id, dest.clone()).unwrap();
+        assert_eq!(
+            a.read_once_detailed().unwrap(),
+            ReadOutcome::OfferAccepted(id)
+        );
+
+        a.send_reader(&mut Cursor::new(b"abc".to_vec())).unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::File(dest.to_string_lossy().into_owned())
+        );
+        assert_eq!(fs::read(&dest).unwrap(), b"abc");
+        assert!(!dir.join("downloads").exists());
+
+        // an existing file at the destination is kept
+        let id = a.offer_file("report.txt", 3).unwrap();
+        b.read_once().unwrap();
+        b.accept_file_to(id, dest.clone()).unwrap();
+        a.read_once().unwrap();
+
+        a.send_reader(&mut Cursor::new(b"xyz".to_vec())).unwrap();
+        let numbered = dir.join("elsewhere").join("kept_1.txt");
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::File(numbered.to_string_lossy().into_owned())
+        );
+        assert_eq!(fs::read(&dest).unwrap(), b"abc");
+        assert_eq!(fs::read(&numbered).unwrap(), b"xyz");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replies_wait_for_a_full_send_buffer() {
+        let (mut a, mut b) = pair();
+        a.send("hello").unwrap();
+
+        // fill the send buffer, as if the peer had stopped reading
+        let mut filled = 0;
+        b.s.set_nonblocking(true).unwrap();
+        loop {
+            match b.s.write(&[0; 65536]) {
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => panic!("{}", e),
             }
         }
+        b.s.set_nonblocking(false).unwrap();
 
-        let instr = instr[0];
-        let mut msg = Vec::new();
+        let mut s = a.s.try_clone().unwrap();
+        let drain = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let mut rest = Vec::new();
+            s.read_to_end(&mut rest).unwrap();
 
-        let mut is_file = false;
-        let mut skip = true;
+            rest.len()
+        });
 
-        let mut hasher = sha2::Sha256::new();
-        let mut fp;
+        // the hash echo has to wait until the peer reads again
+        while !b.read_maybe().unwrap() {}
+        drop(b);
 
-        fp = None;
+        assert_eq!(drain.join().unwrap(), filled + 33);
+    }
 
-        if instr == 33 || instr == 35 {
-            // message or file
-            if instr == 35 {
-                // is file
-                is_file = true;
-            }
-            let mut n_bytes = 0;
-            let mut j = 1;
+    #[test]
+    fn batches_report_aggregate_progress() {
+        let dir = temp_dir("batch");
+        let paths = vec![dir.join("one"), dir.join("two")];
+        fs::write(&paths[0], b"first").unwrap();
+        fs::write(&paths[1], b"second").unwrap();
 
-            self.s
-                .set_nonblocking(false)
-                .expect("Could not set TcpStream to blocking");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.join("received"));
+        b.file_incoming = Box::new(|_| true);
 
-            let mut ch = self.s.try_clone()?.bytes();
-            let mut filen = String::new();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let r = Arc::clone(&reports);
+        a.batch_progress = Some(Box::new(move |index, sent, total| {
+            r.lock().unwrap().push((index, sent, total))
+        }));
 
-            loop {
-                // read length of payload until space or newline
-                if let Some(Ok(ch)) = ch.next() {
-                    if (ch >= 48 && ch <= 57) || ch == 10 || ch == 32 {
-                        if ch == 10 || ch == 32 {
-                            skip = false; // everything seems ok so far
-                            break; // stop reading length
-                        } else {
-                            n_bytes *= 10;
-                            n_bytes += usize::from(ch - 48);
-                        }
-                    } else {
-                        break;
-                    }
-                }
+        assert_eq!(
+            a.send_files(&paths).unwrap(),
+            vec![hash_of(b"first"), hash_of(b"second")]
+        );
+        for _ in 0..2 {
+            assert!(matches!(
+                b.read_once_detailed().unwrap(),
+                ReadOutcome::File(_)
+            ));
+        }
 
-                j += 1;
+        let reports = reports.lock().unwrap();
+        assert!(reports.iter().all(|&(_, _, total)| total == 11));
+        assert!(reports.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert_eq!(reports.first().map(|r| r.0), Some(0));
+        assert_eq!(reports.last(), Some(&(1, 11, 11)));
 
-                if j >= 16 {
-                    // maximum payload length is approx. 10000 TB
-                    break;
-                }
-            }
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-            if !skip && is_file {
-                skip = !(self.file_incoming)(n_bytes);
+    #[test]
+    fn identical_content_is_stored_once() {
+        let dir = temp_dir("hash-names");
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
+        b.hash_names = true;
+        b.file_suffix = String::from(".bin");
+
+        let mut names = Vec::new();
+        for _ in 0..2 {
+            a.send_reader(&mut Cursor::new(b"same".to_vec())).unwrap();
+            match b.read_once_detailed().unwrap() {
+                ReadOutcome::File(name) => names.push(name),
+                outcome => panic!("unexpected outcome: {:?}", outcome),
             }
+        }
 
-            if !skip && is_file {
-                filen = format!(
-                    "transfer_{}",
-                    SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_nanos()
-                );
+        let expected = dir.join(format!("{}.bin", hash_hex(&hash_of(b"same"))));
+        assert_eq!(names, vec![expected.to_string_lossy().into_owned(); 2]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(fs::read(&expected).unwrap(), b"same");
 
-                if let Ok(f) = File::create(&filen) {
-                    fp = Some(f);
-                } else if let Some(ref f) = self.file_failed {
-                    f(
-                        filen.clone(),
-                        Error::new(ErrorKind::PermissionDenied, "Could not open transfer file"),
-                    );
-                }
+        // a file that merely has the name is not replaced
+        let other = dir.join(format!("{}.bin", hash_hex(&hash_of(b"other"))));
+        fs::write(&other, b"tampered").unwrap();
 
-                let mut buf = [0; 1024];
+        a.send_reader(&mut Cursor::new(b"other".to_vec())).unwrap();
+        let numbered = numbered(&other.to_string_lossy(), 1);
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::File(numbered.clone())
+        );
+        assert_eq!(fs::read(&other).unwrap(), b"tampered");
+        assert_eq!(fs::read(&numbered).unwrap(), b"other");
 
-                while let Ok(()) = self.s.read_exact(&mut buf[..min(n_bytes, 1024)]) {
-                    // read from stream
-                    let n = min(n_bytes, 1024);
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-                    n_bytes -= n;
+    #[test]
+    fn transforms_apply_to_send_only() {
+        let (mut a, mut b) = pair();
+        a.send_transform = Some(Box::new(|msg| msg.to_uppercase()));
 
-                    if is_file {
-                        // basically the same as above, but from the fresh buffer
-                        if let Some(ref mut fp) = fp {
-                            if fp.write_all(&buf[..n]).is_err() {
-                                if let Some(ref f) = self.file_failed {
-                                    f(
-                                        filen.clone(),
-                                        Error::new(
-                                            ErrorKind::PermissionDenied,
-                                            "Could not write to transfer file",
-                                        ),
-                                    );
-                                }
-                            }
-                        }
+        a.send("hello").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("HELLO".to_string())
+        );
+        a.expect_hash().unwrap();
 
-                        hasher.update(&buf[..n]);
-                    }
+        a.send_raw("hello").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("hello".to_string())
+        );
+        a.expect_hash().unwrap();
 
-                    if n_bytes == 0 {
-                        break;
-                    }
-                }
+        // the peer's limit applies to the transformed message
+        a.send_transform = Some(Box::new(|msg| msg.repeat(2)));
+        let mut b = exchange_capabilities(&mut a, b);
+        b.set_max_msg_size(8, true).unwrap();
+        while !matches!(a.read_once_detailed().unwrap(), ReadOutcome::Limits(_)) {}
 
-                if let Some(ref f) = self.file_complete {
-                    f(filen.clone());
-                }
+        let err = a.send("hello").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "Message too large");
+    }
 
-                if let Ok(()) = self.s.read_exact(&mut buf[..33]) {
-                    if let Some(ref f) = self.file_hash_by_peer {
-                        f(filen.clone(), buf[1..33].try_into().unwrap());
-                    }
-                }
-            } else if !skip && !is_file {
-                if n_bytes <= 1024 * 1024 {
-                    msg.resize(n_bytes, 0);
+    #[test]
+    fn existing_files_are_not_clobbered() {
+        let files = MemoryFileSystem::new();
+        files.insert("dl/x.bin", b"old".to_vec());
+        files.insert("dl/x.bin.partial", b"older".to_vec());
 
-                    if let Ok(()) = self.s.read_exact(&mut msg[..n_bytes]) {
-                        hasher.update(&msg);
+        let (partial, fp) = create_partial(&files, "dl/x.bin");
+        assert_eq!(partial, "dl/x_1.bin.partial");
+        fp.unwrap().write_all(b"new").unwrap();
 
-                        // message finished
-                        if let Some(ref f) = &self.msg_new {
-                            f(String::from_utf8_lossy(&msg).into_owned());
-                        }
+        assert_eq!(
+            place_file(&files, &partial, "dl/x.bin").unwrap(),
+            "dl/x_1.bin"
+        );
+        assert_eq!(files.contents("dl/x.bin").unwrap(), b"old");
+        assert_eq!(files.contents("dl/x.bin.partial").unwrap(), b"older");
+        assert_eq!(files.contents("dl/x_1.bin").unwrap(), b"new");
+        assert_eq!(files.contents("dl/x_1.bin.partial"), None);
 
-                        // clear message
-                        msg.clear();
-                    }
-                } else {
-                    // payload too large
-                    if let Some(ref f) = &self.payload_too_large {
-                        f(n_bytes);
-                    }
-                }
-            }
+        // the same on disk, where the final name is claimed by linking
+        let dir = temp_dir("clobber");
+        let name = dir.join("x.bin").to_string_lossy().into_owned();
+        fs::write(&name, b"old").unwrap();
 
-            let mut entire_hash = vec![61];
-            entire_hash.extend_from_slice(&hasher.finalize());
+        let (partial, fp) = create_partial(&RealFileSystem, &name);
+        fp.unwrap().write_all(b"new").unwrap();
+        let placed = place_file(&RealFileSystem, &partial, &name).unwrap();
 
-            self.s
-                .write_all(&entire_hash)
-                .expect("Could not send hash to peer");
+        assert_eq!(Path::new(&placed), dir.join("x_1.bin"));
+        assert_eq!(fs::read(&name).unwrap(), b"old");
+        assert_eq!(fs::read(&placed).unwrap(), b"new");
+        assert!(!Path::new(&partial).exists());
 
-            if is_file {
-                if let Some(ref f) = &self.file_our_hash {
-                    f(filen, entire_hash[1..].try_into().unwrap());
-                }
-            }
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-            return Ok(true);
-        } else if let Some(ref f) = &self.invalid_instr {
-            f(instr);
-        }
+    #[test]
+    fn reading_stops_on_request() {
+        let go_on = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&go_on);
 
-        Ok(false)
+        let (mut a, mut b) = pair();
+        b.should_continue = Some(Box::new(move || flag.load(Ordering::Relaxed)));
+
+        // nothing has been sent, so this would block otherwise
+        assert_eq!(b.read_once_detailed().unwrap(), ReadOutcome::Stopped);
+        assert!(!b.read_once().unwrap());
+        assert_eq!(b.measure_rtt().unwrap_err().kind(), ErrorKind::Interrupted);
+
+        go_on.store(true, Ordering::Relaxed);
+        a.read_once().unwrap(); // the ping
+        a.send("hello").unwrap();
+        assert_eq!(b.read_once_detailed().unwrap(), ReadOutcome::Pong);
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("hello".to_string())
+        );
     }
 
-    /// Sets the TCP connection to non-blocking and invokes `read_once`. This has the effect that a instruction might be read from the peer or not. If one is read, it will be processed in blocking mode. If not, this function returns immediately without blocking. Useful if called in a loop. Note that each invocation reads and processes at most one instruction.
-    pub fn read_maybe(&mut self) -> Result<bool> {
-        self.s.set_nonblocking(true)?;
+    #[test]
+    fn state_follows_the_protocol() {
+        let (mut a, mut b) = pair();
+        assert_eq!(a.state(), TalkerState::Idle);
 
-        let ret = self.read_once();
-        self.s.set_nonblocking(false)?;
+        a.send("hello").unwrap();
+        assert_eq!(a.state(), TalkerState::AwaitingHash(1));
 
-        ret
+        // the peer's own message arrives before the hash
+        b.send("hi").unwrap();
+        b.read_once().unwrap();
+        assert_eq!(b.state(), TalkerState::AwaitingHash(1));
+        assert!(a.expect_hash().is_err());
+        assert_eq!(a.state(), TalkerState::Queued(INSTR_MESSAGE));
+
+        assert_eq!(
+            a.read_once_detailed().unwrap(),
+            ReadOutcome::Message("hi".to_string())
+        );
+        assert_eq!(a.state(), TalkerState::AwaitingHash(1));
+        a.expect_hash().unwrap();
+        assert_eq!(a.state(), TalkerState::Idle);
+
+        b.expect_hash().unwrap();
+        assert_eq!(b.state(), TalkerState::Idle);
+
+        a.close().unwrap();
+        assert_eq!(a.state(), TalkerState::Closed);
     }
 
-    /// Instructs the peer that a message will be forthcoming and transmits the message.
-    pub fn send(&mut self, msg: &str) -> Result<()> {
-        let mut hasher = sha2::Sha256::new();
+    #[test]
+    fn concurrent_reads_are_refused() {
+        let (mut a, mut b) = pair();
+        let mut c = Talker::new(b.s.try_clone().unwrap());
 
-        self.s.write_all(format!("!{}\n", msg.len()).as_bytes())?;
-        self.s.write_all(msg.as_bytes())?;
+        // blocks until something arrives
+        let reader = thread::spawn(move || b.read_once_detailed().unwrap());
+        thread::sleep(Duration::from_millis(100));
 
-        hasher.update(msg.as_bytes());
+        let err = c.read_once_detailed().unwrap_err();
+        assert!(err.to_string().contains("already being read"));
 
-        if let Some(ref f) = self.hash_of_sent {
-            f(hasher.finalize().try_into().unwrap());
+        a.send("hello").unwrap();
+        assert_eq!(
+            reader.join().unwrap(),
+            ReadOutcome::Message("hello".to_string())
+        );
+
+        // the connection is free again
+        a.expect_hash().unwrap();
+        a.send("again").unwrap();
+        assert_eq!(
+            c.read_once_detailed().unwrap(),
+            ReadOutcome::Message("again".to_string())
+        );
+    }
+
+    /// Starts a SOCKS5 proxy that serves a single client without authentication, forwarding to wherever it asks. Returns the address of the proxy.
+    #[cfg(feature = "socks")]
+    fn forwarding_proxy() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+
+            let mut greeting = [0; 2];
+            client.read_exact(&mut greeting).unwrap();
+            let mut methods = vec![0; usize::from(greeting[1])];
+            client.read_exact(&mut methods).unwrap();
+            assert!(methods.contains(&0));
+            client.write_all(&[5, 0]).unwrap();
+
+            let mut request = [0; 4];
+            client.read_exact(&mut request).unwrap();
+            assert_eq!(request[..3], [5, 1, 0]);
+            assert_eq!(request[3], 1); // an IPv4 address
+            let mut target = [0; 6];
+            client.read_exact(&mut target).unwrap();
+            let ip = std::net::Ipv4Addr::new(target[0], target[1], target[2], target[3]);
+            let port = u16::from_be_bytes([target[4], target[5]]);
+
+            let mut upstream = TcpStream::connect((ip, port)).unwrap();
+            client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+
+            let mut from_client = client.try_clone().unwrap();
+            let mut to_upstream = upstream.try_clone().unwrap();
+            thread::spawn(move || std::io::copy(&mut from_client, &mut to_upstream));
+            let _ = std::io::copy(&mut upstream, &mut client);
+        });
+
+        addr
+    }
+
+    #[cfg(feature = "socks")]
+    #[test]
+    fn talkers_connect_through_socks5() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let peer = thread::spawn(move || Talker::accept(&listener).unwrap());
+
+        let mut a = Talker::connect_via_socks5(forwarding_proxy(), &target).unwrap();
+        let mut b = peer.join().unwrap();
+
+        a.send("through the proxy").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("through the proxy".to_string())
+        );
+        a.expect_hash().unwrap();
+    }
+
+    #[test]
+    fn message_limit_can_be_lowered() {
+        let (mut a, b) = pair();
+        let mut b = exchange_capabilities(&mut a, b);
+
+        a.send("twelve chars").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("twelve chars".to_string())
+        );
+        a.expect_hash().unwrap();
+
+        b.set_max_msg_size(10, false).unwrap();
+        assert_eq!(b.max_msg_size(), 10);
+        a.send("twelve chars").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::PayloadTooLarge(12)
+        );
+        a.send_raw("ten chars!").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("ten chars!".to_string())
+        );
+
+        // once notified, the peer refuses to send larger messages itself
+        b.set_max_msg_size(5, true).unwrap();
+        while !matches!(a.read_once_detailed().unwrap(), ReadOutcome::Limits(_)) {}
+        assert_eq!(
+            a.send("ten chars!").unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn oversized_attempts_are_counted() {
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&attempts);
+
+        let (mut a, mut b) = pair();
+        b.set_max_msg_size(4, false).unwrap();
+        b.oversized_attempt = Some(Box::new(move |size, count| {
+            log.lock().unwrap().push((size, count))
+        }));
+
+        for msg in ["hello", "fits", "goodbye", "farewell"] {
+            a.send(msg).unwrap();
+            b.read_once().unwrap();
         }
 
-        Ok(())
+        assert_eq!(b.oversized_attempts(), 3);
+        assert_eq!(*attempts.lock().unwrap(), [(5, 1), (7, 2), (8, 3)]);
     }
 
-    /// Send a stream to the peer. While this method technically accepts all streams that implement `Read`, *talkers* currently only has dedicated support for files.
-    pub fn send_stream<T, U>(&mut self, stream: &mut T, len: U) -> Result<()>
-    where
-        T: Read,
-        U: std::fmt::Display,
-    {
-        let mut hasher = sha2::Sha256::new();
-        let mut buf = [0; 1024];
+    #[cfg(unix)]
+    #[test]
+    fn file_modes_are_transferred() {
+        use std::os::unix::fs::PermissionsExt;
 
-        self.s.write_all(format!("#{}\n", len).as_bytes())?;
+        let dir = temp_dir("modes");
+        let script = dir.join("run.sh");
+        fs::write(&script, b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
 
-        while let Ok(n) = stream.read(&mut buf) {
-            if n == 0 {
-                break;
+        let (mut a, b) = pair();
+        let mut b = exchange_capabilities(&mut a, b);
+        b.download_dir = Some(dir.join("received"));
+        b.file_incoming = Box::new(|_| true);
+
+        a.send_file(script.to_str().unwrap()).unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::FileMode(0o755)
+        );
+        let filen = match b.read_once_detailed().unwrap() {
+            ReadOutcome::File(filen) => filen,
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        };
+
+        let mode = fs::metadata(filen).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn remapped_instructions_round_trip() {
+        // swapping two instructions keeps them distinguishable
+        let (mut a, mut b) = pair_with(
+            |a| {
+                a.remap_instructions(&[(INSTR_MESSAGE, INSTR_HASH), (INSTR_HASH, INSTR_MESSAGE)])
+                    .unwrap()
+            },
+            |b| b.remap_instructions(&[(INSTR_HASH, b'h')]).unwrap(),
+        );
+        assert_eq!(a.header(INSTR_MESSAGE, 5)[0], b'=');
+
+        a.send("hello").unwrap();
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("hello".to_string())
+        );
+        a.expect_hash().unwrap();
+
+        b.send("hi").unwrap();
+        assert_eq!(
+            a.read_once_detailed().unwrap(),
+            ReadOutcome::Message("hi".to_string())
+        );
+        b.expect_hash().unwrap();
+
+        // two instructions sent as the same octet, and remapping too late
+        let mut c = Talker::new(raw_pair().0);
+        assert!(c
+            .remap_instructions(&[(INSTR_PING, b'p'), (INSTR_PONG, b'p')])
+            .is_err());
+        assert!(a.remap_instructions(&[(INSTR_PING, b'p')]).is_err());
+    }
+
+    #[cfg(feature = "memmap")]
+    #[test]
+    fn mapped_files_are_received_intact() {
+        let dir = temp_dir("mmap");
+        let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+
+        let (mut a, mut b) = pair();
+        b.download_dir = Some(dir.clone());
+        b.file_incoming = Box::new(|_| true);
+        b.mmap_above = Some(64 * 1024);
+
+        let sent = data.clone();
+        let sender = thread::spawn(move || {
+            a.send_reader(&mut Cursor::new(sent)).unwrap();
+            a.expect_hash().unwrap();
+        });
+
+        let filen = match b.read_once_detailed().unwrap() {
+            ReadOutcome::File(filen) => filen,
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        };
+        sender.join().unwrap();
+
+        assert_eq!(fs::read(filen).unwrap(), data);
+        assert_eq!(b.received_files()[0].our_hash, Some(hash_of(&data)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hashes_are_sent_explicitly() {
+        let (mut a, mut b) = pair();
+        let hash = hash_of(b"confirmed");
+
+        a.send_hash(&hash).unwrap();
+        assert_eq!(b.recv_hash().unwrap(), hash);
+
+        // anything else is left for `read_once`
+        a.send_raw("not a hash").unwrap();
+        assert_eq!(b.recv_hash().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            b.read_once_detailed().unwrap(),
+            ReadOutcome::Message("not a hash".to_string())
+        );
+        assert_eq!(a.recv_hash().unwrap(), hash_of(b"not a hash"));
+    }
+
+    /// A writer that rejects writes of more than `max` octets, as some sockets do.
+    struct Picky {
+        max: usize,
+        written: Vec<u8>,
+    }
+
+    impl Write for Picky {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            if buf.len() > self.max {
+                return Err(Error::from(ErrorKind::OutOfMemory));
             }
-            self.s.write_all(&buf[..n])?;
-            hasher.update(&buf[..n]);
+
+            self.written.extend_from_slice(buf);
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
         }
+    }
 
-        let mut entire_hash = vec![61];
-        entire_hash.extend_from_slice(&hasher.finalize());
-        self.s.write_all(&entire_hash)?;
+    #[test]
+    fn oversized_writes_are_split() {
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let mut w = Picky {
+            max: 1000,
+            written: Vec::new(),
+        };
 
-        if let Some(ref f) = self.hash_of_sent {
-            f(entire_hash[1..].try_into().unwrap());
+        let mut rest = &data[..];
+        let mut limit = data.len();
+        while !rest.is_empty() {
+            let n = write_some(&mut w, rest, &mut limit).unwrap();
+            rest = &rest[n..];
         }
 
-        Ok(())
+        assert_eq!(w.written, data);
+        // later writes start out small enough right away
+        assert!((500..=1000).contains(&limit));
     }
 
-    /// Blocks until a hash has been received. If no hash, but some other instruction, is received, that instruction is written into an internal queue so that it can be processed by subsequent calls to `read_once`. Returns `Ok(())` if a hash was received and an Err variant if not.
-    pub fn expect_hash(&mut self) -> Result<()> {
-        self.s.set_nonblocking(false)?;
+    #[test]
+    fn label_is_shown_in_debug_output() {
+        let (mut a, b) = pair();
+        assert!(format!("{:?}", a).contains("label: None"));
 
-        let mut buf = [0; 33];
+        a.label = Some("alice-laptop".to_string());
+        assert!(format!("{:?}", a).contains("label: Some(\"alice-laptop\")"));
 
-        if let Ok(()) = self.s.read_exact(&mut buf[..1]) {
-            if buf[0] == b'=' {
-                self.s.read_exact(&mut buf[1..])?;
+        a.label = Some("bob-phone".to_string());
+        let debug = format!("{:?}", a);
+        assert!(debug.contains("bob-phone") && !debug.contains("alice-laptop"));
+        assert!(debug.contains(&b.s.local_addr().unwrap().to_string()));
+    }
 
-                if let Some(ref f) = self.hash_rcvd {
-                    f(buf[1..33].try_into().unwrap());
-                }
+    #[test]
+    fn full_inbox_blocks_reading() {
+        let (mut a, mut b) = pair();
+        let inbox = b.inbox(1);
 
-                return Ok(());
-            } else {
-                self.queue = Some(buf[0] as u8);
-            }
+        for msg in ["one", "two", "three"] {
+            a.send(msg).unwrap();
         }
 
-        Err(Error::new(ErrorKind::Other, "No hash transmitted"))
+        let reads = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&reads);
+        let reader = thread::spawn(move || {
+            for _ in 0..3 {
+                b.read_once().unwrap();
+                counted.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        // the second message waits for room in the inbox
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(reads.load(Ordering::Relaxed), 1);
+
+        let received: Vec<String> = inbox.iter().take(3).collect();
+        assert_eq!(received, ["one", "two", "three"]);
+        reader.join().unwrap();
+        assert_eq!(reads.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn files_are_transferred_in_memory() {
+        let ours = MemoryFileSystem::new();
+        let theirs = MemoryFileSystem::new();
+        ours.insert("docs/report.txt", b"quarterly numbers".to_vec());
+
+        let (mut a, mut b) = pair();
+        a.file_system = Box::new(ours.clone());
+        b.file_system = Box::new(theirs.clone());
+        b.download_dir = Some(PathBuf::from("incoming"));
+        b.file_incoming = Box::new(|_| true);
+
+        let offer = prepare_file_in(&ours, "docs/report.txt").unwrap();
+        assert_eq!(offer.name, "report.txt");
+        assert_eq!(offer.size, 17);
+        assert_eq!(offer.hash, hash_of(b"quarterly numbers"));
+
+        assert_eq!(a.send_prepared(&offer).unwrap(), offer.hash);
+        let filen = match b.read_once_detailed().unwrap() {
+            ReadOutcome::File(filen) => filen,
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        };
+        a.expect_hash().unwrap();
+
+        // nothing has touched the disk
+        assert!(Path::new(&filen).starts_with("incoming"));
+        assert!(!Path::new(&filen).exists());
+        assert_eq!(theirs.paths(), [PathBuf::from(&filen)]);
+        assert_eq!(theirs.contents(&filen).unwrap(), b"quarterly numbers");
+        assert_eq!(hash_file_in(&theirs, &filen).unwrap(), offer.hash);
+    }
+
+    #[test]
+    fn frames_are_written_at_once() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&writes);
+
+        let (mut a, mut b) = pair();
+        a.bytes_written = Some(Box::new(move |n| log.lock().unwrap().push(n)));
+
+        a.send("hello").unwrap();
+        assert_eq!(*writes.lock().unwrap(), [8]);
+
+        // the frame itself is as before
+        let mut frame = [0; 8];
+        b.s.read_exact(&mut frame).unwrap();
+        assert_eq!(&frame, b"!5\nhello");
     }
 }
@@ -1,61 +1,333 @@
 mod app;
+mod config;
 
 use std::env;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+#[cfg(feature = "config")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::time;
 
-fn main() {
-    let mut bind_to = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 50505));
-    let mut proxy = None;
+use config::Config;
 
+fn main() {
     let mut args = env::args();
-    let appname = args.next().unwrap();
+    let appname = args.next().unwrap_or_else(|| String::from("talkers"));
+    let args: Vec<String> = args.collect();
+
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        help(&appname);
+        return;
+    }
+
+    let (cli, config_file) = parse_args(args).unwrap_or_else(|e| fail(&appname, &e));
+
+    // command-line flags take precedence over the configuration file
+    let config = load_config(config_file)
+        .unwrap_or_else(|e| fail(&appname, &e))
+        .overridden_by(cli);
+
+    let mut bind_to = config
+        .bind
+        .unwrap_or_else(|| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 50505)));
+
+    if config.dual_stack == Some(true) {
+        // [::] accepts both IPv4 and IPv6 peers
+        bind_to = SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::UNSPECIFIED,
+            bind_to.port(),
+            0,
+            0,
+        ));
+    }
+
+    let proxy_auth = match (config.proxy_user, config.proxy_pass) {
+        (Some(user), Some(pass)) => Some((user, pass)),
+        (None, None) => None,
+        _ => fail(
+            &appname,
+            "Please specify both `--proxy-user` and `--proxy-pass`.",
+        ),
+    };
+
+    let mut server = app::ServerConfig::new(bind_to);
+    server.proxy = config.proxy;
+    server.proxy_auth = proxy_auth;
+    server.downloads = config.downloads;
+    server.max_conns = config.max_conns;
+    server.max_file = config.max_file;
+    server.max_msg_size = config.max_msg_size;
+    server.max_chunked_size = config.max_chunked_size;
+    server.max_total_received = config.max_total_received;
+
+    if let Some(ms) = config.poll_ms {
+        server.poll_interval = time::Duration::from_millis(ms);
+    }
+    server.audit = config.audit;
+    server.heartbeat = config.heartbeat.map(time::Duration::from_secs);
+    server.side_channels = config.side_channels == Some(true);
+
+    app::start_server_with(server);
+}
+
+/// Prints the usage and an error message, then exits with a nonzero code.
+fn fail(appname: &str, msg: &str) -> ! {
+    help(appname);
+    eprintln!("error: {}", msg);
+
+    process::exit(2)
+}
+
+/// Parses the command-line arguments (without the name of the program). Returns the settings given on the command line and the configuration file to be read, if any.
+fn parse_args<I: IntoIterator<Item = String>>(
+    args: I,
+) -> Result<(Config, Option<PathBuf>), String> {
+    let mut cli = Config::default();
+    let mut config_file = None;
+
+    let mut args = args.into_iter();
 
     while let Some(arg) = args.next() {
         if arg == "-x" || arg == "--proxy" {
-            if let Some(arg) = args.next() {
-                if let Ok(b) = arg.parse::<SocketAddr>() {
-                    proxy = Some(b);
-                } else if let Ok(port) = arg.parse() {
-                    proxy = Some(SocketAddr::V4(SocketAddrV4::new(
-                        Ipv4Addr::new(127, 0, 0, 1),
-                        port,
-                    )));
-                } else {
-                    help(&appname);
-                    panic!(
-                        "Could not parse proxy address (should be something like `127.0.0.1:9150` or a port)."
-                    );
-                }
+            let arg = args
+                .next()
+                .ok_or("Please specify the proxy (e.g. `127.0.0.1:9150` or a port).")?;
+
+            if let Ok(b) = arg.parse::<SocketAddr>() {
+                cli.proxy = Some(b);
+            } else if let Ok(port) = arg.parse() {
+                cli.proxy = Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::new(127, 0, 0, 1),
+                    port,
+                )));
             } else {
-                help(&appname);
-                panic!("Please specify the proxy (e.g. `127.0.0.1:9150` or a port).");
+                return Err(format!(
+                    "Could not parse proxy address `{}` (should be something like `127.0.0.1:9150` or a port).",
+                    arg
+                ));
             }
+        } else if arg == "--proxy-user" || arg == "--proxy-pass" {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Please specify the value of `{}`.", arg))?;
+
+            if arg == "--proxy-user" {
+                cli.proxy_user = Some(value);
+            } else {
+                cli.proxy_pass = Some(value);
+            }
+        } else if arg == "-d" || arg == "--downloads" {
+            let arg = args
+                .next()
+                .ok_or("Please specify the directory for received files.")?;
+
+            cli.downloads = Some(PathBuf::from(arg));
+        } else if arg == "-m" || arg == "--max-conns" {
+            let n = args
+                .next()
+                .and_then(|arg| arg.parse().ok())
+                .ok_or("Please specify the maximum number of connections (e.g. `16`).")?;
+
+            cli.max_conns = Some(n);
+        } else if arg == "-6" || arg == "--dual-stack" {
+            cli.dual_stack = Some(true);
+        } else if arg == "--max-file" {
+            let n = args
+                .next()
+                .and_then(|arg| arg.parse().ok())
+                .ok_or("Please specify the size of the largest file accepted in octets.")?;
+
+            cli.max_file = Some(n);
+        } else if arg == "--poll-ms" {
+            let ms = args
+                .next()
+                .and_then(|arg| arg.parse().ok())
+                .filter(|&ms| ms > 0)
+                .ok_or("Please specify the poll interval in milliseconds (e.g. `125`).")?;
+
+            cli.poll_ms = Some(ms);
+        } else if arg == "-a" || arg == "--audit" {
+            let arg = args.next().ok_or("Please specify the audit log file.")?;
+
+            cli.audit = Some(PathBuf::from(arg));
+        } else if arg == "--heartbeat" {
+            let secs = args
+                .next()
+                .and_then(|arg| arg.parse().ok())
+                .filter(|&secs| secs > 0)
+                .ok_or("Please specify the heartbeat interval in seconds (e.g. `60`).")?;
+
+            cli.heartbeat = Some(secs);
+        } else if arg == "-s" || arg == "--side-channels" {
+            cli.side_channels = Some(true);
+        } else if arg == "-c" || arg == "--config" {
+            let arg = args
+                .next()
+                .ok_or("Please specify the configuration file.")?;
+
+            config_file = Some(PathBuf::from(arg));
         } else if let Ok(b) = arg.parse() {
-            bind_to = b;
+            cli.bind = Some(b);
         } else if let Ok(port) = arg.parse() {
-            bind_to = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port));
+            cli.bind = Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::new(0, 0, 0, 0),
+                port,
+            )));
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown flag `{}`.", arg));
         } else {
-            help(&appname);
-            panic!("Invalid argument `{}`.", arg);
+            return Err(format!("Invalid bind address `{}`.", arg));
         }
     }
 
-    app::start_server(bind_to, proxy);
+    Ok((cli, config_file))
+}
+
+/// Loads the given configuration file, or `talkers.toml` if it exists.
+#[cfg(feature = "config")]
+fn load_config(path: Option<PathBuf>) -> Result<Config, String> {
+    let path = match path {
+        Some(path) => path,
+        None if Path::new("talkers.toml").exists() => PathBuf::from("talkers.toml"),
+        None => return Ok(Config::default()),
+    };
+
+    Config::load(&path).map_err(|e| format!("Could not read `{}`: {}", path.display(), e))
+}
+
+#[cfg(not(feature = "config"))]
+fn load_config(path: Option<PathBuf>) -> Result<Config, String> {
+    if path.is_some() {
+        return Err(String::from(
+            "Reading a configuration file requires the `config` feature.",
+        ));
+    }
+
+    Ok(Config::default())
 }
 
 fn help(appname: &str) {
     eprintln!("talkers 0.1.0");
     eprintln!("-------------");
     eprintln!();
-    eprintln!("USAGE:\t{} [-x [host:]port]] [[bhost:]bport]", appname);
+    eprintln!(
+        "USAGE:\t{} [-x [host:]port]] [--proxy-user user --proxy-pass pass] [-d dir] [-m n] [-6] [-a file] [--heartbeat secs] [--max-file n] [--poll-ms ms] [-s] [-c file] [[bhost:]bport]",
+        appname
+    );
     eprintln!();
     eprintln!("ARGUMENTS:");
+    eprintln!("           -h, --help:  Prints this help.");
+    eprintln!();
     eprintln!("      -x [host:]port]:  Specifies a SOCKS5 proxy to be used.");
     eprintln!(" --proxy [host:]port]:  If only a port is specified, 127.0.0.1");
     eprintln!("                        is assumed as the host.");
     eprintln!();
+    eprintln!("    --proxy-user user:  Authenticates with the SOCKS5 proxy");
+    eprintln!("    --proxy-pass pass:  using a username and password.");
+    eprintln!();
+    eprintln!("               -d dir:  Stores files received in chat k in the");
+    eprintln!("      --downloads dir:  subdirectory dir/k.");
+    eprintln!();
+    eprintln!("                 -m n:  Refuses incoming connections while n");
+    eprintln!("        --max-conns n:  chats are open.");
+    eprintln!();
+    eprintln!("                   -6:  Binds on [::] so that both IPv4 and");
+    eprintln!("         --dual-stack:  IPv6 peers are accepted. Only the port");
+    eprintln!("                        of [bhost:]bport] is used.");
+    eprintln!();
+    eprintln!("              -a file:  Appends the hashes of all messages and");
+    eprintln!("         --audit file:  files sent and received to file.");
+    eprintln!();
+    eprintln!("     --heartbeat secs:  Pings chats in which nothing has been");
+    eprintln!("                        sent or received for secs seconds.");
+    eprintln!();
+    eprintln!("         --max-file n:  Rejects files of more than n octets.");
+    eprintln!();
+    eprintln!("         --poll-ms ms:  Checks each chat for incoming data every");
+    eprintln!("                        ms milliseconds (default: 125).");
+    eprintln!();
+    eprintln!("                   -s:  Sends files to chats that we have");
+    eprintln!("      --side-channels:  initiated over a second connection,");
+    eprintln!("                        so that messages keep flowing.");
+    eprintln!();
+    eprintln!("              -c file:  Reads settings from a TOML file (by");
+    eprintln!("        --config file:  default, talkers.toml if it exists).");
+    eprintln!("                        Arguments take precedence.");
+    eprintln!();
     eprintln!("       [bhost:]bport]:  Specifies the address on which talkers");
     eprintln!("                        will bind. If only a port is specified,");
     eprintln!("                        talkers will bind on 0.0.0.0.");
     eprintln!();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Like `parse_args`, but for string literals.
+    fn parse(args: &[&str]) -> Result<(Config, Option<PathBuf>), String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn valid_arguments_are_parsed() {
+        let (cli, config_file) = parse(&[
+            "-x",
+            "9150",
+            "--proxy-user",
+            "alice",
+            "--proxy-pass",
+            "secret",
+            "-m",
+            "16",
+            "-d",
+            "received",
+            "--max-file",
+            "1024",
+            "--poll-ms",
+            "50",
+            "-c",
+            "talkers.toml",
+            "127.0.0.1:60606",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            cli,
+            Config {
+                bind: Some("127.0.0.1:60606".parse().unwrap()),
+                proxy: Some("127.0.0.1:9150".parse().unwrap()),
+                proxy_user: Some("alice".to_string()),
+                proxy_pass: Some("secret".to_string()),
+                max_conns: Some(16),
+                downloads: Some(PathBuf::from("received")),
+                max_file: Some(1024),
+                poll_ms: Some(50),
+                ..Config::default()
+            }
+        );
+        assert_eq!(config_file, Some(PathBuf::from("talkers.toml")));
+
+        // a port alone binds on all interfaces
+        let (cli, _) = parse(&["50506"]).unwrap();
+        assert_eq!(cli.bind, Some("0.0.0.0:50506".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        assert!(parse(&["-x", "not a proxy"])
+            .unwrap_err()
+            .contains("proxy address"));
+        assert!(parse(&["-x"]).is_err());
+        assert!(parse(&["example.com:50505"])
+            .unwrap_err()
+            .contains("bind address"));
+        assert!(parse(&["--frobnicate"])
+            .unwrap_err()
+            .contains("Unknown flag"));
+        assert!(parse(&["-m", "many"]).is_err());
+        assert!(parse(&["--poll-ms", "0"]).is_err());
+        assert!(parse(&["--max-file", "lots"]).is_err());
+    }
+}